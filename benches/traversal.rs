@@ -0,0 +1,137 @@
+/*
+ * The depth experiments of `benchmarks/lh_bench`, without the mount: a chain
+ * of files where every file was derived from the one before, each with
+ * `BENCH_FIELDS` fields whose check always passes and whose execute stores a
+ * value. Opening the last file runs the fields of the whole chain.
+ *
+ * Run with `cargo bench --bench traversal`. Every depth is timed `BENCH_ITER`
+ * times, checking the chain serially, in parallel, and in parallel with the
+ * memo kept between opens. The times are written in seconds, one per line, to
+ * `<BENCH_RESULTS>/<mode> <depth>_metadata_<depth>` for the plots in
+ * `benchmarks/draw_programs`.
+ */
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
+
+use dynamic_exec::{Cache, Sandbox};
+use lh_mount::metadata::{
+    ancestors, Context, MetadataHandler, Operation, Registry, RegistryHandler, Traversal, MANIFEST,
+    REGISTRY,
+};
+use tempfile::TempDir;
+
+const DEPTHS: [usize; 8] = [1, 2, 4, 8, 16, 32, 64, 100];
+
+const CHECK: &str = r#"fn main(){println!("{{\"allow\": true}}");}"#;
+const EXECUTE: &str = r#"fn main(){println!("{{\"updates\": {{\"foo\": \"barerino\"}}}}");}"#;
+
+fn env_or(name: &str, default: usize) -> usize
+{
+    std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+// `<root>/files/file0..file<depth>`, and the fields for all of them
+fn setup(root: &Path, depth: usize, fields: usize) -> Vec<PathBuf>
+{
+    let files = root.join("files");
+    std::fs::create_dir_all(&files).unwrap();
+    let chain = (0..depth).map(|i| files.join(format!("file{}", i))).collect::<Vec<_>>();
+    for file in &chain
+    {
+        std::fs::write(file, "").unwrap();
+    }
+
+    let metadata = root.join("metadata");
+    let mut registry = String::new();
+    for k in 0..fields
+    {
+        let name = format!("dummy_{}", k);
+        let field = metadata.join(&name);
+        std::fs::create_dir_all(&field).unwrap();
+        std::fs::write(field.join("check.rs"), CHECK).unwrap();
+        std::fs::write(field.join("execute.rs"), EXECUTE).unwrap();
+        std::fs::write(field.join(MANIFEST), "check = \"check.rs\"\nexecute = \"execute.rs\"\n")
+            .unwrap();
+        registry += &format!("[[field]]\nname = {:?}\nfiles = [{:?}]\n", name, files.join("*"));
+    }
+    std::fs::write(metadata.join(REGISTRY), registry).unwrap();
+    chain
+}
+
+fn open(handler: &Arc<RegistryHandler>, traversal: &Traversal, lineage: &[PathBuf])
+{
+    let context = Context::default();
+    for change in traversal.changes(handler.clone(), lineage, Operation::Open, &context).unwrap()
+    {
+        change.execute().unwrap();
+    }
+}
+
+fn main()
+{
+    // `cargo test --all-targets` runs benches without `--bench`
+    if !std::env::args().any(|arg| arg == "--bench")
+    {
+        return;
+    }
+
+    let fields = env_or("BENCH_FIELDS", 1);
+    let iterations = env_or("BENCH_ITER", 20);
+    let workers = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let results = PathBuf::from(
+        std::env::var("BENCH_RESULTS").unwrap_or_else(|_| "benchmarks/results/traversal".into()),
+    );
+    std::fs::create_dir_all(&results).unwrap();
+
+    let cache = Cache::new(std::env::temp_dir().join("gurret-bench-cache"));
+    for depth in DEPTHS
+    {
+        let root = TempDir::new().unwrap();
+        let chain = setup(root.path(), depth, fields);
+        let registry = Registry::load(&root.path().join("metadata"), root.path()).unwrap();
+        let handler = RegistryHandler::new(registry, Sandbox::disabled(), &cache).unwrap();
+        let handler = Arc::new(handler);
+
+        // file<n> is derived from file<n - 1>
+        let last = chain.last().unwrap();
+        let lineage = ancestors(last, |file| {
+            let i = chain.iter().position(|f| f == file).unwrap();
+            chain[..i].last().cloned().into_iter().collect()
+        });
+
+        for (mode, workers, memo) in
+            [("serial", 1, false), ("parallel", workers, false), ("memo", workers, true)]
+        {
+            let traversal = Traversal::new(workers);
+            let name = format!("{} {}_metadata_{}", mode, depth, depth);
+            let mut out = std::fs::File::create(results.join(&name)).unwrap();
+
+            let mut total = 0.0;
+            for _ in 0..iterations
+            {
+                if !memo
+                {
+                    lineage.iter().for_each(|file| handler.invalidate(file));
+                }
+                let start = Instant::now();
+                open(&handler, &traversal, &lineage);
+                let elapsed = start.elapsed().as_secs_f64();
+                writeln!(out, "{:.9}", elapsed).unwrap();
+                total += elapsed;
+            }
+            println!("{:<30} {:>12.6}s", name, total / iterations as f64);
+        }
+
+        for (field, timing) in handler.timings()
+        {
+            println!(
+                "  {} checked {} times ({} cached) in {:?}",
+                field, timing.checks, timing.cached, timing.total
+            );
+        }
+    }
+}
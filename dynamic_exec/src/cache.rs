@@ -0,0 +1,282 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Error, ErrorKind},
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt},
+        io::AsRawFd,
+    },
+    path::{Path, PathBuf},
+    process::Command,
+    sync::OnceLock,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::ExecError;
+
+/*
+ * Compiled programs, named by the hash of their source and the rustc that
+ * built them. Artifacts outlive the `Executable`s using them, so a restart
+ * only compiles the sources that changed since last time.
+ */
+#[derive(Debug, Clone)]
+pub struct Cache
+{
+    dir: PathBuf,
+}
+
+impl Default for Cache
+{
+    /*
+     * `$GURRET_CACHE`, or `gurret` in the user's cache directory. Without
+     * one, as for a daemon started by init, it is `/var/cache/gurret`, and
+     * never a folder anyone may write to.
+     */
+    fn default() -> Self
+    {
+        let dir = match std::env::var_os("GURRET_CACHE")
+        {
+            Some(dir) => PathBuf::from(dir),
+            None => std::env::var_os("XDG_CACHE_HOME")
+                .map(PathBuf::from)
+                .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+                .unwrap_or_else(|| PathBuf::from("/var/cache"))
+                .join("gurret"),
+        };
+        Self::new(dir)
+    }
+}
+
+impl Cache
+{
+    pub fn new(dir: impl Into<PathBuf>) -> Self
+    {
+        Self {
+            dir: dir.into()
+        }
+    }
+
+    pub fn dir(&self) -> &Path
+    {
+        &self.dir
+    }
+
+    /*
+     * The binary for the source at `path`, compiling it only if there is no
+     * artifact for this exact source yet.
+     */
+    pub fn compile<P: AsRef<Path>>(&self, path: P) -> std::io::Result<PathBuf>
+    {
+        let path = path.as_ref();
+        let source = std::fs::read(path)?;
+        let artifact = self.dir.join(key(&source)?);
+
+        trusted(&self.dir)?;
+        if artifact.exists()
+        {
+            return Ok(artifact);
+        }
+
+        // Whoever holds the lock compiles, the rest find the artifact after
+        let _lock = Lock::new(&artifact.with_extension("lock"))?;
+        if artifact.exists()
+        {
+            return Ok(artifact);
+        }
+
+        let partial = artifact.with_extension(format!("{}.partial", std::process::id()));
+        let output = Command::new("rustc").arg(path).arg("-o").arg(&partial).output()?;
+        if !output.status.success()
+        {
+            let _ = std::fs::remove_file(&partial);
+            return Err(ExecError::status(path, output.status, &output.stderr).into());
+        }
+
+        std::fs::rename(&partial, &artifact)?;
+        Ok(artifact)
+    }
+}
+
+/*
+ * Make the folder, only for ourselves, if it is not there. What is run from
+ * it must not be planted by someone else, so it has to be ours and only
+ * writable by us.
+ */
+pub(crate) fn trusted(dir: &Path) -> std::io::Result<()>
+{
+    std::fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    let metadata = std::fs::symlink_metadata(dir)?;
+    let ours = metadata.uid() == unsafe { libc::geteuid() };
+    if !metadata.is_dir() || !ours || metadata.mode() & 0o022 != 0
+    {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("{} is not a folder only we may write to", dir.display()),
+        ));
+    }
+    Ok(())
+}
+
+fn key(source: &[u8]) -> std::io::Result<String>
+{
+    let mut hasher = Sha256::new();
+    hasher.update(rustc_version()?);
+    hasher.update([0]);
+    hasher.update(source);
+
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn rustc_version() -> std::io::Result<&'static str>
+{
+    static VERSION: OnceLock<String> = OnceLock::new();
+
+    if let Some(version) = VERSION.get()
+    {
+        return Ok(version);
+    }
+
+    let output = Command::new("rustc").arg("-vV").output()?;
+    if !output.status.success()
+    {
+        return Err(ExecError::status(Path::new("rustc"), output.status, &output.stderr).into());
+    }
+    let version = String::from_utf8(output.stdout)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "rustc -vV is not utf8"))?;
+
+    Ok(VERSION.get_or_init(|| version))
+}
+
+
+// An exclusive flock, released when dropped
+struct Lock(File);
+
+impl Drop for Lock
+{
+    fn drop(&mut self)
+    {
+        unsafe { libc::flock(self.0.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+impl Lock
+{
+    fn new(path: &Path) -> std::io::Result<Self>
+    {
+        let file = OpenOptions::new().create(true).truncate(false).write(true).open(path)?;
+
+        loop
+        {
+            match unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) }
+            {
+                0 => return Ok(Self(file)),
+                _ =>
+                {
+                    let err = Error::last_os_error();
+                    if err.kind() != ErrorKind::Interrupted
+                    {
+                        return Err(err);
+                    }
+                },
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use std::{sync::Arc, thread};
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn source(dir: &Path, body: &str) -> PathBuf
+    {
+        let path = dir.join("main.rs");
+        std::fs::write(&path, body).unwrap();
+        path
+    }
+
+    #[test]
+    fn artifact_is_reused()
+    {
+        let dir = TempDir::new().unwrap();
+        let cache = Cache::new(dir.path().join("cache"));
+        let path = source(dir.path(), "fn main(){}");
+
+        let first = cache.compile(&path).unwrap();
+        let modified = std::fs::metadata(&first).unwrap().modified().unwrap();
+        let second = cache.compile(&path).unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(std::fs::metadata(&second).unwrap().modified().unwrap(), modified);
+        assert!(first.starts_with(cache.dir()));
+        assert!(!dir.path().join("main").exists());
+    }
+
+    #[test]
+    fn source_change_invalidates()
+    {
+        let dir = TempDir::new().unwrap();
+        let cache = Cache::new(dir.path().join("cache"));
+
+        let first = cache.compile(source(dir.path(), "fn main(){}")).unwrap();
+        let second = cache.compile(source(dir.path(), "fn main(){println!()}")).unwrap();
+
+        assert_ne!(first, second);
+        assert!(first.exists() && second.exists());
+    }
+
+    #[test]
+    fn concurrent_compiles_agree()
+    {
+        let dir = TempDir::new().unwrap();
+        let cache = Arc::new(Cache::new(dir.path().join("cache")));
+        let path = Arc::new(source(dir.path(), "fn main(){println!(\"7\")}"));
+
+        let handles = (0..4)
+            .map(|_| {
+                let (cache, path) = (Arc::clone(&cache), Arc::clone(&path));
+                thread::spawn(move || cache.compile(&*path).unwrap())
+            })
+            .collect::<Vec<_>>();
+        let artifacts = handles.into_iter().map(|h| h.join().unwrap()).collect::<Vec<_>>();
+
+        assert!(artifacts.windows(2).all(|w| w[0] == w[1]));
+        let output = Command::new(&artifacts[0]).output().unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "7\n");
+    }
+
+    #[test]
+    fn shared_folder_is_refused()
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new().unwrap();
+        let cache = Cache::new(dir.path().join("cache"));
+        let path = source(dir.path(), "fn main(){}");
+        cache.compile(&path).unwrap();
+
+        std::fs::set_permissions(cache.dir(), std::fs::Permissions::from_mode(0o777)).unwrap();
+        assert_eq!(cache.compile(&path).unwrap_err().kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn broken_source_leaves_nothing()
+    {
+        let dir = TempDir::new().unwrap();
+        let cache = Cache::new(dir.path().join("cache"));
+
+        assert!(cache.compile(source(dir.path(), "fn main(){")).is_err());
+
+        let leftovers = std::fs::read_dir(cache.dir())
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension() != Some("lock".as_ref()))
+            .count();
+        assert_eq!(leftovers, 0);
+    }
+}
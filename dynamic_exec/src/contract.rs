@@ -0,0 +1,124 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+// Metadata values, both builtin and from programs, are kept as xattrs here
+pub const METADATA_PREFIX: &str = "user.gurret.";
+
+/*
+ * What a program is given as JSON on its input: the operation, the file and
+ * who touched it, along with the labels and current metadata of the file.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Request
+{
+    pub operation: String,
+    pub path:      PathBuf,
+    pub pid:       u32,
+    pub uid:       u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset:    Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size:      Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub old_path:  Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_path:  Option<PathBuf>,
+    #[serde(default)]
+    pub labels:    serde_json::Value,
+    #[serde(default)]
+    pub metadata:  BTreeMap<String, String>,
+}
+
+/*
+ * What a program answers with as JSON on its output. Every field is optional,
+ * and printing nothing at all is the same as `{}`. Only `access` programs
+ * answer with a `value`, the current value of their field. A plain `true` or
+ * `false`, what checks printed before there was JSON, is only the `allow`.
+ * The `updates` are stored on the file by the caller, since a sandboxed
+ * program runs as `nobody` and can not write the file itself.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Response
+{
+    pub allow:   bool,
+    pub updates: BTreeMap<String, String>,
+    pub log:     Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value:   Option<String>,
+}
+
+impl Response
+{
+    pub fn parse(output: &str) -> serde_json::Result<Self>
+    {
+        match output.trim()
+        {
+            "" => Ok(Self::default()),
+            answer @ ("true" | "false") => Ok(Self {
+                allow: answer == "true",
+                ..Self::default()
+            }),
+            output => serde_json::from_str(output),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn empty_output_is_default()
+    {
+        assert_eq!(Response::parse("\n").unwrap(), Response::default());
+    }
+
+    #[test]
+    fn partial_response()
+    {
+        let response = Response::parse(r#"{"allow": true, "updates": {"owner": "me"}}"#).unwrap();
+        assert!(response.allow);
+        assert_eq!(response.updates["owner"], "me");
+        assert!(response.log.is_empty());
+        assert_eq!(response.value, None);
+    }
+
+    #[test]
+    fn access_value()
+    {
+        let response = Response::parse(r#"{"value": "alice"}"#).unwrap();
+        assert!(!response.allow);
+        assert_eq!(response.value.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn plain_boolean_is_allow()
+    {
+        assert!(Response::parse("true\n").unwrap().allow);
+        assert_eq!(Response::parse("false").unwrap(), Response::default());
+        assert!(Response::parse("yes").is_err());
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected()
+    {
+        assert!(Response::parse(r#"{"alow": true}"#).is_err());
+    }
+
+    #[test]
+    fn request_leaves_out_missing_context()
+    {
+        let request = Request {
+            operation: "Open".to_string(),
+            path: "/data/a.csv".into(),
+            ..Request::default()
+        };
+        let json = serde_json::to_string(&request).unwrap();
+        assert!(!json.contains("offset"));
+        assert_eq!(serde_json::from_str::<Request>(&json).unwrap(), request);
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    fmt,
+    io::ErrorKind,
+    os::unix::process::ExitStatusExt,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+};
+
+/*
+ * Why running a program failed, along with which program it was. Converts
+ * into an `std::io::Error` for callers that only want to pass it on.
+ */
+#[derive(Debug)]
+pub struct ExecError
+{
+    pub program: PathBuf,
+    pub kind:    ExecErrorKind,
+}
+
+#[derive(Debug)]
+pub enum ExecErrorKind
+{
+    // Starting, compiling or waiting for the program failed
+    Io(std::io::Error),
+    // It ran, and exited unsuccessfully
+    Status
+    {
+        code:   Option<i32>,
+        signal: Option<i32>,
+        stderr: String,
+    },
+    // A WebAssembly program trapped
+    Trap(String),
+    // It succeeded, but the output was not understood
+    Parse
+    {
+        reason: String, output: String
+    },
+}
+
+impl ExecError
+{
+    pub fn io(program: &Path, error: std::io::Error) -> Self
+    {
+        Self {
+            program: program.to_path_buf(),
+            kind:    ExecErrorKind::Io(error),
+        }
+    }
+
+    pub fn status(program: &Path, status: ExitStatus, stderr: &[u8]) -> Self
+    {
+        Self {
+            program: program.to_path_buf(),
+            kind:    ExecErrorKind::Status {
+                code:   status.code(),
+                signal: status.signal(),
+                stderr: String::from_utf8_lossy(stderr).trim().to_string(),
+            },
+        }
+    }
+
+    pub fn trap(program: &Path, trap: impl fmt::Display) -> Self
+    {
+        Self {
+            program: program.to_path_buf(),
+            kind:    ExecErrorKind::Trap(trap.to_string()),
+        }
+    }
+
+    pub fn parse(program: &Path, reason: impl fmt::Display, output: &str) -> Self
+    {
+        Self {
+            program: program.to_path_buf(),
+            kind:    ExecErrorKind::Parse {
+                reason: reason.to_string(),
+                output: output.to_string(),
+            },
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind
+    {
+        match &self.kind
+        {
+            ExecErrorKind::Io(e) => e.kind(),
+            ExecErrorKind::Parse {
+                ..
+            } => ErrorKind::InvalidData,
+            _ => ErrorKind::Other,
+        }
+    }
+
+    pub fn stderr(&self) -> Option<&str>
+    {
+        match &self.kind
+        {
+            ExecErrorKind::Status {
+                stderr, ..
+            } => Some(stderr),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ExecError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{}: ", self.program.display())?;
+        match &self.kind
+        {
+            ExecErrorKind::Io(e) => write!(f, "{}", e),
+            ExecErrorKind::Status {
+                code,
+                signal,
+                stderr,
+            } =>
+            {
+                match (code, signal)
+                {
+                    (Some(code), _) => write!(f, "exited with status {}", code)?,
+                    (_, Some(signal)) => write!(f, "killed by signal {}", signal)?,
+                    _ => write!(f, "failed")?,
+                }
+                match stderr.is_empty()
+                {
+                    true => Ok(()),
+                    false => write!(f, ": {}", stderr),
+                }
+            },
+            ExecErrorKind::Trap(trap) => write!(f, "trapped: {}", trap),
+            ExecErrorKind::Parse {
+                reason,
+                output,
+            } => write!(f, "could not parse {:?}: {}", output, reason),
+        }
+    }
+}
+
+impl std::error::Error for ExecError
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)>
+    {
+        match &self.kind
+        {
+            ExecErrorKind::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ExecError> for std::io::Error
+{
+    fn from(error: ExecError) -> Self
+    {
+        std::io::Error::new(error.kind(), error)
+    }
+}
@@ -4,36 +4,171 @@ use std::{
     str::FromStr,
 };
 
-pub struct Executable(PathBuf);
+mod cache;
+pub use cache::Cache;
 
-impl Executable
+mod contract;
+pub use contract::{Request, Response, METADATA_PREFIX};
+
+mod error;
+pub use error::{ExecError, ExecErrorKind};
+
+mod process;
+pub use process::ProcessExecutable;
+
+mod runtime;
+pub use runtime::{load, load_with, Runtime};
+
+mod sandbox;
+pub use sandbox::Sandbox;
+
+mod wasm;
+pub use wasm::WasmExecutable;
+
+/*
+ * A metadata program. The structured way to talk to one is `call`, which
+ * sends a JSON `Request` on its input and reads a JSON `Response` back.
+ * Backends decide what the sandbox means for them.
+ */
+pub trait Executable: Send + Sync
 {
-    pub fn from_path<P: AsRef<Path>>(path: P) -> std::io::Result<Self>
+    // Where the program came from, for errors
+    fn path(&self) -> &Path;
+
+    // Run with `arg` as argv[1] and `input` on stdin, giving what it printed
+    fn run(
+        &self,
+        arg: Option<String>,
+        input: &[u8],
+        env: &[(String, String)],
+        sandbox: &Sandbox,
+    ) -> Result<String, ExecError>;
+
+    /*
+     * The operation is still passed as argv[1], so programs that only care
+     * about the operation can skip reading the request.
+     */
+    fn call(
+        &self,
+        request: &Request,
+        env: &[(String, String)],
+        sandbox: &Sandbox,
+    ) -> Result<Response, ExecError>
+    {
+        let input = serde_json::to_vec(request).expect("serializing a request");
+        let output = self.run(Some(request.operation.clone()), &input, env, sandbox)?;
+        Response::parse(&output).map_err(|e| ExecError::parse(self.path(), e, &output))
+    }
+
+    fn exec<T>(&self, arg: Option<String>) -> Result<T, ExecError>
+    where
+        T: FromStr,
+        Self: Sized,
     {
-        Ok(Self(compile_file(path)?))
+        self.exec_in(arg, &[], &Sandbox::default())
     }
 
-    pub fn exec<T>(&self, arg: Option<String>) -> std::io::Result<T>
+    fn exec_in<T>(
+        &self,
+        arg: Option<String>,
+        env: &[(String, String)],
+        sandbox: &Sandbox,
+    ) -> Result<T, ExecError>
     where
         T: FromStr,
+        Self: Sized,
     {
-        run_file::<T, _>(&self.0, arg)
+        parse(self.path(), &self.run(arg, &[], env, sandbox)?)
     }
 
-    pub fn exec_void(&self) -> std::io::Result<()>
+    fn exec_void(&self) -> Result<(), ExecError>
+    where
+        Self: Sized,
     {
-        run_file_void(&self.0)
+        self.exec_void_in(&[], &Sandbox::default())
+    }
+
+    fn exec_void_in(&self, env: &[(String, String)], sandbox: &Sandbox) -> Result<(), ExecError>
+    where
+        Self: Sized,
+    {
+        self.run(None, &[], env, sandbox).map(|_| ())
     }
 }
 
-impl Drop for Executable
+impl<E: Executable + ?Sized> Executable for Box<E>
 {
-    fn drop(&mut self)
+    fn path(&self) -> &Path
+    {
+        (**self).path()
+    }
+
+    fn run(
+        &self,
+        arg: Option<String>,
+        input: &[u8],
+        env: &[(String, String)],
+        sandbox: &Sandbox,
+    ) -> Result<String, ExecError>
     {
-        std::fs::remove_file(&self.0).unwrap();
+        (**self).run(arg, input, env, sandbox)
     }
 }
 
+/*
+ * A program compiled with rustc and run as a sandboxed subprocess. The binary
+ * belongs to the `Cache` it was compiled in, so it is left in place when
+ * dropped.
+ */
+pub struct RustExecutable
+{
+    source: PathBuf,
+    binary: PathBuf,
+}
+
+impl RustExecutable
+{
+    pub fn from_path<P: AsRef<Path>>(path: P) -> std::io::Result<Self>
+    {
+        Self::from_path_in(path, &Cache::default())
+    }
+
+    pub fn from_path_in<P: AsRef<Path>>(path: P, cache: &Cache) -> std::io::Result<Self>
+    {
+        Ok(Self {
+            source: path.as_ref().to_path_buf(),
+            binary: cache.compile(path)?,
+        })
+    }
+}
+
+impl Executable for RustExecutable
+{
+    fn path(&self) -> &Path
+    {
+        &self.source
+    }
+
+    fn run(
+        &self,
+        arg: Option<String>,
+        input: &[u8],
+        env: &[(String, String)],
+        sandbox: &Sandbox,
+    ) -> Result<String, ExecError>
+    {
+        run_output(&self.binary, None, arg, input, env, sandbox)
+            .map_err(|e| ExecError { program: self.source.clone(), ..e })
+    }
+}
+
+
+fn parse<T: FromStr>(program: &Path, output: &str) -> Result<T, ExecError>
+{
+    output.trim().parse::<T>().map_err(|_| {
+        ExecError::parse(program, format!("expected a {}", std::any::type_name::<T>()), output)
+    })
+}
 
 pub fn compile_file<P: AsRef<Path>>(path: P) -> std::io::Result<PathBuf>
 {
@@ -60,69 +195,112 @@ pub fn compile_file<P: AsRef<Path>>(path: P) -> std::io::Result<PathBuf>
             }
             else
             {
-                Err(std::io::Error::from_raw_os_error(output.status.code().unwrap()))
+                Err(ExecError::status(path, output.status, &output.stderr).into())
             }
         })
 }
 
-pub fn run_file_void<P>(path: P) -> std::io::Result<()>
+pub fn run_file_void<P>(path: P) -> Result<(), ExecError>
 where
     P: AsRef<Path>,
 {
-    let path = path.as_ref();
-    Command::new(path.as_os_str()).output().map(|_| ())
+    run_file_void_in(path, &[], &Sandbox::default())
 }
 
-pub fn run_file<T, P>(path: P, arg: Option<String>) -> std::io::Result<T>
+pub fn run_file_void_in<P>(
+    path: P,
+    env: &[(String, String)],
+    sandbox: &Sandbox,
+) -> Result<(), ExecError>
+where
+    P: AsRef<Path>,
+{
+    run_output(path.as_ref(), None, None, &[], env, sandbox).map(|_| ())
+}
+
+pub fn run_file<T, P>(path: P, arg: Option<String>) -> Result<T, ExecError>
+where
+    P: AsRef<Path>,
+    T: FromStr,
+{
+    run_file_in(path, arg, &[], &Sandbox::default())
+}
+
+pub fn run_file_in<T, P>(
+    path: P,
+    arg: Option<String>,
+    env: &[(String, String)],
+    sandbox: &Sandbox,
+) -> Result<T, ExecError>
 where
     P: AsRef<Path>,
     T: FromStr,
 {
     let path = path.as_ref();
-    let mut command = Command::new(path.as_os_str());
+    parse(path, &run_output(path, None, arg, &[], env, sandbox)?)
+}
+
+// Runs `program`, or `program script` for interpreters, blaming the script
+fn run_output(
+    program: &Path,
+    script: Option<&Path>,
+    arg: Option<String>,
+    input: &[u8],
+    env: &[(String, String)],
+    sandbox: &Sandbox,
+) -> Result<String, ExecError>
+{
+    let mut command = Command::new(program.as_os_str());
+    command.envs(env.iter().cloned());
+
+    let mut programs = vec![program];
+    if let Some(script) = script
+    {
+        command.arg(script);
+        programs.push(script);
+    }
     if let Some(arg) = arg
     {
         command.arg(arg);
     }
 
-    command.output().and_then(|output| {
-        if output.status.success()
-        {
-            const EINVAL: i32 = 22;
-            let s = std::str::from_utf8(&output.stdout).unwrap().trim();
-            s.parse::<T>().map_err(|_| std::io::Error::from_raw_os_error(EINVAL))
-        }
-        else
-        {
-            Err(std::io::Error::from_raw_os_error(output.status.code().unwrap()))
-        }
-    })
+    let blame = script.unwrap_or(program);
+    let output = sandbox.output(command, &programs, input).map_err(|e| ExecError::io(blame, e))?;
+    if output.status.success()
+    {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+    else
+    {
+        Err(ExecError::status(blame, output.status, &output.stderr))
+    }
 }
 
 
 #[cfg(test)]
 mod tests
 {
-    use std::{ffi::OsString, io::Write};
+    use std::io::Write;
 
     use tempfile::Builder;
 
     use super::*;
 
 
-    struct Guard(OsString);
+    // Removes the compiled binary next to the source
+    struct Guard(PathBuf);
     impl Guard
     {
         fn new(path: impl AsRef<Path>) -> Self
         {
-            Self(path.as_ref().file_stem().unwrap().to_os_string())
+            Self(path.as_ref().with_extension(""))
         }
     }
     impl Drop for Guard
     {
         fn drop(&mut self)
         {
-            //std::fs::remove_file(&self.0).unwrap();
+            let _ = std::fs::remove_file(&self.0);
         }
     }
 
@@ -154,10 +332,26 @@ mod tests
         let file = Builder::new().prefix("temp_").suffix(".rs").rand_bytes(5).tempfile().unwrap();
         writeln!(file.as_file(), "fn main(){{println!(\"12\")}}").unwrap();
 
-        let exec = Executable::from_path(file.path()).unwrap();
+        let exec = RustExecutable::from_path(file.path()).unwrap();
         assert_eq!(exec.exec::<i32>(None).unwrap(), 12);
     }
 
+    #[test]
+    fn drop_keeps_cached_binary()
+    {
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache = Cache::new(dir.path());
+        let file = Builder::new().prefix("temp_").suffix(".rs").rand_bytes(5).tempfile().unwrap();
+        writeln!(file.as_file(), "fn main(){{println!(\"3\")}}").unwrap();
+
+        let binary = RustExecutable::from_path_in(file.path(), &cache).unwrap().binary;
+        assert!(binary.exists());
+
+        let exec = RustExecutable::from_path_in(file.path(), &cache).unwrap();
+        assert_eq!(exec.binary, binary);
+        assert_eq!(exec.exec::<i32>(None).unwrap(), 3);
+    }
+
     #[test]
     fn test_execute_with_arg()
     {
@@ -172,7 +366,75 @@ mod tests
         .unwrap();
 
         let arg = String::from("foo");
-        let exec = Executable::from_path(file.path()).unwrap();
+        let exec = RustExecutable::from_path(file.path()).unwrap();
         assert_eq!(exec.exec::<String>(Some(arg.clone())).unwrap(), arg);
     }
+
+    #[test]
+    fn test_execute_with_env()
+    {
+        let file = Builder::new().prefix("temp_").suffix(".rs").rand_bytes(5).tempfile().unwrap();
+        writeln!(file.as_file(), "fn main(){{println!(\"{{}}\", std::env::var(\"FOO\").unwrap())}}")
+            .unwrap();
+
+        let env = [("FOO".to_string(), "bar".to_string())];
+        let exec = RustExecutable::from_path(file.path()).unwrap();
+        assert_eq!(exec.exec_in::<String>(None, &env, &Sandbox::default()).unwrap(), "bar");
+    }
+
+    #[test]
+    fn call_sends_request_on_stdin()
+    {
+        let file = Builder::new().prefix("temp_").suffix(".rs").rand_bytes(5).tempfile().unwrap();
+        std::fs::write(
+            file.path(),
+            r#"use std::io::Read;
+            fn main(){
+                let mut request = String::new();
+                std::io::stdin().read_to_string(&mut request).unwrap();
+                let allow = request.contains("/data/a.csv");
+                let operation = std::env::args().nth(1).unwrap();
+                println!("{{\"allow\": {}, \"log\": [\"saw {}\"]}}", allow, operation);
+            }"#,
+        )
+        .unwrap();
+
+        let exec = RustExecutable::from_path(file.path()).unwrap();
+        let request = Request {
+            operation: "Open".to_string(),
+            path: "/data/a.csv".into(),
+            ..Request::default()
+        };
+        let response = exec.call(&request, &[], &Sandbox::default()).unwrap();
+        assert!(response.allow);
+        assert_eq!(response.log, vec!["saw Open".to_string()]);
+    }
+
+    #[test]
+    fn failure_carries_stderr()
+    {
+        let file = Builder::new().prefix("temp_").suffix(".rs").rand_bytes(5).tempfile().unwrap();
+        let source = "fn main(){{eprintln!(\"boom\"); std::process::exit(3)}}";
+        writeln!(file.as_file(), "{}", source).unwrap();
+
+        let exec = RustExecutable::from_path(file.path()).unwrap();
+        let err = exec.call(&Request::default(), &[], &Sandbox::default()).unwrap_err();
+
+        assert_eq!(err.program, file.path());
+        assert_eq!(err.stderr(), Some("boom"));
+        assert!(matches!(err.kind, ExecErrorKind::Status { code: Some(3), .. }));
+    }
+
+    #[test]
+    fn bad_response_is_a_parse_error()
+    {
+        let file = Builder::new().prefix("temp_").suffix(".rs").rand_bytes(5).tempfile().unwrap();
+        writeln!(file.as_file(), "fn main(){{println!(\"yes\")}}").unwrap();
+
+        let exec = RustExecutable::from_path(file.path()).unwrap();
+        let err = exec.call(&Request::default(), &[], &Sandbox::default()).unwrap_err();
+
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(matches!(&err.kind, ExecErrorKind::Parse { output, .. } if output.trim() == "yes"));
+    }
 }
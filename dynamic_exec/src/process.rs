@@ -0,0 +1,72 @@
+use std::{
+    io::{Error, ErrorKind},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{run_output, ExecError, Executable, Sandbox};
+
+/*
+ * A program that needs no building, either a prebuilt binary or a script
+ * given to an interpreter, run as a sandboxed subprocess.
+ */
+pub struct ProcessExecutable
+{
+    source:      PathBuf,
+    interpreter: Option<PathBuf>,
+}
+
+impl ProcessExecutable
+{
+    pub fn binary<P: AsRef<Path>>(path: P) -> std::io::Result<Self>
+    {
+        let source = path.as_ref().canonicalize()?;
+        if std::fs::metadata(&source)?.permissions().mode() & 0o111 == 0
+        {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("{} is not executable", source.display()),
+            ));
+        }
+
+        Ok(Self {
+            source,
+            interpreter: None,
+        })
+    }
+
+    pub fn script<P: AsRef<Path>>(path: P, interpreter: impl AsRef<Path>) -> std::io::Result<Self>
+    {
+        Ok(Self {
+            source:      path.as_ref().canonicalize()?,
+            // Canonical, as the sandbox only has the real file
+            interpreter: Some(interpreter.as_ref().canonicalize()?),
+        })
+    }
+}
+
+impl Executable for ProcessExecutable
+{
+    fn path(&self) -> &Path
+    {
+        &self.source
+    }
+
+    fn run(
+        &self,
+        arg: Option<String>,
+        input: &[u8],
+        env: &[(String, String)],
+        sandbox: &Sandbox,
+    ) -> Result<String, ExecError>
+    {
+        match &self.interpreter
+        {
+            Some(interpreter) =>
+            {
+                run_output(interpreter, Some(&self.source), arg, input, env, sandbox)
+            },
+            None => run_output(&self.source, None, arg, input, env, sandbox),
+        }
+    }
+}
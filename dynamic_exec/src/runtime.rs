@@ -0,0 +1,170 @@
+use std::{
+    io::{Error, ErrorKind},
+    path::Path,
+    str::FromStr,
+};
+
+use serde::Deserialize;
+
+use crate::{Cache, Executable, ProcessExecutable, RustExecutable, WasmExecutable};
+
+const SHELL: &str = "/bin/sh";
+const PYTHON: &str = "/usr/bin/python3";
+
+/*
+ * How a program is built and run.
+ */
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Runtime
+{
+    // Source compiled with rustc
+    Rust,
+    // Anything executable as is
+    Binary,
+    // A script for `/bin/sh`
+    Shell,
+    // A script for `/usr/bin/python3`
+    Python,
+    // WebAssembly, binary or text, run in-process
+    Wasm,
+}
+
+impl Runtime
+{
+    // Guessed from the extension, where anything unknown is taken as a binary
+    pub fn from_path(path: &Path) -> Self
+    {
+        match path.extension().and_then(|e| e.to_str())
+        {
+            Some("rs") => Runtime::Rust,
+            Some("sh") => Runtime::Shell,
+            Some("py") => Runtime::Python,
+            Some("wasm" | "wat") => Runtime::Wasm,
+            _ => Runtime::Binary,
+        }
+    }
+}
+
+impl FromStr for Runtime
+{
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        match s
+        {
+            "rust" => Ok(Runtime::Rust),
+            "binary" => Ok(Runtime::Binary),
+            "shell" => Ok(Runtime::Shell),
+            "python" => Ok(Runtime::Python),
+            "wasm" => Ok(Runtime::Wasm),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown runtime {:?}", s))),
+        }
+    }
+}
+
+pub fn load<P: AsRef<Path>>(path: P, cache: &Cache) -> std::io::Result<Box<dyn Executable>>
+{
+    let path = path.as_ref();
+    load_with(Runtime::from_path(path), path, cache)
+}
+
+pub fn load_with<P: AsRef<Path>>(
+    runtime: Runtime,
+    path: P,
+    cache: &Cache,
+) -> std::io::Result<Box<dyn Executable>>
+{
+    let path = path.as_ref();
+    Ok(match runtime
+    {
+        Runtime::Rust => Box::new(RustExecutable::from_path_in(path, cache)?),
+        Runtime::Binary => Box::new(ProcessExecutable::binary(path)?),
+        Runtime::Shell => Box::new(ProcessExecutable::script(path, SHELL)?),
+        Runtime::Python => Box::new(ProcessExecutable::script(path, PYTHON)?),
+        Runtime::Wasm => Box::new(WasmExecutable::from_path(path)?),
+    })
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use std::os::unix::fs::PermissionsExt;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{Request, Sandbox};
+
+    fn request() -> Request
+    {
+        Request {
+            operation: "Open".to_string(),
+            ..Request::default()
+        }
+    }
+
+    #[test]
+    fn runtime_from_extension()
+    {
+        assert_eq!(Runtime::from_path(Path::new("check.py")), Runtime::Python);
+        assert_eq!(Runtime::from_path(Path::new("check.wat")), Runtime::Wasm);
+        assert_eq!(Runtime::from_path(Path::new("check")), Runtime::Binary);
+        assert_eq!("shell".parse::<Runtime>().unwrap(), Runtime::Shell);
+        assert!("perl".parse::<Runtime>().is_err());
+    }
+
+    #[test]
+    fn shell_script()
+    {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("check.sh");
+        std::fs::write(
+            &path,
+            r#"read request
+            if [ "$1" = Open ]; then echo '{"allow": true}'; else echo '{"allow": false}'; fi
+            "#,
+        )
+        .unwrap();
+
+        let exec = load(&path, &Cache::new(dir.path())).unwrap();
+        assert!(exec.call(&request(), &[], &Sandbox::default()).unwrap().allow);
+    }
+
+    #[test]
+    fn python_script()
+    {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("check.py");
+        std::fs::write(
+            &path,
+            r#"import json, sys
+request = json.load(sys.stdin)
+print(json.dumps({"allow": request["operation"] == "Open", "log": ["hi"]}))
+"#,
+        )
+        .unwrap();
+
+        let exec = load(&path, &Cache::new(dir.path())).unwrap();
+        let response = exec.call(&request(), &[], &Sandbox::default()).unwrap();
+        assert!(response.allow);
+        assert_eq!(response.log, vec!["hi".to_string()]);
+    }
+
+    #[test]
+    fn prebuilt_binary()
+    {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("check");
+        std::fs::write(&path, "#!/bin/sh\necho '{\"allow\": true}'\n").unwrap();
+
+        let cache = Cache::new(dir.path());
+        assert_eq!(load(&path, &cache).err().unwrap().kind(), ErrorKind::PermissionDenied);
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let exec = load(&path, &cache).unwrap();
+        assert!(exec.call(&request(), &[], &Sandbox::disabled()).unwrap().allow);
+    }
+}
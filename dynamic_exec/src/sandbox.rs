@@ -0,0 +1,825 @@
+use std::{
+    collections::BTreeSet,
+    ffi::CString,
+    io::{Error, ErrorKind, Write},
+    os::unix::{ffi::OsStrExt, process::CommandExt},
+    path::{Path, PathBuf},
+    process::{Child, Command, Output, Stdio},
+    sync::mpsc,
+    time::Duration,
+};
+
+use crate::cache::trusted;
+
+const NOBODY: u32 = 65534;
+
+// Where the sandbox is put together, a folder only root may enter
+const ROOT: &str = "/run/gurret-sandbox";
+
+// Needed by every dynamically linked program, always visible but read-only
+const SYSTEM_PATHS: &[&str] = &["/lib", "/lib64", "/usr/lib", "/usr/lib64", "/etc/ld.so.cache"];
+const DEVICES: &[&str] = &["/dev/null", "/dev/zero", "/dev/urandom"];
+
+/*
+ * The restrictions a program is run under. The default drops to `nobody` in
+ * fresh namespaces when we are root, where only the program, the system
+ * libraries and the `visible` paths exist. Without root only the limits, the
+ * timeout and the seccomp filter apply.
+ */
+#[derive(Debug, Clone)]
+pub struct Sandbox
+{
+    pub uid:             Option<u32>,
+    pub gid:             Option<u32>,
+    pub namespaces:      bool,
+    pub seccomp:         bool,
+    pub cpu_seconds:     Option<u64>,
+    pub memory_bytes:    Option<u64>,
+    pub file_size_bytes: Option<u64>,
+    pub processes:       Option<u64>,
+    pub timeout:         Option<Duration>,
+    pub visible:         Vec<PathBuf>,
+}
+
+impl Default for Sandbox
+{
+    fn default() -> Self
+    {
+        let root = unsafe { libc::geteuid() } == 0;
+        Self {
+            uid:             root.then_some(NOBODY),
+            gid:             root.then_some(NOBODY),
+            namespaces:      root,
+            seccomp:         true,
+            cpu_seconds:     Some(5),
+            memory_bytes:    Some(512 << 20),
+            file_size_bytes: Some(16 << 20),
+            processes:       Some(64),
+            timeout:         Some(Duration::from_secs(10)),
+            visible:         Vec::new(),
+        }
+    }
+}
+
+impl Sandbox
+{
+    // Run with the same privileges as ourselves
+    pub fn disabled() -> Self
+    {
+        Self {
+            uid:             None,
+            gid:             None,
+            namespaces:      false,
+            seccomp:         false,
+            cpu_seconds:     None,
+            memory_bytes:    None,
+            file_size_bytes: None,
+            processes:       None,
+            timeout:         None,
+            visible:         Vec::new(),
+        }
+    }
+
+    pub fn visible(mut self, path: impl Into<PathBuf>) -> Self
+    {
+        self.visible.push(path.into());
+        self
+    }
+
+    /*
+     * Run `command` with `input` on its stdin. The `programs` are the files
+     * it needs to start, like the binary, or an interpreter and its script,
+     * and are made visible read-only.
+     */
+    pub fn output(&self, mut command: Command, programs: &[&Path], input: &[u8])
+        -> std::io::Result<Output>
+    {
+        let program = programs.first().copied().unwrap_or_else(|| Path::new(""));
+        let setup = Setup::new(self, programs)?;
+
+        unsafe {
+            command.pre_exec(move || setup.apply());
+        }
+        command
+            .process_group(0)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        let mut child = command.spawn()?;
+
+        // Written from a thread, the program may not read before it prints
+        let mut stdin = child.stdin.take().expect("piped stdin");
+        let input = input.to_vec();
+        std::thread::spawn(move || {
+            // Programs are free to ignore their input
+            let _ = stdin.write_all(&input);
+        });
+
+        self.wait(child, program)
+    }
+
+    fn wait(&self, child: Child, program: &Path) -> std::io::Result<Output>
+    {
+        let timeout = match self.timeout
+        {
+            Some(timeout) => timeout,
+            None => return child.wait_with_output(),
+        };
+
+        let pid = child.id() as libc::pid_t;
+        let (tx, rx) = mpsc::channel();
+        let waiter = std::thread::spawn(move || {
+            let output = child.wait_with_output();
+            let _ = tx.send(());
+            output
+        });
+
+        let timed_out = rx.recv_timeout(timeout).is_err();
+        if timed_out
+        {
+            // The program is the leader of its own process group
+            unsafe {
+                libc::kill(-pid, libc::SIGKILL);
+            }
+        }
+
+        let output = waiter.join().expect("joining sandboxed program")?;
+        if timed_out
+        {
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("{} ran for longer than {:?}", program.display(), timeout),
+            ));
+        }
+        Ok(output)
+    }
+}
+
+
+enum Step
+{
+    Dir(CString),
+    File(CString),
+    Symlink
+    {
+        target: CString,
+        at:     CString,
+    },
+    Bind
+    {
+        source:   CString,
+        at:       CString,
+        readonly: bool,
+    },
+}
+
+/*
+ * Everything the child needs, prepared up front since nothing may be
+ * allocated between fork and exec.
+ */
+struct Setup
+{
+    limits:     Vec<(libc::__rlimit_resource_t, u64)>,
+    namespaces: bool,
+    root:       CString,
+    steps:      Vec<Step>,
+    uid:        Option<u32>,
+    gid:        Option<u32>,
+    filter:     Option<Vec<libc::sock_filter>>,
+}
+
+// The fields are only read between fork and exec
+unsafe impl Send for Setup {}
+unsafe impl Sync for Setup {}
+
+fn cstring(path: &Path) -> std::io::Result<CString>
+{
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("{:?} contains nul", path)))
+}
+
+impl Setup
+{
+    fn new(sandbox: &Sandbox, programs: &[&Path]) -> std::io::Result<Self>
+    {
+        let limits = [
+            (libc::RLIMIT_CPU, sandbox.cpu_seconds),
+            (libc::RLIMIT_AS, sandbox.memory_bytes),
+            (libc::RLIMIT_FSIZE, sandbox.file_size_bytes),
+            (libc::RLIMIT_NPROC, sandbox.processes),
+        ]
+        .into_iter()
+        .filter_map(|(resource, limit)| limit.map(|l| (resource, l)))
+        .collect();
+
+        let root = PathBuf::from(ROOT);
+        let mut steps = Vec::new();
+
+        if sandbox.namespaces
+        {
+            trusted(&root)?;
+
+            let mut dirs = BTreeSet::new();
+            let mut entries: Vec<(PathBuf, bool)> = SYSTEM_PATHS
+                .iter()
+                .chain(DEVICES)
+                .map(|p| (PathBuf::from(p), true))
+                .filter(|(p, _)| p.exists())
+                .collect();
+            for program in programs
+            {
+                entries.push((program.canonicalize()?, true));
+            }
+            for path in &sandbox.visible
+            {
+                entries.push((path.canonicalize()?, false));
+            }
+
+            for (path, readonly) in entries
+            {
+                let at = root.join(path.strip_prefix("/").unwrap_or(&path));
+                let parents: Vec<_> = at
+                    .ancestors()
+                    .skip(1)
+                    .take_while(|d| d.starts_with(&root) && *d != root)
+                    .collect();
+                for dir in parents.into_iter().rev()
+                {
+                    if dirs.insert(dir.to_path_buf())
+                    {
+                        steps.push(Step::Dir(cstring(dir)?));
+                    }
+                }
+
+                let meta = std::fs::symlink_metadata(&path)?;
+                if meta.file_type().is_symlink() && readonly
+                {
+                    steps.push(Step::Symlink {
+                        target: cstring(&std::fs::read_link(&path)?)?,
+                        at:     cstring(&at)?,
+                    });
+                    continue;
+                }
+
+                if path.is_dir()
+                {
+                    if dirs.insert(at.clone())
+                    {
+                        steps.push(Step::Dir(cstring(&at)?));
+                    }
+                }
+                else
+                {
+                    steps.push(Step::File(cstring(&at)?));
+                }
+                steps.push(Step::Bind {
+                    source: cstring(&path)?,
+                    at: cstring(&at)?,
+                    readonly,
+                });
+            }
+        }
+
+        Ok(Self {
+            limits,
+            namespaces: sandbox.namespaces,
+            root: cstring(&root)?,
+            steps,
+            uid: sandbox.uid,
+            gid: sandbox.gid,
+            filter: if sandbox.seccomp { Some(seccomp::filter()?) } else { None },
+        })
+    }
+
+    // Runs in the child, between fork and exec
+    fn apply(&self) -> std::io::Result<()>
+    {
+        let check = |ret: libc::c_int| {
+            if ret < 0
+            {
+                Err(Error::last_os_error())
+            }
+            else
+            {
+                Ok(())
+            }
+        };
+
+        unsafe {
+            for &(resource, limit) in &self.limits
+            {
+                let rlimit = libc::rlimit {
+                    rlim_cur: limit,
+                    rlim_max: limit,
+                };
+                check(libc::setrlimit(resource, &rlimit))?;
+            }
+
+            if self.namespaces
+            {
+                check(libc::unshare(
+                    libc::CLONE_NEWNS
+                        | libc::CLONE_NEWNET
+                        | libc::CLONE_NEWIPC
+                        | libc::CLONE_NEWUTS
+                        | libc::CLONE_NEWPID,
+                ))?;
+                Self::enter_pid_namespace()?;
+                check(libc::mount(
+                    std::ptr::null(),
+                    c"/".as_ptr(),
+                    std::ptr::null(),
+                    libc::MS_REC | libc::MS_PRIVATE,
+                    std::ptr::null(),
+                ))?;
+                check(libc::mount(
+                    c"tmpfs".as_ptr(),
+                    self.root.as_ptr(),
+                    c"tmpfs".as_ptr(),
+                    libc::MS_NOSUID | libc::MS_NODEV,
+                    c"size=1m,mode=755".as_ptr().cast(),
+                ))?;
+
+                for step in &self.steps
+                {
+                    self.step(step)?;
+                }
+
+                check(libc::chroot(self.root.as_ptr()))?;
+                check(libc::chdir(c"/".as_ptr()))?;
+            }
+
+            if let Some(gid) = self.gid
+            {
+                check(libc::setgroups(0, std::ptr::null()))?;
+                check(libc::setgid(gid))?;
+            }
+            if let Some(uid) = self.uid
+            {
+                check(libc::setuid(uid))?;
+            }
+
+            if let Some(filter) = &self.filter
+            {
+                let program = libc::sock_fprog {
+                    len:    filter.len() as libc::c_ushort,
+                    filter: filter.as_ptr() as *mut libc::sock_filter,
+                };
+                check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+                check(libc::prctl(
+                    libc::PR_SET_SECCOMP,
+                    libc::SECCOMP_MODE_FILTER,
+                    &program as *const libc::sock_fprog,
+                ))?;
+            }
+        }
+        Ok(())
+    }
+
+    /*
+     * Only our children end up in the new pid namespace, so fork once more.
+     * The child goes on to be the program, the parent waits for it and ends
+     * the same way.
+     */
+    unsafe fn enter_pid_namespace() -> std::io::Result<()>
+    {
+        let child = libc::fork();
+        if child < 0
+        {
+            return Err(Error::last_os_error());
+        }
+        if child == 0
+        {
+            // The timeout kills our process group, this is in case we die another way
+            libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+            return Ok(());
+        }
+
+        /*
+         * We hold the pipe spawn waits on until the exec, close it so it does
+         * not wait for the whole run.
+         */
+        libc::syscall(libc::SYS_close_range, 3, libc::c_uint::MAX, 0);
+        let mut status = 0;
+        while libc::waitpid(child, &mut status, 0) < 0 && *libc::__errno_location() == libc::EINTR
+        {
+        }
+        if libc::WIFSIGNALED(status)
+        {
+            let signal = libc::WTERMSIG(status);
+            libc::signal(signal, libc::SIG_DFL);
+            libc::kill(libc::getpid(), signal);
+        }
+        libc::_exit(libc::WEXITSTATUS(status))
+    }
+
+    unsafe fn step(&self, step: &Step) -> std::io::Result<()>
+    {
+        let ret = match step
+        {
+            Step::Dir(at) => match libc::mkdir(at.as_ptr(), 0o755)
+            {
+                -1 if *libc::__errno_location() == libc::EEXIST => 0,
+                ret => ret,
+            },
+            Step::File(at) =>
+            {
+                let flags = libc::O_CREAT | libc::O_WRONLY | libc::O_CLOEXEC;
+                let fd = libc::open(at.as_ptr(), flags, 0o644);
+                if fd >= 0
+                {
+                    libc::close(fd);
+                }
+                fd
+            },
+            Step::Symlink {
+                target,
+                at,
+            } => libc::symlink(target.as_ptr(), at.as_ptr()),
+            Step::Bind {
+                source,
+                at,
+                readonly,
+            } =>
+            {
+                let flags = libc::MS_BIND | libc::MS_REC;
+                let mut ret = libc::mount(
+                    source.as_ptr(),
+                    at.as_ptr(),
+                    std::ptr::null(),
+                    flags,
+                    std::ptr::null(),
+                );
+                if ret == 0 && *readonly
+                {
+                    ret = libc::mount(
+                        std::ptr::null(),
+                        at.as_ptr(),
+                        std::ptr::null(),
+                        flags | libc::MS_REMOUNT | libc::MS_RDONLY,
+                        std::ptr::null(),
+                    );
+                }
+                ret
+            },
+        };
+
+        if ret < 0
+        {
+            Err(Error::last_os_error())
+        }
+        else
+        {
+            Ok(())
+        }
+    }
+}
+
+
+mod seccomp
+{
+    use std::io::{Error, ErrorKind};
+
+    const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+    const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+    const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+
+    // Classic BPF opcodes, from linux/bpf_common.h
+    const BPF_LD: u32 = 0x00;
+    const BPF_JMP: u32 = 0x05;
+    const BPF_RET: u32 = 0x06;
+    const BPF_W: u32 = 0x00;
+    const BPF_ABS: u32 = 0x20;
+    const BPF_JEQ: u32 = 0x10;
+    const BPF_K: u32 = 0x00;
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    const AUDIT_ARCH: Option<u32> = None;
+
+    // Offsets into `struct seccomp_data`
+    const NR_OFFSET: u32 = 0;
+    const ARCH_OFFSET: u32 = 4;
+    // The low half of args[1], both architectures are little endian
+    const ARG1_OFFSET: u32 = 24;
+
+    // The only ioctls allowed, interpreters set close-on-exec with these
+    const FIONCLEX: u32 = 0x5450;
+    const FIOCLEX: u32 = 0x5451;
+
+    /*
+     * What a program may do: use the memory, files and threads it has, read
+     * and write files and their xattrs, and exit. Everything else, like
+     * removing files, sockets or mounting, fails with EPERM.
+     */
+    fn allowed() -> Vec<libc::c_long>
+    {
+        let mut allowed = vec![
+            libc::SYS_read,
+            libc::SYS_write,
+            libc::SYS_readv,
+            libc::SYS_writev,
+            libc::SYS_pread64,
+            libc::SYS_pwrite64,
+            libc::SYS_openat,
+            libc::SYS_close,
+            libc::SYS_close_range,
+            libc::SYS_lseek,
+            libc::SYS_fstat,
+            libc::SYS_newfstatat,
+            libc::SYS_statx,
+            libc::SYS_faccessat,
+            libc::SYS_faccessat2,
+            libc::SYS_readlinkat,
+            libc::SYS_getdents64,
+            libc::SYS_getcwd,
+            libc::SYS_fcntl,
+            libc::SYS_dup,
+            libc::SYS_dup3,
+            libc::SYS_pipe2,
+            libc::SYS_ppoll,
+            libc::SYS_fsync,
+            libc::SYS_fdatasync,
+            libc::SYS_ftruncate,
+            libc::SYS_getxattr,
+            libc::SYS_lgetxattr,
+            libc::SYS_fgetxattr,
+            libc::SYS_setxattr,
+            libc::SYS_lsetxattr,
+            libc::SYS_fsetxattr,
+            libc::SYS_listxattr,
+            libc::SYS_llistxattr,
+            libc::SYS_flistxattr,
+            libc::SYS_brk,
+            libc::SYS_mmap,
+            libc::SYS_munmap,
+            libc::SYS_mremap,
+            libc::SYS_mprotect,
+            libc::SYS_madvise,
+            libc::SYS_membarrier,
+            libc::SYS_rt_sigaction,
+            libc::SYS_rt_sigprocmask,
+            libc::SYS_rt_sigreturn,
+            libc::SYS_sigaltstack,
+            libc::SYS_futex,
+            libc::SYS_set_robust_list,
+            libc::SYS_set_tid_address,
+            libc::SYS_clone,
+            libc::SYS_clone3,
+            libc::SYS_execve,
+            libc::SYS_wait4,
+            libc::SYS_exit,
+            libc::SYS_exit_group,
+            libc::SYS_tgkill,
+            libc::SYS_getpid,
+            libc::SYS_gettid,
+            libc::SYS_getppid,
+            libc::SYS_getuid,
+            libc::SYS_geteuid,
+            libc::SYS_getgid,
+            libc::SYS_getegid,
+            libc::SYS_uname,
+            libc::SYS_prlimit64,
+            libc::SYS_getrandom,
+            libc::SYS_sched_getaffinity,
+            libc::SYS_sched_yield,
+            libc::SYS_clock_gettime,
+            libc::SYS_clock_getres,
+            libc::SYS_clock_nanosleep,
+            libc::SYS_nanosleep,
+            libc::SYS_gettimeofday,
+        ];
+
+        #[cfg(target_arch = "x86_64")]
+        allowed.extend([
+            libc::SYS_open,
+            libc::SYS_stat,
+            libc::SYS_lstat,
+            libc::SYS_access,
+            libc::SYS_readlink,
+            libc::SYS_poll,
+            libc::SYS_dup2,
+            libc::SYS_pipe,
+            libc::SYS_arch_prctl,
+            libc::SYS_time,
+        ]);
+
+        allowed
+    }
+
+    fn statement(code: u32, k: u32) -> libc::sock_filter
+    {
+        libc::sock_filter {
+            code: code as u16,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter
+    {
+        libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    pub fn filter() -> std::io::Result<Vec<libc::sock_filter>>
+    {
+        let arch = AUDIT_ARCH.ok_or_else(|| {
+            Error::new(ErrorKind::Unsupported, "seccomp is not supported on this architecture")
+        })?;
+
+        let load = BPF_LD | BPF_W | BPF_ABS;
+        let equal = BPF_JMP | BPF_JEQ | BPF_K;
+        let ret = BPF_RET | BPF_K;
+
+        // Programs of another architecture would have other syscall numbers
+        let mut filter = vec![
+            statement(load, ARCH_OFFSET),
+            jump(equal, arch, 1, 0),
+            statement(ret, SECCOMP_RET_KILL_PROCESS),
+            statement(load, NR_OFFSET),
+        ];
+        for nr in allowed()
+        {
+            filter.push(jump(equal, nr as u32, 0, 1));
+            filter.push(statement(ret, SECCOMP_RET_ALLOW));
+        }
+
+        // Any other ioctl is answered as if nothing was a terminal
+        filter.extend([
+            jump(equal, libc::SYS_ioctl as u32, 0, 5),
+            statement(load, ARG1_OFFSET),
+            jump(equal, FIOCLEX, 2, 0),
+            jump(equal, FIONCLEX, 1, 0),
+            statement(ret, SECCOMP_RET_ERRNO | libc::ENOTTY as u32),
+            statement(ret, SECCOMP_RET_ALLOW),
+        ]);
+        filter.push(statement(ret, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+
+        Ok(filter)
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use std::io::Write;
+
+    use tempfile::{Builder, TempDir};
+
+    use super::*;
+    use crate::{compile_file, run_file_in};
+
+    fn is_root() -> bool
+    {
+        unsafe { libc::geteuid() == 0 }
+    }
+
+    fn compile(dir: &Path, source: &str) -> PathBuf
+    {
+        let mut file = Builder::new().prefix("temp_").suffix(".rs").tempfile_in(dir).unwrap();
+        write!(file, "{}", source).unwrap();
+        let (_, path) = file.keep().unwrap();
+        let binary = compile_file(&path).unwrap();
+
+        // `nobody` has to be able to reach the binary
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        binary
+    }
+
+    #[test]
+    fn infinite_loop_is_killed()
+    {
+        let dir = TempDir::new().unwrap();
+        let binary = compile(dir.path(), "fn main(){ loop {} }");
+
+        let sandbox = Sandbox {
+            timeout: Some(Duration::from_millis(500)),
+            ..Sandbox::default()
+        };
+        let start = std::time::Instant::now();
+        let err = run_file_in::<String, _>(&binary, None, &[], &sandbox).unwrap_err();
+
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn cpu_limit_kills_without_timeout()
+    {
+        let dir = TempDir::new().unwrap();
+        let binary = compile(dir.path(), "fn main(){ loop {} }");
+
+        let sandbox = Sandbox {
+            timeout: None,
+            cpu_seconds: Some(1),
+            ..Sandbox::default()
+        };
+        assert!(run_file_in::<String, _>(&binary, None, &[], &sandbox).is_err());
+    }
+
+    #[test]
+    fn rm_rf_is_contained()
+    {
+        let dir = TempDir::new().unwrap();
+        let victim = TempDir::new().unwrap();
+        std::fs::write(victim.path().join("data"), "precious").unwrap();
+
+        let source = format!(
+            "fn main(){{
+                let a = std::fs::remove_dir_all({:?}).is_ok();
+                let b = std::fs::remove_file({:?}).is_ok();
+                println!(\"{{}}\", a || b);
+            }}",
+            victim.path(),
+            victim.path().join("data")
+        );
+        let binary = compile(dir.path(), &source);
+
+        // Even when the files are visible, they cannot be removed
+        let sandbox = Sandbox::default().visible(victim.path());
+        assert!(!run_file_in::<bool, _>(&binary, None, &[], &sandbox).unwrap());
+        assert!(victim.path().join("data").exists());
+    }
+
+    #[test]
+    fn only_visible_paths_exist()
+    {
+        if !is_root()
+        {
+            return;
+        }
+
+        let dir = TempDir::new().unwrap();
+        let visible = TempDir::new().unwrap();
+        let hidden = TempDir::new().unwrap();
+        std::fs::write(visible.path().join("file"), "").unwrap();
+        std::fs::write(hidden.path().join("file"), "").unwrap();
+
+        let source = format!(
+            "fn main(){{
+                let p = |path: &str| std::path::Path::new(path).exists();
+                println!(\"{{}}{{}}{{}}\", p({:?}), p({:?}), p(\"/etc/passwd\"));
+            }}",
+            visible.path().join("file"),
+            hidden.path().join("file"),
+        );
+        let binary = compile(dir.path(), &source);
+
+        let sandbox = Sandbox::default().visible(visible.path());
+        let seen = run_file_in::<String, _>(&binary, None, &[], &sandbox).unwrap();
+        assert_eq!(seen, "truefalsefalse");
+    }
+
+    #[test]
+    fn runs_as_another_user()
+    {
+        if !is_root()
+        {
+            return;
+        }
+
+        let dir = TempDir::new().unwrap();
+        let owned = TempDir::new().unwrap();
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(owned.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let source = format!(
+            "fn main(){{ println!(\"{{}}\", std::fs::write({:?}, \"\").is_ok()); }}",
+            owned.path().join("new")
+        );
+        let binary = compile(dir.path(), &source);
+
+        // Visible, but owned by root
+        let sandbox = Sandbox::default().visible(owned.path());
+        assert!(!run_file_in::<bool, _>(&binary, None, &[], &sandbox).unwrap());
+        assert!(run_file_in::<bool, _>(&binary, None, &[], &Sandbox::disabled()).unwrap());
+    }
+
+    #[test]
+    fn runs_in_its_own_pid_namespace()
+    {
+        if !is_root()
+        {
+            return;
+        }
+
+        let dir = TempDir::new().unwrap();
+        let binary = compile(dir.path(), "fn main(){ println!(\"{}\", std::process::id()); }");
+
+        // The first process of a fresh namespace, no host process has a number it can see
+        assert_eq!(run_file_in::<u32, _>(&binary, None, &[], &Sandbox::default()).unwrap(), 1);
+        assert_ne!(run_file_in::<u32, _>(&binary, None, &[], &Sandbox::disabled()).unwrap(), 1);
+    }
+}
@@ -0,0 +1,530 @@
+use std::{
+    ffi::CString,
+    io::{Error, ErrorKind},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use wasmi::{
+    core::TrapCode, Caller, Config, Engine, Extern, Linker, Memory, Module, Store, StoreLimits,
+    StoreLimitsBuilder,
+};
+
+use crate::{ExecError, Executable, Sandbox, METADATA_PREFIX};
+
+/*
+ * A program compiled to WebAssembly, run in-process. Instead of a filesystem
+ * the program gets the host API below, imported from the `gurret` module, and
+ * it has to export `memory` and a `main: () -> ()`.
+ *
+ *   arg(ptr, len) -> i32                 copy argv[1], -1 if there is none
+ *   input(ptr, len) -> i32               copy the input, the JSON request for `call`
+ *   env(name, name_len, ptr, len) -> i32 copy an environment variable, -1 if unset
+ *   getxattr(name, name_len, ptr, len) -> i32
+ *   setxattr(name, name_len, value, value_len) -> i32
+ *   time() -> i64                        seconds since the epoch
+ *   print(ptr, len)                      append to the output
+ *
+ * Copies return the full length, so a too small buffer can be retried. The
+ * program runs with our rights, so the xattr calls only reach the metadata
+ * xattrs (`user.gurret.`) of `GURRET_PATH`, and never its labels. They return
+ * the length (or 0) on success and -errno otherwise.
+ *
+ * Every run gets `fuel` instructions, running out is reported as `TimedOut`.
+ * Fuel does not bound what is allocated, so memory is kept to the
+ * `memory_bytes` of the sandbox and at most `MAX_MEMORY`, tables to
+ * `MAX_TABLE_ELEMENTS` and what is printed to `MAX_OUTPUT`.
+ */
+pub struct WasmExecutable
+{
+    path:   PathBuf,
+    engine: Engine,
+    module: Module,
+    fuel:   u64,
+}
+
+struct Host
+{
+    arg:    Option<String>,
+    input:  Vec<u8>,
+    env:    Vec<(String, String)>,
+    file:   Option<PathBuf>,
+    output: Vec<u8>,
+    limits: StoreLimits,
+}
+
+impl WasmExecutable
+{
+    pub const DEFAULT_FUEL: u64 = 100_000_000;
+    pub const MAX_MEMORY: usize = 64 << 20;
+    pub const MAX_TABLE_ELEMENTS: u32 = 10_000;
+    pub const MAX_OUTPUT: usize = 1 << 20;
+
+    // Either a binary `.wasm`, or the text format in a `.wat`
+    pub fn from_path<P: AsRef<Path>>(path: P) -> std::io::Result<Self>
+    {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)?;
+
+        let exec = match path.extension().and_then(|e| e.to_str())
+        {
+            Some("wat") => Self::new(&wat::parse_bytes(&bytes).map_err(invalid)?)?,
+            _ => Self::new(&bytes)?,
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            ..exec
+        })
+    }
+
+    pub fn new(bytes: &[u8]) -> std::io::Result<Self>
+    {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, bytes).map_err(invalid)?;
+
+        Ok(Self {
+            path: PathBuf::from("<wasm>"),
+            engine,
+            module,
+            fuel: Self::DEFAULT_FUEL,
+        })
+    }
+
+    pub fn with_fuel(mut self, fuel: u64) -> Self
+    {
+        self.fuel = fuel;
+        self
+    }
+
+    fn linker(&self) -> std::io::Result<Linker<Host>>
+    {
+        let mut linker = Linker::<Host>::new(&self.engine);
+
+        linker
+            .func_wrap("gurret", "arg", |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+                let arg = caller.data().arg.clone();
+                match arg
+                {
+                    Some(arg) => copy_out(&mut caller, arg.as_bytes(), ptr, len),
+                    None => Ok(-1),
+                }
+            })
+            .map_err(invalid)?;
+
+        linker
+            .func_wrap("gurret", "input", |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+                let input = std::mem::take(&mut caller.data_mut().input);
+                let result = copy_out(&mut caller, &input, ptr, len);
+                caller.data_mut().input = input;
+                result
+            })
+            .map_err(invalid)?;
+
+        linker
+            .func_wrap(
+                "gurret",
+                "env",
+                |mut caller: Caller<'_, Host>, name: i32, name_len: i32, ptr: i32, len: i32| {
+                    let name = read_string(&caller, name, name_len)?;
+                    let value =
+                        caller.data().env.iter().find(|(k, _)| *k == name).map(|(_, v)| v.clone());
+                    match value
+                    {
+                        Some(value) => copy_out(&mut caller, value.as_bytes(), ptr, len),
+                        None => Ok(-1),
+                    }
+                },
+            )
+            .map_err(invalid)?;
+
+        linker
+            .func_wrap(
+                "gurret",
+                "getxattr",
+                |mut caller: Caller<'_, Host>, name: i32, name_len: i32, ptr: i32, len: i32| {
+                    let name = read_string(&caller, name, name_len)?;
+                    match get_xattr(caller.data().file.as_deref(), &name)
+                    {
+                        Ok(value) => copy_out(&mut caller, &value, ptr, len),
+                        Err(errno) => Ok(-errno),
+                    }
+                },
+            )
+            .map_err(invalid)?;
+
+        linker
+            .func_wrap(
+                "gurret",
+                "setxattr",
+                |caller: Caller<'_, Host>, name: i32, name_len: i32, value: i32, value_len: i32| {
+                    let name = read_string(&caller, name, name_len)?;
+                    let value = read(&caller, value, value_len)?;
+                    match set_xattr(caller.data().file.as_deref(), &name, &value)
+                    {
+                        Ok(()) => Ok(0),
+                        Err(errno) => Ok(-errno),
+                    }
+                },
+            )
+            .map_err(invalid)?;
+
+        linker
+            .func_wrap("gurret", "time", || {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH);
+                now.map(|d| d.as_secs() as i64).unwrap_or(0)
+            })
+            .map_err(invalid)?;
+
+        linker
+            .func_wrap("gurret", "print", |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+                let bytes = read(&caller, ptr, len)?;
+                let output = &mut caller.data_mut().output;
+                if output.len() + bytes.len() > Self::MAX_OUTPUT
+                {
+                    return Err(wasmi::core::Trap::new("printed too much"));
+                }
+                output.extend(bytes);
+                Ok(())
+            })
+            .map_err(invalid)?;
+
+        Ok(linker)
+    }
+}
+
+impl Executable for WasmExecutable
+{
+    fn path(&self) -> &Path
+    {
+        &self.path
+    }
+
+    fn run(
+        &self,
+        arg: Option<String>,
+        input: &[u8],
+        env: &[(String, String)],
+        sandbox: &Sandbox,
+    ) -> Result<String, ExecError>
+    {
+        let memory = sandbox.memory_bytes.map_or(Self::MAX_MEMORY, |bytes| {
+            (bytes as usize).min(Self::MAX_MEMORY)
+        });
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(memory)
+            .table_elements(Self::MAX_TABLE_ELEMENTS)
+            .instances(1)
+            .build();
+        let host = Host {
+            arg,
+            input: input.to_vec(),
+            env: env.to_vec(),
+            file: env.iter().find(|(k, _)| k == "GURRET_PATH").map(|(_, v)| v.into()),
+            output: Vec::new(),
+            limits,
+        };
+        let error = |e| ExecError::io(&self.path, e);
+
+        let mut store = Store::new(&self.engine, host);
+        store.limiter(|host| &mut host.limits);
+        store.add_fuel(self.fuel).map_err(|e| error(invalid(e)))?;
+
+        let instance = self
+            .linker()
+            .map_err(error)?
+            .instantiate(&mut store, &self.module)
+            .and_then(|pre| pre.start(&mut store))
+            .map_err(|e| error(invalid(e)))?;
+        let main = instance
+            .get_typed_func::<(), ()>(&store, "main")
+            .map_err(|e| error(invalid(e)))?;
+
+        main.call(&mut store, ()).map_err(|trap| match trap.trap_code()
+        {
+            Some(TrapCode::OutOfFuel) => error(Error::new(ErrorKind::TimedOut, "ran out of fuel")),
+            _ => ExecError::trap(&self.path, trap),
+        })?;
+
+        let output = store.into_data().output;
+        String::from_utf8(output)
+            .map_err(|e| ExecError::parse(&self.path, "output is not utf8", &e.to_string()))
+    }
+}
+
+
+fn invalid(e: impl std::fmt::Display) -> Error
+{
+    Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+fn memory(caller: &Caller<'_, Host>) -> Result<Memory, wasmi::core::Trap>
+{
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| wasmi::core::Trap::new("no exported memory"))
+}
+
+// Checked against the memory before anything is copied, `len` is up to the program
+fn read(caller: &Caller<'_, Host>, ptr: i32, len: i32) -> Result<Vec<u8>, wasmi::core::Trap>
+{
+    let start = ptr as u32 as usize;
+    let end = start.checked_add(len.max(0) as usize);
+    memory(caller)?
+        .data(caller)
+        .get(start..end.unwrap_or(usize::MAX))
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| wasmi::core::Trap::from(TrapCode::MemoryOutOfBounds))
+}
+
+fn read_string(caller: &Caller<'_, Host>, ptr: i32, len: i32)
+    -> Result<String, wasmi::core::Trap>
+{
+    String::from_utf8(read(caller, ptr, len)?)
+        .map_err(|_| wasmi::core::Trap::new("string is not utf8"))
+}
+
+// Copies as much of `bytes` as fits, giving the full length
+fn copy_out(
+    caller: &mut Caller<'_, Host>,
+    bytes: &[u8],
+    ptr: i32,
+    len: i32,
+) -> Result<i32, wasmi::core::Trap>
+{
+    let n = bytes.len().min(len.max(0) as usize);
+    memory(caller)?
+        .write(caller, ptr as u32 as usize, &bytes[..n])
+        .map_err(|_| wasmi::core::Trap::from(TrapCode::MemoryOutOfBounds))?;
+    Ok(bytes.len() as i32)
+}
+
+
+fn xattr_args(file: Option<&Path>, name: &str) -> Result<(CString, CString), i32>
+{
+    let file = file.ok_or(libc::ENOENT)?;
+    if !name.starts_with(METADATA_PREFIX)
+    {
+        return Err(libc::EPERM);
+    }
+    let file = CString::new(file.as_os_str().as_bytes()).map_err(|_| libc::EINVAL)?;
+    let name = CString::new(name).map_err(|_| libc::EINVAL)?;
+    Ok((file, name))
+}
+
+fn errno() -> i32
+{
+    Error::last_os_error().raw_os_error().unwrap_or(libc::EIO)
+}
+
+fn get_xattr(file: Option<&Path>, name: &str) -> Result<Vec<u8>, i32>
+{
+    let (file, name) = xattr_args(file, name)?;
+
+    let size = unsafe { libc::getxattr(file.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+    if size < 0
+    {
+        return Err(errno());
+    }
+    let mut buf = vec![0u8; size as usize];
+    let size = unsafe {
+        libc::getxattr(file.as_ptr(), name.as_ptr(), buf.as_mut_ptr().cast(), buf.len())
+    };
+    if size < 0
+    {
+        return Err(errno());
+    }
+    buf.truncate(size as usize);
+    Ok(buf)
+}
+
+fn set_xattr(file: Option<&Path>, name: &str, value: &[u8]) -> Result<(), i32>
+{
+    let (file, name) = xattr_args(file, name)?;
+
+    let result = unsafe {
+        libc::setxattr(file.as_ptr(), name.as_ptr(), value.as_ptr().cast(), value.len(), 0)
+    };
+    match result
+    {
+        0 => Ok(()),
+        _ => Err(errno()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    fn program(body: &str) -> WasmExecutable
+    {
+        let source = format!(
+            r#"(module
+                (import "gurret" "arg" (func $arg (param i32 i32) (result i32)))
+                (import "gurret" "input" (func $input (param i32 i32) (result i32)))
+                (import "gurret" "env" (func $env (param i32 i32 i32 i32) (result i32)))
+                (import "gurret" "getxattr" (func $getxattr (param i32 i32 i32 i32) (result i32)))
+                (import "gurret" "setxattr" (func $setxattr (param i32 i32 i32 i32) (result i32)))
+                (import "gurret" "time" (func $time (result i64)))
+                (import "gurret" "print" (func $print (param i32 i32)))
+                (memory (export "memory") 1)
+                {})"#,
+            body
+        );
+        WasmExecutable::new(&wat::parse_str(source).unwrap()).unwrap()
+    }
+
+    fn env(file: &Path) -> Vec<(String, String)>
+    {
+        vec![("GURRET_PATH".to_string(), file.display().to_string())]
+    }
+
+    #[test]
+    fn echoes_arg()
+    {
+        let exec = program(
+            r#"(func (export "main")
+                (call $print (i32.const 0) (call $arg (i32.const 0) (i32.const 64))))"#,
+        );
+        let out = exec.run(Some("Open".to_string()), &[], &[], &Sandbox::disabled()).unwrap();
+        assert_eq!(out, "Open");
+    }
+
+    #[test]
+    fn parses_like_a_process()
+    {
+        let exec = program(
+            r#"(data (i32.const 0) "true")
+            (func (export "main") (call $print (i32.const 0) (i32.const 4)))"#,
+        );
+        assert!(exec.exec::<bool>(None).unwrap());
+    }
+
+    #[test]
+    fn answers_a_request()
+    {
+        let exec = program(
+            r#"(data (i32.const 0) "{\"allow\": true}")
+            (func (export "main")
+                (if (i32.gt_s (call $input (i32.const 64) (i32.const 1024)) (i32.const 0))
+                    (then (call $print (i32.const 0) (i32.const 15)))))"#,
+        );
+        let request = crate::Request {
+            operation: "Open".to_string(),
+            ..crate::Request::default()
+        };
+        assert!(exec.call(&request, &[], &Sandbox::disabled()).unwrap().allow);
+    }
+
+    #[test]
+    fn reads_env()
+    {
+        let exec = program(
+            r#"(data (i32.const 0) "GURRET_UID")
+            (func (export "main")
+                (call $print (i32.const 16)
+                    (call $env (i32.const 0) (i32.const 10) (i32.const 16) (i32.const 16))))"#,
+        );
+        let env = [("GURRET_UID".to_string(), "1000".to_string())];
+        assert_eq!(exec.run(None, &[], &env, &Sandbox::disabled()).unwrap(), "1000");
+    }
+
+    #[test]
+    fn xattrs_of_the_file()
+    {
+        let file = NamedTempFile::new().unwrap();
+        let exec = program(
+            r#"(data (i32.const 0) "user.gurret.count")
+            (data (i32.const 24) "41")
+            (func (export "main")
+                (drop (call $setxattr (i32.const 0) (i32.const 17) (i32.const 24) (i32.const 2)))
+                (call $print (i32.const 32)
+                    (call $getxattr (i32.const 0) (i32.const 17) (i32.const 32) (i32.const 16))))"#,
+        );
+        assert_eq!(exec.run(None, &[], &env(file.path()), &Sandbox::disabled()).unwrap(), "41");
+    }
+
+    #[test]
+    fn only_metadata_xattrs()
+    {
+        // Not the labels of the file, nor anything outside of `user.`
+        for name in ["user.label", "trusted.x"]
+        {
+            let file = NamedTempFile::new().unwrap();
+            let exec = program(&format!(
+                r#"(data (i32.const 0) "{}")
+                (data (i32.const 16) "denied")
+                (func (export "main")
+                    (if (i32.eq
+                            (call $setxattr
+                                (i32.const 0) (i32.const {}) (i32.const 0) (i32.const 1))
+                            (i32.const -1))
+                        (then (call $print (i32.const 16) (i32.const 6)))))"#,
+                name,
+                name.len()
+            ));
+            let out = exec.run(None, &[], &env(file.path()), &Sandbox::disabled()).unwrap();
+            assert_eq!(out, "denied");
+        }
+    }
+
+    #[test]
+    fn memory_and_output_are_bounded()
+    {
+        // Growing past the memory of the sandbox fails, in pages of 64 KiB
+        let exec = program(
+            r#"(data (i32.const 0) "failed")
+            (func (export "main")
+                (if (i32.eq (memory.grow (i32.const 64)) (i32.const -1))
+                    (then (call $print (i32.const 0) (i32.const 6)))))"#,
+        );
+        let sandbox = Sandbox {
+            memory_bytes: Some(1 << 20),
+            ..Sandbox::disabled()
+        };
+        assert_eq!(exec.run(None, &[], &[], &sandbox).unwrap(), "failed");
+        assert_eq!(exec.run(None, &[], &[], &Sandbox::disabled()).unwrap(), "");
+
+        let exec = program(
+            r#"(func (export "main")
+                (loop $l (call $print (i32.const 0) (i32.const 65536)) (br $l)))"#,
+        );
+        assert!(exec.run(None, &[], &[], &Sandbox::disabled()).is_err());
+    }
+
+    #[test]
+    fn gets_the_time()
+    {
+        let exec = program(
+            r#"(data (i32.const 0) "truefalse")
+            (func (export "main")
+                (if (i64.gt_s (call $time) (i64.const 0))
+                    (then (call $print (i32.const 0) (i32.const 4)))
+                    (else (call $print (i32.const 4) (i32.const 5)))))"#,
+        );
+        assert!(exec.exec::<bool>(None).unwrap());
+    }
+
+    #[test]
+    fn infinite_loop_runs_out_of_fuel()
+    {
+        let exec = program(r#"(func (export "main") (loop $l (br $l)))"#).with_fuel(10_000);
+        let err = exec.run(None, &[], &[], &Sandbox::disabled()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn missing_main_is_invalid()
+    {
+        let exec = program("");
+        let err = exec.run(None, &[], &[], &Sandbox::disabled()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
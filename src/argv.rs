@@ -0,0 +1,334 @@
+use std::path::{Component, Path, PathBuf};
+
+use globset::GlobBuilder;
+use serde::Deserialize;
+
+/*
+ * Which arguments of an attested program are the files it reads and writes,
+ * from the `[argv]` table of its `exe/` TOML:
+ *
+ *   [argv]
+ *   input  = { positions = ["0..-1"] }
+ *   output = { positions = [-1], flags = ["-o", "--output"] }
+ *   valued = ["-k"]   # other flags that take a value, which is not a file
+ *
+ * Positions count the arguments that are neither flags nor their values,
+ * from 0 after the program itself. A negative position counts from the end,
+ * and "a..b" is every position from a up to, but not including, b where
+ * either end can be left out. A flag is followed by its file, or given it as
+ * `-ofile` or `--output=file`. Anything after `--` is positional. A file with
+ * `*`, `?` or `[` in it is a glob, and stands for every file it matches.
+ */
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Argv
+{
+    #[serde(default)]
+    input:  Files,
+    #[serde(default)]
+    output: Files,
+    #[serde(default)]
+    valued: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Files
+{
+    #[serde(default)]
+    positions: Vec<Position>,
+    #[serde(default)]
+    flags:     Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Position
+{
+    At(i64),
+    Range(String),
+}
+
+// The files an invocation reads and writes, as they were given to it
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ArgFiles
+{
+    pub inputs:  Vec<String>,
+    pub outputs: Vec<String>,
+}
+
+impl Position
+{
+    // The indexes of `len` positional arguments it stands for
+    fn indexes(&self, len: usize) -> Result<Vec<usize>, String>
+    {
+        let index = |i: i64| match i < 0
+        {
+            true => len as i64 + i,
+            false => i,
+        };
+        match self
+        {
+            Position::At(i) => match index(*i)
+            {
+                i if (0..len as i64).contains(&i) => Ok(vec![i as usize]),
+                _ => Err(format!("no argument at position {}", i)),
+            },
+            Position::Range(range) =>
+            {
+                let (from, to) =
+                    range.split_once("..").ok_or_else(|| format!("not a range: {:?}", range))?;
+                let end = |s: &str, default: i64| match s
+                {
+                    "" => Ok(default),
+                    s => s.parse().map(index).map_err(|_| format!("not a range: {:?}", range)),
+                };
+                let (from, to) = (end(from, 0)?.max(0), end(to, len as i64)?.min(len as i64));
+                Ok((from..to.max(from)).map(|i| i as usize).collect())
+            },
+        }
+    }
+}
+
+impl Argv
+{
+    // The schema in `toml`, if it has one
+    pub fn from_toml(toml: &toml::Value) -> Option<Result<Self, String>>
+    {
+        let argv = toml.get("argv")?.clone();
+        Some(argv.try_into().map_err(|e: toml::de::Error| e.to_string()))
+    }
+
+    // Whether `arg` is `flag`, or `flag` given its value, and the value if so
+    fn value_of<'a>(flag: &str, arg: &'a str) -> Option<Option<&'a str>>
+    {
+        if arg == flag
+        {
+            return Some(None);
+        }
+        let value = match flag.starts_with("--")
+        {
+            true => arg.strip_prefix(flag).and_then(|rest| rest.strip_prefix('=')),
+            false if flag.len() == 2 => arg.strip_prefix(flag),
+            false => None,
+        };
+        value.map(Some)
+    }
+
+    /*
+     * The files `args`, the arguments after the program, name. An error is
+     * given back when the arguments do not fit the schema.
+     */
+    pub fn files(&self, args: &[String]) -> Result<ArgFiles, String>
+    {
+        let mut files = ArgFiles::default();
+        let mut positional = Vec::new();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next()
+        {
+            if arg == "--"
+            {
+                positional.extend(args.by_ref().cloned());
+                break;
+            }
+            if !arg.starts_with('-') || arg == "-"
+            {
+                positional.push(arg.clone());
+                continue;
+            }
+
+            let flagged = [
+                (&self.input.flags, &mut files.inputs),
+                (&self.output.flags, &mut files.outputs),
+            ];
+            let mut matched = false;
+            for (flags, found) in flagged
+            {
+                if let Some(value) = flags.iter().find_map(|flag| Self::value_of(flag, arg))
+                {
+                    let value = match value
+                    {
+                        Some(value) => Some(value.to_string()),
+                        None => args.next().cloned(),
+                    };
+                    found.push(value.ok_or_else(|| format!("{} has no value", arg))?);
+                    matched = true;
+                    break;
+                }
+            }
+
+            // The value of any other flag is not a file, or anything positional
+            if !matched && self.valued.iter().any(|flag| Self::value_of(flag, arg) == Some(None))
+            {
+                args.next();
+            }
+        }
+
+        let positioned = [(&self.input, &mut files.inputs), (&self.output, &mut files.outputs)];
+        for (files, found) in positioned
+        {
+            for position in &files.positions
+            {
+                let indexes = position.indexes(positional.len())?;
+                found.extend(indexes.into_iter().map(|i| positional[i].clone()));
+            }
+        }
+
+        // "-" is the standard input or output, not a file
+        files.inputs.retain(|file| file != "-");
+        files.outputs.retain(|file| file != "-");
+        Ok(files)
+    }
+}
+
+// `path`, without any `.` or `..` in it
+pub fn normalize(path: &Path) -> PathBuf
+{
+    let mut normal = PathBuf::new();
+    for component in path.components()
+    {
+        match component
+        {
+            Component::CurDir => (),
+            Component::ParentDir =>
+            {
+                normal.pop();
+            },
+            component => normal.push(component),
+        }
+    }
+    normal
+}
+
+/*
+ * The files `pattern` stands for: itself, or every file it matches if it is
+ * a glob. Like in a shell, `*` stays within a folder.
+ */
+pub fn expand(pattern: &Path) -> Vec<PathBuf>
+{
+    let is_glob = |s: &str| s.contains(['*', '?', '[']);
+    let text = pattern.to_string_lossy();
+    if !is_glob(&text)
+    {
+        return vec![pattern.to_path_buf()];
+    }
+
+    let glob = match GlobBuilder::new(&text).literal_separator(true).build()
+    {
+        Ok(glob) => glob.compile_matcher(),
+        Err(_) => return Vec::new(),
+    };
+
+    // Walk from the last folder before the glob, and no deeper than it goes
+    let root: PathBuf =
+        pattern.components().take_while(|c| !is_glob(&c.as_os_str().to_string_lossy())).collect();
+    let depth = pattern.components().count() - root.components().count();
+
+    let mut found = Vec::new();
+    let mut folders = vec![(root, 0)];
+    while let Some((folder, level)) = folders.pop()
+    {
+        let entries = match std::fs::read_dir(&folder)
+        {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten()
+        {
+            let path = entry.path();
+            if level + 1 == depth && glob.is_match(&path)
+            {
+                found.push(path);
+            }
+            else if level + 1 < depth && entry.file_type().map(|t| t.is_dir()).unwrap_or(false)
+            {
+                folders.push((path, level + 1));
+            }
+        }
+    }
+    found.sort();
+    found
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn fixture(program: &str) -> Argv
+    {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/exe").join(program);
+        let toml = fs::read_to_string(path).unwrap().parse::<toml::Value>().unwrap();
+        Argv::from_toml(&toml).unwrap().unwrap()
+    }
+
+    fn files(program: &str, args: &str) -> Result<(Vec<String>, Vec<String>), String>
+    {
+        let args: Vec<String> = args.split_whitespace().map(str::to_string).collect();
+        fixture(program).files(&args).map(|files| (files.inputs, files.outputs))
+    }
+
+    fn strings(s: &[&str]) -> Vec<String>
+    {
+        s.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn common_tools()
+    {
+        let found = |inputs: &[&str], outputs: &[&str]| Ok((strings(inputs), strings(outputs)));
+        assert_eq!(files("cp", "a b"), found(&["a"], &["b"]));
+        assert_eq!(files("cp", "-r a b dir"), found(&["a", "b"], &["dir"]));
+        assert_eq!(files("sort", "-k 2 -o out in"), found(&["in"], &["out"]));
+        assert_eq!(files("sort", "--output=out a b"), found(&["a", "b"], &["out"]));
+        assert_eq!(files("gcc", "-O2 -oprog a.c b.c"), found(&["a.c", "b.c"], &["prog"]));
+        assert_eq!(
+            files("ffmpeg", "-y -i a.mp4 -i b.wav -c copy out.mkv"),
+            found(&["a.mp4", "b.wav"], &["out.mkv"])
+        );
+        assert_eq!(files("pandoc", "-s in.md -o out.pdf"), found(&["in.md"], &["out.pdf"]));
+        assert_eq!(files("python3", "train.py data.csv model"), found(&["data.csv"], &["model"]));
+    }
+
+    #[test]
+    fn invoked_otherwise()
+    {
+        // Nothing to copy, nothing to copy to, and sorting to the standard output
+        assert_eq!(files("cp", "a"), Ok((vec![], strings(&["a"]))));
+        assert!(files("cp", "-r").is_err());
+        assert_eq!(files("sort", "in -"), Ok((strings(&["in"]), vec![])));
+        assert!(files("sort", "in -o").is_err());
+        assert_eq!(files("cp", "-- -a b"), Ok((strings(&["-a"]), strings(&["b"]))));
+    }
+
+    #[test]
+    fn unknown_keys_are_refused()
+    {
+        let toml = "[argv]\ninput = { position = [0] }".parse::<toml::Value>().unwrap();
+        assert!(Argv::from_toml(&toml).unwrap().is_err());
+        assert!(Argv::from_toml(&toml::Value::Table(Default::default())).is_none());
+    }
+
+    #[test]
+    fn globs_expand_within_a_folder()
+    {
+        let root = TempDir::new().unwrap();
+        fs::create_dir(root.path().join("sub")).unwrap();
+        for file in ["a.csv", "b.csv", "c.json", "sub/d.csv"]
+        {
+            fs::write(root.path().join(file), "").unwrap();
+        }
+
+        let found = expand(&root.path().join("*.csv"));
+        assert_eq!(found, [root.path().join("a.csv"), root.path().join("b.csv")]);
+        assert_eq!(expand(&root.path().join("*/*.csv")), [root.path().join("sub/d.csv")]);
+        assert_eq!(expand(&root.path().join("c.json")), [root.path().join("c.json")]);
+        assert_eq!(normalize(Path::new("/a/./b/../c")), Path::new("/a/c"));
+    }
+}
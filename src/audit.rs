@@ -0,0 +1,373 @@
+use std::{
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{daemon, get_program_name_by_pid, parse_config, try_config};
+
+// The audit log of the mount, once it is opened by `init`
+static AUDIT: Mutex<Option<AuditLog>> = Mutex::new(None);
+
+// Where the audit log is kept, unless config says otherwise
+pub const AUDIT_LOG: &str = "/var/log/gurret/audit.log";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict
+{
+    Allow,
+    Deny,
+}
+
+/*
+ * One access decision: who asked, for what, the labels that were compared
+ * and what they were told. A line of JSON in the audit log.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Decision
+{
+    pub time:      String,
+    pub pid:       u32,
+    pub uid:       u32,
+    pub program:   Option<String>,
+    pub file:      PathBuf,
+    pub operation: String,
+    // The clearance of the program, and the label of the file, if they were compared
+    pub clearance: Option<String>,
+    pub label:     Option<String>,
+    pub verdict:   Verdict,
+}
+
+impl Decision
+{
+    pub fn new(pid: u32, uid: u32, file: &Path, operation: &str, allowed: bool) -> Self
+    {
+        Self::by(program_of(pid), pid, uid, file, operation, allowed)
+    }
+
+    // A decision for `program`, when it was looked up already
+    pub fn by(
+        program: Option<String>,
+        pid: u32,
+        uid: u32,
+        file: &Path,
+        operation: &str,
+        allowed: bool,
+    ) -> Self
+    {
+        Self {
+            time: chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+            pid,
+            uid,
+            program,
+            file: file.to_path_buf(),
+            operation: operation.to_string(),
+            clearance: None,
+            label: None,
+            verdict: match allowed
+            {
+                true => Verdict::Allow,
+                false => Verdict::Deny,
+            },
+        }
+    }
+
+    pub fn compared(mut self, clearance: impl Display, label: impl Display) -> Self
+    {
+        self.clearance = Some(clearance.to_string());
+        self.label = Some(label.to_string());
+        self
+    }
+}
+
+// The program `pid` runs, as it is named in the audit log
+pub fn program_of(pid: u32) -> Option<String>
+{
+    get_program_name_by_pid(pid).map(|name| name.to_string_lossy().into_owned())
+}
+
+/*
+ * The audit log, `AUDIT_LOG` in config, is only ever appended to. When it
+ * would grow past `AUDIT_MAX_SIZE` bytes it is moved to `<log>.1`, the older
+ * `<log>.1` to `<log>.2` and so on, keeping `AUDIT_KEEP` of them.
+ */
+pub struct AuditLog
+{
+    path:     PathBuf,
+    max_size: u64,
+    keep:     usize,
+    // The log while it is open, and how big it is
+    file:     Option<(File, u64)>,
+}
+
+impl AuditLog
+{
+    pub fn new(path: impl AsRef<Path>, max_size: u64, keep: usize) -> Self
+    {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            max_size,
+            keep,
+            file: None,
+        }
+    }
+
+    // The log of config, or what is wrong with its AUDIT_MAX_SIZE or AUDIT_KEEP
+    pub fn from_config() -> std::io::Result<Self>
+    {
+        let path = try_config("AUDIT_LOG").unwrap_or_else(|| AUDIT_LOG.to_string());
+        let max_size = parse_config("AUDIT_MAX_SIZE", "a number of bytes")?.unwrap_or(10 << 20);
+        let keep = parse_config("AUDIT_KEEP", "a number")?.unwrap_or(5);
+        Ok(Self::new(path, max_size, keep))
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf
+    {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&self) -> std::io::Result<()>
+    {
+        if self.keep == 0
+        {
+            return std::fs::remove_file(&self.path);
+        }
+        for n in (1..self.keep).rev()
+        {
+            if self.rotated(n).exists()
+            {
+                std::fs::rename(self.rotated(n), self.rotated(n + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, self.rotated(1))
+    }
+
+    pub fn record(&mut self, decision: &Decision) -> std::io::Result<()>
+    {
+        let mut line = serde_json::to_string(decision)?;
+        line.push('\n');
+
+        let (length, max_size) = (line.len() as u64, self.max_size);
+        let full = |size: u64| size > 0 && size + length > max_size;
+        let (mut file, mut size) = match self.file.take()
+        {
+            Some(file) => file,
+            None => self.open()?,
+        };
+        if full(size)
+        {
+            drop(file);
+            self.rotate()?;
+            (file, size) = self.open()?;
+        }
+
+        file.write_all(line.as_bytes())?;
+        size += length;
+        self.file = Some((file, size));
+        Ok(())
+    }
+
+    /*
+     * The log is only opened in a folder nobody else can write to, and only if
+     * it is a file of the mount, so nobody can have it written where they like
+     */
+    fn open(&self) -> std::io::Result<(File, u64)>
+    {
+        let mut options = OpenOptions::new();
+        let file = daemon::open_owned(&self.path, options.create(true).append(true).mode(0o600))?;
+        let size = file.metadata()?.len();
+        Ok((file, size))
+    }
+
+    // The log and what was rotated out of it that is still kept, the oldest first
+    pub fn files(&self) -> Vec<PathBuf>
+    {
+        let mut files: Vec<PathBuf> = (1..=self.keep).rev().map(|n| self.rotated(n)).collect();
+        files.push(self.path.clone());
+        files.retain(|path| path.exists());
+        files
+    }
+
+    // Every decision kept that `query` matches, in the order they were made
+    pub fn read(&self, query: &Query) -> std::io::Result<Vec<Decision>>
+    {
+        let mut found = Vec::new();
+        for path in self.files()
+        {
+            for line in BufReader::new(File::open(&path)?).lines()
+            {
+                match serde_json::from_str::<Decision>(&line?)
+                {
+                    Ok(decision) if query.matches(&decision) => found.push(decision),
+                    Ok(_) => (),
+                    Err(e) => warn!("skipping a line of {}: {}", path.display(), e),
+                }
+            }
+        }
+        Ok(found)
+    }
+}
+
+/*
+ * Open the audit log of config for `record`, or give back why it can not be.
+ * The mount does before it is mounted.
+ */
+pub fn init() -> std::io::Result<()>
+{
+    let mut log = AuditLog::from_config()?;
+    log.file = Some(log.open()?);
+    *AUDIT.lock().expect("getting lock") = Some(log);
+    Ok(())
+}
+
+// Record `decision` in the audit log, if it was opened
+pub fn record(decision: Decision)
+{
+    let mut audit = AUDIT.lock().expect("getting lock");
+    if let Err(e) = audit.as_mut().map_or(Ok(()), |log| log.record(&decision))
+    {
+        error!("auditing {:?} failed: {}", decision, e);
+    }
+}
+
+// What to look for in the audit log, anything if left out
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Query
+{
+    // The file, or the folder of the files
+    pub file:      Option<PathBuf>,
+    pub pid:       Option<u32>,
+    pub program:   Option<String>,
+    pub operation: Option<String>,
+    pub verdict:   Option<Verdict>,
+    // Decisions from this time on, such as 2024-01-31 or 2024-01-31T12:00
+    pub since:     Option<String>,
+}
+
+impl Query
+{
+    // From arguments such as `--file <file> --denied`
+    pub fn parse(args: &[&str]) -> Result<Self, String>
+    {
+        let mut query = Self::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next()
+        {
+            let mut value =
+                || args.next().map(|s| s.to_string()).ok_or(format!("{} needs a value", arg));
+            match *arg
+            {
+                "--file" => query.file = Some(value()?.into()),
+                "--pid" =>
+                {
+                    query.pid = Some(value()?.parse().map_err(|e| format!("--pid: {}", e))?)
+                },
+                "--program" => query.program = Some(value()?),
+                "--operation" => query.operation = Some(value()?),
+                "--since" => query.since = Some(value()?),
+                "--allowed" => query.verdict = Some(Verdict::Allow),
+                "--denied" => query.verdict = Some(Verdict::Deny),
+                arg => return Err(format!("unknown argument {}", arg)),
+            }
+        }
+        Ok(query)
+    }
+
+    pub fn matches(&self, decision: &Decision) -> bool
+    {
+        let program = decision.program.as_ref();
+        self.file.as_ref().is_none_or(|file| decision.file.starts_with(file))
+            && self.pid.is_none_or(|pid| decision.pid == pid)
+            && self.program.as_ref().is_none_or(|wanted| program == Some(wanted))
+            && self.operation.as_ref().is_none_or(|operation| &decision.operation == operation)
+            && self.verdict.is_none_or(|verdict| decision.verdict == verdict)
+            && self.since.as_ref().is_none_or(|since| decision.time.as_str() >= since.as_str())
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn decision(file: &str, operation: &str, allowed: bool) -> Decision
+    {
+        Decision::new(std::process::id(), 0, Path::new(file), operation, allowed).compared(3, 1)
+    }
+
+    #[test]
+    fn rotated_when_full()
+    {
+        let root = TempDir::new().unwrap();
+        let line = serde_json::to_string(&decision("/data/a", "open", true)).unwrap().len() + 1;
+        let mut log = AuditLog::new(root.path().join("audit"), 2 * line as u64, 2);
+        for i in 0..7
+        {
+            log.record(&decision(&format!("/data/{}", i), "open", true)).unwrap();
+        }
+
+        // Two to a file, and the oldest are gone
+        let files = log.files();
+        assert_eq!(files, [log.rotated(2), log.rotated(1), log.path.clone()]);
+        let read = log.read(&Query::default()).unwrap();
+        let read: Vec<_> = read.iter().map(|decision| decision.file.clone()).collect();
+        let kept: Vec<PathBuf> = (2..7).map(|i| format!("/data/{}", i).into()).collect();
+        assert_eq!(read, kept);
+    }
+
+    #[test]
+    fn log_of_someone_else_is_refused()
+    {
+        let root = TempDir::new().unwrap();
+        let path = root.path().join("audit");
+        std::os::unix::fs::symlink(root.path().join("elsewhere"), &path).unwrap();
+        let mut log = AuditLog::new(&path, 1 << 20, 1);
+        assert!(log.record(&decision("/data/a", "open", true)).is_err());
+        assert!(!root.path().join("elsewhere").exists());
+
+        // Nor is a file someone else made, which only root can try
+        if unsafe { libc::geteuid() } != 0
+        {
+            return;
+        }
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, "").unwrap();
+        std::os::unix::fs::chown(&path, Some(65534), None).unwrap();
+        let refused = log.record(&decision("/data/a", "open", true)).unwrap_err();
+        assert_eq!(refused.kind(), std::io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn queried_by_what_was_decided()
+    {
+        let root = TempDir::new().unwrap();
+        let mut log = AuditLog::new(root.path().join("audit"), 1 << 20, 1);
+        log.record(&decision("/data/a", "open", true)).unwrap();
+        log.record(&decision("/data/b", "lookup", false)).unwrap();
+        log.record(&decision("/other/c", "open", false)).unwrap();
+
+        let query = Query::parse(&["--file", "/data", "--denied"]).unwrap();
+        let found = log.read(&query).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].file, Path::new("/data/b"));
+        assert_eq!(found[0].clearance.as_deref(), Some("3"));
+
+        let query = Query::parse(&["--operation", "open", "--pid", "0"]).unwrap();
+        assert!(log.read(&query).unwrap().is_empty());
+        assert_eq!(log.read(&Query::parse(&["--since", "2000"]).unwrap()).unwrap().len(), 3);
+        assert!(Query::parse(&["--pid"]).is_err());
+        assert!(Query::parse(&["--verdict"]).is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+};
+
+pub type MetadataId = u64;
+
+/*
+ * Where the metadata of a file comes from, either only the file itself, or
+ * also every file it was derived from.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum MSource
+{
+    Value(OsString),
+    Parent
+    {
+        parent_ids: Vec<MetadataId>,
+        own:        OsString,
+    },
+}
+
+impl MSource
+{
+    fn own(&self) -> &OsString
+    {
+        match self
+        {
+            MSource::Value(own) => own,
+            MSource::Parent {
+                own, ..
+            } => own,
+        }
+    }
+
+    fn parent_ids(&self) -> &[MetadataId]
+    {
+        match self
+        {
+            MSource::Value(_) => &[],
+            MSource::Parent {
+                parent_ids, ..
+            } => parent_ids,
+        }
+    }
+}
+
+/*
+ * The lineage that metadata propagates along. It mirrors the provenance
+ * `Table`, which keeps it up to date as datasets are derived, renamed and
+ * revoked. Ids are never reused, a removed file just loses its name.
+ */
+#[derive(Default, Debug, Clone)]
+pub struct Broker
+{
+    pub available_id:    MetadataId,
+    pub id_to_metadata:  Vec<MSource>,
+    pub file_name_to_id: HashMap<OsString, MetadataId>,
+}
+
+impl Broker
+{
+    pub fn get_name(&self, id: MetadataId) -> &OsString
+    {
+        match self.id_to_metadata.get(id as usize)
+        {
+            Some(source) => source.own(),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn get_id(&self, name: &OsStr) -> Option<MetadataId>
+    {
+        self.file_name_to_id.get(name).copied()
+    }
+
+    fn map_name_to_id(&mut self, name: &OsStr) -> MetadataId
+    {
+        if let Some(id) = self.get_id(name)
+        {
+            return id;
+        }
+
+        let id = self.available_id;
+        self.available_id += 1;
+
+        self.file_name_to_id.insert(name.to_os_string(), id);
+        self.id_to_metadata.push(MSource::Value(name.to_os_string()));
+        id
+    }
+
+    // Record that `name` was derived from `parent`, along with its other parents
+    pub fn derive(&mut self, name: &OsStr, parent: &OsStr)
+    {
+        if name == parent
+        {
+            return;
+        }
+        let parent = self.map_name_to_id(parent);
+        let id = self.map_name_to_id(name);
+
+        let source = &mut self.id_to_metadata[id as usize];
+        match source
+        {
+            MSource::Value(own) =>
+            {
+                *source = MSource::Parent {
+                    parent_ids: vec![parent],
+                    own:        own.clone(),
+                }
+            },
+            MSource::Parent {
+                parent_ids, ..
+            } if !parent_ids.contains(&parent) => parent_ids.push(parent),
+            MSource::Parent {
+                ..
+            } =>
+            {},
+        }
+    }
+
+    // The files `name` was derived from, directly
+    pub fn parents(&self, name: &OsStr) -> Vec<&OsString>
+    {
+        match self.get_id(name)
+        {
+            Some(id) => self.id_to_metadata[id as usize]
+                .parent_ids()
+                .iter()
+                .map(|id| self.get_name(*id))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn rename(&mut self, new: &OsStr, old: &OsStr)
+    {
+        if let Some(id) = self.file_name_to_id.remove(old)
+        {
+            self.file_name_to_id.insert(new.to_os_string(), id);
+            match &mut self.id_to_metadata[id as usize]
+            {
+                MSource::Value(own) => *own = new.to_os_string(),
+                MSource::Parent {
+                    own, ..
+                } => *own = new.to_os_string(),
+            }
+        }
+    }
+
+    // Forget `name`, and that anything was derived from it
+    pub fn remove(&mut self, name: &OsStr)
+    {
+        let id = match self.file_name_to_id.remove(name)
+        {
+            Some(id) => id,
+            None => return,
+        };
+
+        for source in self.id_to_metadata.iter_mut()
+        {
+            if let MSource::Parent {
+                parent_ids,
+                own,
+            } = source
+            {
+                parent_ids.retain(|parent| *parent != id);
+                if parent_ids.is_empty()
+                {
+                    *source = MSource::Value(own.clone());
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn parents<'a>(broker: &'a Broker, name: &str) -> Vec<&'a str>
+    {
+        broker.parents(OsStr::new(name)).iter().map(|p| p.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn many_parents()
+    {
+        let mut broker = Broker::default();
+        broker.derive(OsStr::new("/c"), OsStr::new("/a"));
+        broker.derive(OsStr::new("/c"), OsStr::new("/b"));
+        broker.derive(OsStr::new("/c"), OsStr::new("/a"));
+
+        assert_eq!(parents(&broker, "/c"), ["/a", "/b"]);
+        assert!(parents(&broker, "/a").is_empty());
+        assert!(parents(&broker, "/unknown").is_empty());
+    }
+
+    #[test]
+    fn rename_keeps_lineage()
+    {
+        let mut broker = Broker::default();
+        broker.derive(OsStr::new("/b"), OsStr::new("/a"));
+        broker.derive(OsStr::new("/c"), OsStr::new("/b"));
+        broker.rename(OsStr::new("/x"), OsStr::new("/b"));
+
+        assert_eq!(parents(&broker, "/c"), ["/x"]);
+        assert_eq!(parents(&broker, "/x"), ["/a"]);
+        assert!(parents(&broker, "/b").is_empty());
+    }
+
+    #[test]
+    fn removed_parent_is_forgotten()
+    {
+        let mut broker = Broker::default();
+        broker.derive(OsStr::new("/c"), OsStr::new("/a"));
+        broker.derive(OsStr::new("/c"), OsStr::new("/b"));
+        broker.remove(OsStr::new("/a"));
+
+        assert_eq!(parents(&broker, "/c"), ["/b"]);
+        broker.remove(OsStr::new("/b"));
+        let c = broker.get_id(OsStr::new("/c")).unwrap();
+        assert_eq!(broker.id_to_metadata[c as usize], MSource::Value("/c".into()));
+    }
+}
@@ -0,0 +1,715 @@
+use std::{
+    collections::HashSet,
+    os::unix::{fs::OpenOptionsExt, process::CommandExt},
+    path::{Path, PathBuf},
+    process::{Command as Process, Stdio},
+};
+
+use serde_json::{json, Value};
+
+use crate::{
+    config::*,
+    daemon,
+    metadata::QUERY_PREFIX,
+    permission::{file_label, relabel},
+    policy::{self, read_tag, LABEL_XATTR},
+    socket,
+    table::Table,
+    BASE_PATH,
+};
+
+pub const USAGE: &str = "\
+usage: gurret [--json] <command>
+
+  mount [--daemon]                     mount the TARGET on the PATH of config
+  umount                               unmount it again
+  status                               whether it is mounted, and what is tracked
+  checkout <file>                      start tracking a dataset
+  label get <file>                     the label of a file
+  label set [-r] <file> <labels>       label a file, or a folder and all in it
+  lineage <file>                       what a dataset is derived from, and to
+  revoke <file>                        remove a dataset and all derived from it
+  programs list                        the attested programs
+  programs attest <name> <level> [--gate <integrity>]
+                                       attest a program, at a confidentiality level
+  metadata show <file>                 the current metadata of a file
+
+Files are relative to the TARGET, unless they are absolute.";
+
+// The command was carried out
+pub const EXIT_OK: i32 = 0;
+// The command failed
+pub const EXIT_FAILED: i32 = 1;
+// The command was not understood
+pub const EXIT_USAGE: i32 = 2;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command<'a>
+{
+    Mount
+    {
+        daemon: bool,
+    },
+    Umount,
+    Status,
+    Checkout(&'a str),
+    LabelGet(&'a str),
+    LabelSet
+    {
+        file:      &'a str,
+        labels:    &'a str,
+        recursive: bool,
+    },
+    Lineage(&'a str),
+    Revoke(&'a str),
+    ProgramsList,
+    ProgramsAttest
+    {
+        program:         &'a str,
+        confidentiality: i64,
+        integrity:       Option<i64>,
+    },
+    Metadata(&'a str),
+}
+
+// What a command has to say, for people and for scripts
+pub struct Report
+{
+    pub text: String,
+    pub json: Value,
+}
+
+impl<'a> Command<'a>
+{
+    // The command in `args`, those after the program and without `--json`
+    pub fn parse(args: &[&'a str]) -> Option<Self>
+    {
+        let level = |s: &str| s.parse::<i64>().ok();
+        let command = match *args
+        {
+            ["mount"] => Command::Mount {
+                daemon: false
+            },
+            ["mount", "--daemon"] => Command::Mount {
+                daemon: true
+            },
+            ["umount"] => Command::Umount,
+            ["status"] => Command::Status,
+            ["checkout", file] => Command::Checkout(file),
+            ["label", "get", file] => Command::LabelGet(file),
+            ["label", "set", "-r", file, labels] => Command::LabelSet {
+                file,
+                labels,
+                recursive: true,
+            },
+            ["label", "set", file, labels] => Command::LabelSet {
+                file,
+                labels,
+                recursive: false,
+            },
+            ["lineage", file] => Command::Lineage(file),
+            ["revoke", file] => Command::Revoke(file),
+            ["programs", "list"] => Command::ProgramsList,
+            ["programs", "attest", program, confidentiality] => Command::ProgramsAttest {
+                program,
+                confidentiality: level(confidentiality)?,
+                integrity: None,
+            },
+            ["programs", "attest", program, confidentiality, "--gate", integrity] =>
+            {
+                Command::ProgramsAttest {
+                    program,
+                    confidentiality: level(confidentiality)?,
+                    integrity: Some(level(integrity)?),
+                }
+            },
+            ["metadata", "show", file] => Command::Metadata(file),
+            // Kept from before `show`, scripts may still call it that way
+            ["metadata", file] if file != "show" => Command::Metadata(file),
+            _ => return None,
+        };
+        Some(command)
+    }
+
+    pub fn run(&self) -> Result<Report, String>
+    {
+        match self
+        {
+            Command::Mount {
+                daemon,
+            } => mount(*daemon),
+            Command::Umount => umount(),
+            Command::Status => status(),
+            Command::Checkout(file) => checkout(file),
+            Command::LabelGet(file) => label_get(file),
+            Command::LabelSet {
+                file,
+                labels,
+                recursive,
+            } => label_set(file, labels, *recursive),
+            Command::Lineage(file) => lineage(file),
+            Command::Revoke(file) => revoke(file),
+            Command::ProgramsList => programs_list(),
+            Command::ProgramsAttest {
+                program,
+                confidentiality,
+                integrity,
+            } => programs_attest(program, *confidentiality, *integrity),
+            Command::Metadata(file) => metadata(file),
+        }
+    }
+}
+
+/*
+ * Run the command in `args`, the whole command line, and give back the exit
+ * code. With `--json` anywhere in it, the outcome is printed as JSON, errors
+ * too, otherwise as text.
+ */
+pub fn run(args: &[String]) -> i32
+{
+    let json = args.iter().any(|arg| arg == "--json");
+    let args: Vec<&str> =
+        args.iter().skip(1).map(String::as_str).filter(|arg| *arg != "--json").collect();
+
+    let command = match Command::parse(&args)
+    {
+        Some(command) => command,
+        None =>
+        {
+            eprintln!("{}", USAGE);
+            return EXIT_USAGE;
+        },
+    };
+
+    match (command.run(), json)
+    {
+        (Ok(report), true) => println!("{}", report.json),
+        (Ok(report), false) if report.text.is_empty() => (),
+        (Ok(report), false) => println!("{}", report.text),
+        (Err(e), true) =>
+        {
+            println!("{}", json!({ "error": e }));
+            return EXIT_FAILED;
+        },
+        (Err(e), false) =>
+        {
+            eprintln!("gurret: {}", e);
+            return EXIT_FAILED;
+        },
+    }
+    EXIT_OK
+}
+
+// `file` in the backing folder, whether it is given on the mount, there or relative to it
+fn backing(file: &str) -> PathBuf
+{
+    let mountpoint = config("PATH");
+    match Path::new(file).strip_prefix(&mountpoint)
+    {
+        Ok(rest) => Path::new(&*BASE_PATH).join(rest),
+        Err(_) => Path::new(&*BASE_PATH).join(file),
+    }
+}
+
+// `file` on the mount
+fn mounted(file: &str) -> Result<PathBuf, String>
+{
+    let backing = backing(file);
+    match backing.strip_prefix(&*BASE_PATH)
+    {
+        Ok(rest) => Ok(Path::new(&config("PATH")).join(rest)),
+        Err(_) => Err(format!("{} is not on the mount", file)),
+    }
+}
+
+// Have the running mount pick up what was changed behind its back
+fn reload()
+{
+    daemon::signal(libc::SIGHUP);
+}
+
+fn report(text: String, json: Value) -> Result<Report, String>
+{
+    Ok(Report {
+        text,
+        json,
+    })
+}
+
+fn mount(daemon: bool) -> Result<Report, String>
+{
+    if !daemon
+    {
+        crate::mount_file_system().map_err(|e| format!("mounting: {}", e))?;
+        return report(String::new(), json!({ "unmounted": config("PATH") }));
+    }
+
+    if let Some(pid) = daemon::running()
+    {
+        return Err(format!("already mounted by pid {}", pid));
+    }
+
+    // The same program, detached in a session of its own, writing to the log
+    let logfile = daemon::logfile();
+    let mut options = std::fs::OpenOptions::new();
+    options.create(true).append(true).mode(0o640);
+    let log = daemon::open_owned(&logfile, &mut options)
+        .map_err(|e| format!("{}: {}", logfile.display(), e))?;
+    let err = log.try_clone().map_err(|e| e.to_string())?;
+    let exe = std::env::current_exe().map_err(|e| e.to_string())?;
+    let mut process = Process::new(exe);
+    process.arg("mount").stdin(Stdio::null()).stdout(log).stderr(err);
+    unsafe {
+        process.pre_exec(|| match libc::setsid()
+        {
+            -1 => Err(std::io::Error::last_os_error()),
+            _ => Ok(()),
+        });
+    }
+    let child = process.spawn().map_err(|e| format!("starting the mount: {}", e))?;
+    report(
+        format!("mounting, pid {}, logging to {}", child.id(), logfile.display()),
+        json!({ "pid": child.id(), "log": logfile }),
+    )
+}
+
+/*
+ * Ask the running mount to stop, so it saves the table before it goes, and
+ * wait for it. Without one, only the mountpoint is left to unmount.
+ */
+fn umount() -> Result<Report, String>
+{
+    let mountpoint = config("PATH");
+    if let Some(pid) = daemon::signal(libc::SIGTERM)
+    {
+        for _ in 0..100
+        {
+            if daemon::running() != Some(pid)
+            {
+                return report(String::new(), json!({ "unmounted": mountpoint, "pid": pid }));
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        return Err(format!("pid {} has not unmounted {} yet", pid, mountpoint));
+    }
+
+    let status = Process::new("fusermount").arg("-u").arg(&mountpoint).status();
+    match status
+    {
+        Ok(status) if status.success() =>
+        {
+            report(String::new(), json!({ "unmounted": mountpoint }))
+        },
+        Ok(status) => Err(format!("fusermount -u {} failed: {}", mountpoint, status)),
+        Err(e) => Err(format!("running fusermount: {}", e)),
+    }
+}
+
+// Whether `mountpoint` is mounted, by the mounts of the system
+fn is_mounted(mountpoint: &str) -> bool
+{
+    let mounts = std::fs::read_to_string("/proc/self/mounts").unwrap_or_default();
+    mounts.lines().any(|line| line.split_whitespace().nth(1) == Some(mountpoint))
+}
+
+fn status() -> Result<Report, String>
+{
+    let mountpoint = config("PATH");
+    let mounted = is_mounted(&mountpoint);
+    let pid = daemon::running();
+    let datasets = Table::from_file().map(|table| table.datasets().len()).unwrap_or(0);
+    let programs = known_programs().len();
+
+    let mut text = format!(
+        "{} {} on {}\n{} datasets tracked, {} programs attested",
+        *BASE_PATH,
+        if mounted { "is mounted" } else { "is not mounted" },
+        mountpoint,
+        datasets,
+        programs
+    );
+    if let Some(pid) = pid
+    {
+        text.push_str(&format!("\nmounted by pid {}", pid));
+    }
+    report(
+        text,
+        json!({
+            "target": *BASE_PATH,
+            "mountpoint": mountpoint,
+            "mounted": mounted,
+            "pid": pid,
+            "datasets": datasets,
+            "programs": programs,
+        }),
+    )
+}
+
+/*
+ * Have the running mount do `request` on its table, see `socket::control`.
+ * It would save what it has over a table changed behind its back.
+ */
+fn ask(request: String) -> Result<Value, String>
+{
+    socket::ask(&request).map_err(|e| format!("asking the mount: {}", e))
+}
+
+fn checkout(file: &str) -> Result<Report, String>
+{
+    let file = backing(file);
+    if daemon::running().is_some()
+    {
+        let answer = ask(format!("checkout {}", file.display()))?;
+        return report(String::new(), answer);
+    }
+
+    let mut table = Table::from_file().unwrap_or_default();
+    table.insert(&file).map_err(|e| format!("checking out {}: {}", file.display(), e))?;
+    table.flush().map_err(|e| format!("saving the table: {}", e))?;
+    report(String::new(), json!({ "checkout": file }))
+}
+
+fn label_get(file: &str) -> Result<Report, String>
+{
+    policy::set_target(&*BASE_PATH);
+    let file = backing(file);
+    if !file.exists()
+    {
+        return Err(format!("{} does not exist", file.display()));
+    }
+    let own = read_tag(&file, LABEL_XATTR).map_err(|e| e.to_string())?;
+    let (ltype, label) = file_label(&file);
+
+    let text = match own.is_empty()
+    {
+        true => format!("{} {} (from its folders)", ltype, label),
+        false => format!("{} {}\n{}", ltype, label, own.trim_end()),
+    };
+    report(
+        text,
+        json!({ "path": file, "type": ltype.to_string(), "value": label.to_string(), "own": own }),
+    )
+}
+
+fn label_set(file: &str, labels: &str, recursive: bool) -> Result<Report, String>
+{
+    policy::set_target(&*BASE_PATH);
+    let file = backing(file);
+    let relabelled = match daemon::running()
+    {
+        Some(_) =>
+        {
+            let flag = match recursive
+            {
+                true => "-r ",
+                false => "",
+            };
+            let answer = ask(format!("relabel {}{} {}", flag, file.display(), labels))?;
+            serde_json::from_value(answer["relabelled"].clone()).map_err(|e| e.to_string())?
+        },
+        None => relabel_unmounted(&file, labels, recursive)?,
+    };
+
+    let text = relabelled.iter().map(|path| path.display().to_string()).collect::<Vec<_>>();
+    report(text.join("\n"), json!({ "path": file, "relabelled": relabelled }))
+}
+
+// Relabel `file` and what it is in the table, while nothing is mounted
+fn relabel_unmounted(file: &Path, labels: &str, recursive: bool)
+    -> Result<Vec<PathBuf>, String>
+{
+    let relabelled = relabel(file, labels, recursive).map_err(|e| e.to_string())?;
+
+    // The lineage shows the labels the datasets have now
+    if let Ok(mut table) = Table::from_file()
+    {
+        for path in &relabelled
+        {
+            if table.contains(path)
+            {
+                table.relabel(path).map_err(|e| e.to_string())?;
+            }
+        }
+        table.flush().map_err(|e| format!("saving the table: {}", e))?;
+    }
+    Ok(relabelled)
+}
+
+// Every dataset reached from `dataset` by `next`, with how far away it is
+fn reach(dataset: &str, next: impl Fn(&str) -> Vec<String>) -> Vec<(String, usize)>
+{
+    let mut seen = HashSet::from([dataset.to_string()]);
+    let mut found = Vec::new();
+    let mut stack: Vec<(String, usize)> =
+        next(dataset).into_iter().rev().map(|name| (name, 1)).collect();
+    while let Some((name, depth)) = stack.pop()
+    {
+        if !seen.insert(name.clone())
+        {
+            continue;
+        }
+        stack.extend(next(&name).into_iter().rev().map(|name| (name, depth + 1)));
+        found.push((name, depth));
+    }
+    found
+}
+
+fn lineage(file: &str) -> Result<Report, String>
+{
+    let file = backing(file);
+    let table = Table::from_file().map_err(|_| "no datasets are being tracked".to_string())?;
+    let name = file.to_string_lossy().into_owned();
+    if !table.contains(&file)
+    {
+        return Err(format!("{} is not tracked", name));
+    }
+
+    let ancestors = reach(&name, |name| table.parents(name));
+    let descendants = reach(&name, |name| table.children(name));
+
+    let mut text = vec![name.clone()];
+    let indent = |depth: usize| "  ".repeat(depth);
+    text.extend(ancestors.iter().map(|(name, depth)| format!("{}from {}", indent(*depth), name)));
+    text.extend(descendants.iter().map(|(name, depth)| format!("{}to {}", indent(*depth), name)));
+
+    let list = |found: &[(String, usize)]| -> Value {
+        found.iter().map(|(path, depth)| json!({ "path": path, "depth": depth })).collect()
+    };
+    report(
+        text.join("\n"),
+        json!({
+            "path": name,
+            "parents": table.parents(&name),
+            "children": table.children(&name),
+            "ancestors": list(&ancestors),
+            "descendants": list(&descendants),
+        }),
+    )
+}
+
+fn revoke(file: &str) -> Result<Report, String>
+{
+    let file = backing(file);
+    if daemon::running().is_some()
+    {
+        let answer = ask(format!("revoke {}", file.display()))?;
+        return report(String::new(), answer);
+    }
+
+    let mut table = Table::from_file().map_err(|_| "no datasets are being tracked".to_string())?;
+    table.revoke(&file).map_err(|e| e.to_string())?;
+    table.flush().map_err(|e| format!("saving the table: {}", e))?;
+    report(String::new(), json!({ "revoked": file }))
+}
+
+fn exe_folder() -> PathBuf
+{
+    Path::new(&*BASE_PATH).join("exe")
+}
+
+// The attested programs, and their TOML
+fn known_programs() -> Vec<(String, Option<toml::Value>)>
+{
+    let entries = match std::fs::read_dir(exe_folder())
+    {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut programs: Vec<_> = entries
+        .flatten()
+        .map(|entry| {
+            let toml = std::fs::read_to_string(entry.path()).ok().and_then(|s| s.parse().ok());
+            (entry.file_name().to_string_lossy().into_owned(), toml)
+        })
+        .collect();
+    programs.sort_by(|a, b| a.0.cmp(&b.0));
+    programs
+}
+
+// The level of the `name` label in `toml`, such as `confidentiality = {name=..,value=..}`
+fn level(toml: &toml::Value, name: &str) -> Option<i64>
+{
+    toml.get(name)?.get("value")?.as_integer()
+}
+
+fn programs_list() -> Result<Report, String>
+{
+    let mut text = Vec::new();
+    let mut list = Vec::new();
+    for (name, toml) in known_programs()
+    {
+        let toml = match toml
+        {
+            Some(toml) => toml,
+            None =>
+            {
+                text.push(format!("{} (unreadable)", name));
+                list.push(json!({ "name": name, "error": "unreadable" }));
+                continue;
+            },
+        };
+        let gate = toml.get("gate");
+        let confidentiality = gate.map_or(level(&toml, "confidentiality"), |gate| {
+            level(gate, "confidentiality")
+        });
+        let integrity = gate.and_then(|gate| level(gate, "integrity"));
+        let argv = toml.get("argv").is_some();
+
+        let mut line = match confidentiality
+        {
+            Some(confidentiality) => format!("{} confidentiality {}", name, confidentiality),
+            None => format!("{} without a confidentiality", name),
+        };
+        if let Some(integrity) = integrity
+        {
+            line.push_str(&format!(", gate to integrity {}", integrity));
+        }
+        if argv
+        {
+            line.push_str(", with an argv schema");
+        }
+        text.push(line);
+        list.push(json!({
+            "name": name,
+            "confidentiality": confidentiality,
+            "gate": gate.is_some(),
+            "integrity": integrity,
+            "argv": argv,
+        }));
+    }
+    report(text.join("\n"), Value::Array(list))
+}
+
+/*
+ * Attest `program`, keeping what else its TOML says, such as its `[argv]`. A
+ * program with an `integrity` is a gate.
+ */
+fn programs_attest(
+    program: &str,
+    confidentiality: i64,
+    integrity: Option<i64>,
+) -> Result<Report, String>
+{
+    if program.contains('/') || program.is_empty()
+    {
+        return Err(format!("{:?} is not a program name", program));
+    }
+    let path = exe_folder().join(program);
+    let mut toml = match std::fs::read_to_string(&path)
+    {
+        Ok(s) => s.parse::<toml::Value>().map_err(|e| format!("{}: {}", path.display(), e))?,
+        Err(_) => toml::Value::Table(Default::default()),
+    };
+    let table = toml.as_table_mut().ok_or_else(|| format!("{} is not a table", path.display()))?;
+
+    let label = |value: i64| {
+        let mut label = toml::value::Table::new();
+        label.insert("name".into(), "linear".into());
+        label.insert("value".into(), value.into());
+        toml::Value::Table(label)
+    };
+    match integrity
+    {
+        Some(integrity) =>
+        {
+            table.remove("confidentiality");
+            let mut gate = toml::value::Table::new();
+            gate.insert("confidentiality".into(), label(confidentiality));
+            gate.insert("integrity".into(), label(integrity));
+            table.insert("gate".into(), toml::Value::Table(gate));
+        },
+        None =>
+        {
+            table.remove("gate");
+            table.insert("confidentiality".into(), label(confidentiality));
+        },
+    }
+
+    std::fs::create_dir_all(exe_folder()).map_err(|e| e.to_string())?;
+    let content = toml::to_string(&toml).map_err(|e| e.to_string())?;
+    std::fs::write(&path, content).map_err(|e| format!("{}: {}", path.display(), e))?;
+    reload();
+    report(String::new(), json!({ "attested": program, "path": path }))
+}
+
+// Through the `gurret.meta.*` xattrs of the file on the mount, see `MetadataHandler::query`
+fn metadata(file: &str) -> Result<Report, String>
+{
+    let file = mounted(file)?;
+    let names = xattr::list(&file).map_err(|e| format!("{}: {}", file.display(), e))?;
+
+    let mut values = serde_json::Map::new();
+    for name in names
+    {
+        let field = match name.to_str().and_then(|name| name.strip_prefix(QUERY_PREFIX))
+        {
+            Some(field) => field.to_string(),
+            None => continue,
+        };
+        if let Ok(Some(value)) = xattr::get(&file, &name)
+        {
+            values.insert(field, String::from_utf8_lossy(&value).into_owned().into());
+        }
+    }
+
+    let text = values.iter().map(|(field, value)| format!("{}={}", field, value.as_str().unwrap()));
+    report(text.collect::<Vec<_>>().join("\n"), json!({ "path": file, "metadata": values }))
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn commands_and_their_arguments()
+    {
+        assert_eq!(Command::parse(&["mount"]), Some(Command::Mount {
+            daemon: false
+        }));
+        assert_eq!(Command::parse(&["mount", "--daemon"]), Some(Command::Mount {
+            daemon: true
+        }));
+        let label = Command::LabelSet {
+            file:      "data",
+            labels:    "labels = 1",
+            recursive: true,
+        };
+        assert_eq!(Command::parse(&["label", "set", "-r", "data", "labels = 1"]), Some(label));
+        let attest = Command::ProgramsAttest {
+            program:         "cp",
+            confidentiality: 2,
+            integrity:       Some(3),
+        };
+        assert_eq!(Command::parse(&["programs", "attest", "cp", "2", "--gate", "3"]), Some(attest));
+        assert_eq!(Command::parse(&["lineage", "a.csv"]), Some(Command::Lineage("a.csv")));
+        let metadata = Some(Command::Metadata("a.csv"));
+        assert_eq!(Command::parse(&["metadata", "show", "a.csv"]), metadata);
+        assert_eq!(Command::parse(&["metadata", "a.csv"]), metadata);
+    }
+
+    #[test]
+    fn anything_else_is_a_usage_error()
+    {
+        assert_eq!(Command::parse(&[]), None);
+        assert_eq!(Command::parse(&["label", "get"]), None);
+        assert_eq!(Command::parse(&["metadata", "show"]), None);
+        assert_eq!(Command::parse(&["programs", "attest", "cp", "secret"]), None);
+        assert_eq!(run(&["gurret".into(), "--json".into(), "nothing".into()]), EXIT_USAGE);
+    }
+
+    #[test]
+    fn lineage_is_reached_once()
+    {
+        let edges = |name: &str| match name
+        {
+            "a" => vec!["b".to_string(), "c".to_string()],
+            "b" => vec!["c".to_string()],
+            "c" => vec!["a".to_string()],
+            _ => vec![],
+        };
+        let found = reach("a", edges);
+        assert_eq!(found, [("b".to_string(), 1), ("c".to_string(), 2)]);
+    }
+}
@@ -6,9 +6,17 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use crate::{file_system::Program, permission::*, XmpFS, BASE_PATH, TABLE};
+use log::error;
+
+use crate::{argv::*, file_system::Program, permission::*, XmpFS, BASE_PATH, TABLE};
 
 pub fn config<S: AsRef<str>>(name: S) -> String
+{
+    try_config(name).unwrap()
+}
+
+/// Like `config`, but for optional keys.
+pub fn try_config<S: AsRef<str>>(name: S) -> Option<String>
 {
     let file = match std::fs::File::open("/home/sivert/master/lh_mount/config")
     {
@@ -26,7 +34,21 @@ pub fn config<S: AsRef<str>>(name: S) -> String
         })
         .collect::<HashMap<String, String>>()
         .remove(name.as_ref())
-        .unwrap()
+}
+
+// `name` in config as a `T`, if it is set, or an error saying what it should be
+pub fn parse_config<T: std::str::FromStr>(name: &str, what: &str) -> std::io::Result<Option<T>>
+{
+    match try_config(name)
+    {
+        None => Ok(None),
+        Some(value) => value.trim().parse().map(Some).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} {:?} is not {}", name, value, what),
+            )
+        }),
+    }
 }
 
 
@@ -65,56 +87,16 @@ pub fn log_time() -> u64
         .unwrap()
         .as_secs()
 }
-/*pub fn log_operation<P, C>(path: P, prefix: C, content: C)
-where
-    P: AsRef<std::path::Path>,
-    C: AsRef<str>,
-{
-    let mut filename = std::path::PathBuf::from(path.as_ref());
-    let s = filename.file_name().unwrap();
-    filename.set_file_name(&format!(".{}", s.to_str().unwrap()));
-
-    println!("filename: ::: {:?}", filename.as_os_str());
-    let mut file = std::fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(filename.as_path())
-        .expect("opening file");
-    println!("???");
-
-    // Another allocation, bleh
-    let content = format!("{} {} {}\n", prefix.as_ref(), content.as_ref(), log_time());
-    file.write_all(content.as_bytes()).unwrap();
-}*/
-
-pub fn get_name_and_file<'s>(info: &'s str) -> (&'s str, &'s str)
-{
-    let s = info.split_once(char::from(0)).unwrap();
-
-    let path = std::path::Path::new(s.0);
-    let name = path.file_name().unwrap().to_str().unwrap();
-    (name, s.1.trim_matches(char::from(0)))
-}
-
-pub fn get_temp_name(path: impl AsRef<Path>) -> String
-{
-    let filename = std::path::PathBuf::from(path.as_ref());
-    let s = filename.file_name().unwrap();
-    format!(".{}", s.to_str().unwrap())
-}
 
-pub fn file_for_temp(path: &str) -> bool
+#[allow(dead_code)]
+pub fn get_program_name(req: &fuser::Request) -> Option<OsString>
 {
-    let path = Path::new(path);
-    let file_name = path.file_name().unwrap();
-    let s = file_name.to_str().unwrap();
-    (s[1..]).starts_with('.')
+    get_program_name_by_pid(req.pid())
 }
 
-#[allow(dead_code)]
-pub fn get_program_name(req: &fuser::Request) -> Option<OsString>
+pub fn get_program_name_by_pid(pid: u32) -> Option<OsString>
 {
-    let cmdline = match std::fs::read_to_string(format!("/proc/{}/cmdline", req.pid()))
+    let cmdline = match std::fs::read_to_string(format!("/proc/{}/cmdline", pid))
     {
         Ok(s) => s,
         _ => return None,
@@ -164,74 +146,157 @@ pub fn program_name_from_path<P: AsRef<Path>>(program_name: P) -> OsString
     s
 }
 
-pub fn derive_from_source(fs: &XmpFS, path: &OsString, source: &OsString)
+pub fn derive_from_source(
+    fs: &XmpFS,
+    path: impl AsRef<Path>,
+    source: impl AsRef<Path>,
+) -> std::io::Result<()>
 {
+    let (path, source) = (path.as_ref(), source.as_ref());
     let mut table = TABLE!(fs.table);
 
     if !table.contains_key(path, source)
     {
-        table.derive(path, source).expect("deriving");
-        table.flush().expect("flushing");
+        table.derive(path, source)?;
+        table.flush()?;
     }
+    Ok(())
 }
 
-pub fn derive_from_toml(fs: &XmpFS, toml: toml::Value, cmdline: String)
+/*
+ * How an attested program was run: its `exe/` TOML, its arguments and the
+ * folder it ran in. Taken when it opens a file for writing, as the process
+ * may well be gone by the time the file is closed.
+ */
+#[derive(Debug, Clone)]
+pub struct Invocation
 {
-    use regex::Regex;
-    let get_regex_field = |field: &str| -> Option<&str> {
-        if let Some(f) = toml.get(field)
+    pub toml: toml::Value,
+    pub args: Vec<String>,
+    pub cwd:  PathBuf,
+}
+
+impl Invocation
+{
+    // How `req` was run, if it is an attested program
+    pub fn of(fs: &XmpFS, req: &fuser::Request) -> Option<Self>
+    {
+        let args = get_cmdline_output(req)?;
+        let toml = known_program(fs, args.first()?)?;
+        let cwd = std::fs::read_link(format!("/proc/{}/cwd", req.pid())).ok()?;
+        Some(Self {
+            toml,
+            args,
+            cwd,
+        })
+    }
+}
+
+/*
+ * Derive the outputs of an attested program from its inputs, by the `[argv]`
+ * schema of its `exe/` TOML, see `argv::Argv`. A TOML without one can still
+ * give `input` and `output` regexes, matched against the whole cmdline. An
+ * invocation that fits neither is left to the lineage from what the process
+ * reads and writes.
+ */
+pub fn derive_from_toml(fs: &XmpFS, invocation: &Invocation)
+{
+    let (toml, args) = (&invocation.toml, &invocation.args);
+    let files = match Argv::from_toml(toml)
+    {
+        Some(Ok(argv)) => argv.files(&args[1..]),
+        Some(Err(e)) => Err(format!("bad [argv]: {}", e)),
+        None => files_from_regex(toml, &args.join(" ")),
+    };
+    let files = match files
+    {
+        Ok(files) => files,
+        Err(e) =>
+        {
+            error!("{:?} does not fit the attested program: {}", args, e);
+            return;
+        },
+    };
+
+    // Relative to the process, and to the backing folder rather than the mount
+    let cwd = &invocation.cwd;
+    let resolve = |files: &[String]| -> Vec<PathBuf> {
+        files
+            .iter()
+            .filter_map(|file| fs.backing(&normalize(&cwd.join(file))))
+            .flat_map(|pattern| expand(&pattern))
+            .filter(|file| file.exists())
+            .collect()
+    };
+    let (inputs, outputs) = (resolve(&files.inputs), resolve(&files.outputs));
+
+    let mut table = TABLE!(fs.table);
+    let mut derived = false;
+    for (output, input) in outputs.iter().flat_map(|o| inputs.iter().map(move |i| (o, i)))
+    {
+        if output == input || table.contains_key(output, input)
         {
-            let f = f.as_str().unwrap();
-            let regex = Regex::new(f).unwrap();
-            let cap = regex.captures(cmdline.as_str()).expect("did not match");
-            cap.get(1).map(|c| c.as_str())
+            continue;
         }
-        else
+        match table.derive(output, input)
         {
-            None
+            Ok(()) => derived = true,
+            Err(e) =>
+            {
+                error!("deriving {} from {} failed: {}", output.display(), input.display(), e)
+            },
         }
-    };
-    let input = get_regex_field("input");
-    let output = get_regex_field("output");
-
-    if let (Some(output), Some(input)) = (output, input)
+    }
+    if derived
     {
-        let input_path = format!("{}/{}", *BASE_PATH, input);
-        let output_path = format!("{}/{}", *BASE_PATH, output);
-
-        let mut table = TABLE!(fs.table);
-
-        if !table.contains_key(&output_path, &input_path)
+        if let Err(e) = table.flush()
         {
-            table.derive(&output_path, &input_path).expect("creating new");
-            table.flush().expect("flushing");
+            error!("flushing lineage failed: {}", e);
         }
     }
 }
 
-pub fn check_for_known_program(fs: &XmpFS, req: &fuser::Request) -> Option<toml::Value>
+// The first group of the `input` and `output` regexes, relative to the TARGET
+fn files_from_regex(toml: &toml::Value, cmdline: &str) -> Result<ArgFiles, String>
 {
-    match get_cmdline_output(req).as_ref().map(|v| v.as_slice())
-    {
-        Some([program_name, ..]) =>
-        {
-            let s = program_name_from_path(program_name);
-            if fs.known_programs.contains(&s)
-            {
-                let path = format!("{}/exe/{}", *BASE_PATH, s.to_str().unwrap());
-                let path = Path::new(&path);
+    use regex::Regex;
+    let get_regex_field = |field: &str| -> Result<String, String> {
+        let f = toml.get(field).and_then(|f| f.as_str()).ok_or(format!("no {}", field))?;
+        let regex = Regex::new(f).map_err(|e| e.to_string())?;
+        let cap = regex.captures(cmdline).and_then(|cap| cap.get(1));
+        let found = cap.ok_or(format!("{} did not match", field))?.as_str();
+        Ok(format!("{}/{}", *BASE_PATH, found))
+    };
+
+    Ok(ArgFiles {
+        inputs:  vec![get_regex_field("input")?],
+        outputs: vec![get_regex_field("output")?],
+    })
+}
 
-                assert!(path.exists());
+// The `exe/` TOML of `program_name`, the first argument of a process, if it is attested
+pub fn known_program(fs: &XmpFS, program_name: &str) -> Option<toml::Value>
+{
+    let s = program_name_from_path(program_name);
+    if fs.known_programs.contains(&s)
+    {
+        let path = format!("{}/exe/{}", *BASE_PATH, s.to_str().unwrap());
+        let toml = std::fs::read_to_string(path).ok()?;
+        toml.parse::<toml::Value>().ok()
+    }
+    else
+    {
+        None
+    }
+}
 
-                let toml = std::fs::read_to_string(path).expect("could not find attested program");
-                toml.parse::<toml::Value>().ok()
-            }
-            else
-            {
-                None
-            }
-        },
-        _ => None,
+// The process group of `pid`, or `pid` itself if it is gone
+pub fn get_process_group(pid: u32) -> u32
+{
+    match unsafe { libc::getpgid(pid as libc::pid_t) }
+    {
+        -1 => pid,
+        group => group as u32,
     }
 }
 
@@ -266,3 +331,18 @@ pub fn set_lattice_of_new_file(path: &OsStr, program: &Program) -> Result<(), i3
     set_output_label(path, program.integrity.clone());
     Ok(())
 }
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn process_group_of_a_process()
+    {
+        let group = unsafe { libc::getpgrp() } as u32;
+        assert_eq!(get_process_group(std::process::id()), group);
+        assert_eq!(get_process_group(u32::MAX / 2), u32::MAX / 2);
+    }
+}
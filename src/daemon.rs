@@ -0,0 +1,264 @@
+use std::{
+    fs::{DirBuilder, File, OpenOptions},
+    io::{Error, ErrorKind, Read, Write},
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt, OpenOptionsExt},
+        io::AsRawFd,
+    },
+    path::{Path, PathBuf},
+};
+
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    iterator::Signals,
+};
+
+use crate::try_config;
+
+// Where the files of a running mount are kept, unless config says otherwise
+pub const RUN_DIR: &str = "/run/gurret";
+
+/*
+ * Make sure nobody but the mount, that runs as root, can put anything where
+ * `file` goes. Its folder is made if it is not there, and has to be owned by
+ * the mount and not writable by anyone else.
+ */
+pub fn root_owned_folder(file: &Path) -> std::io::Result<()>
+{
+    let folder = match file.parent()
+    {
+        Some(folder) if !folder.as_os_str().is_empty() => folder,
+        _ => Path::new("."),
+    };
+    DirBuilder::new().recursive(true).mode(0o755).create(folder)?;
+    let meta = std::fs::symlink_metadata(folder)?;
+    if !meta.is_dir() || meta.uid() != unsafe { libc::geteuid() } || meta.mode() & 0o022 != 0
+    {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("{} is not a folder only the mount can write to", folder.display()),
+        ));
+    }
+    Ok(())
+}
+
+// The socket root controls the running mount through, `CONTROL_SOCKET` in config
+pub fn control_socket() -> PathBuf
+{
+    try_config("CONTROL_SOCKET").unwrap_or_else(|| format!("{}/control.sock", RUN_DIR)).into()
+}
+
+/*
+ * Open `file` in a folder only the mount can write to, without following a
+ * link, and only if it is a file of the mount.
+ */
+pub fn open_owned(file: &Path, options: &mut OpenOptions) -> std::io::Result<File>
+{
+    root_owned_folder(file)?;
+    let opened = options.custom_flags(libc::O_NOFOLLOW).open(file)?;
+    let meta = opened.metadata()?;
+    if !meta.is_file() || meta.uid() != unsafe { libc::geteuid() }
+    {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("{} is not a file of the mount", file.display()),
+        ));
+    }
+    Ok(opened)
+}
+
+/*
+ * Where the pid of a running mount is kept, `PIDFILE` in config. The mount
+ * holds a lock on it from when it is up until it is down again.
+ */
+pub fn pidfile() -> PathBuf
+{
+    try_config("PIDFILE").unwrap_or_else(|| format!("{}/gurret.pid", RUN_DIR)).into()
+}
+
+// Where a daemonised mount writes what it prints, `LOGFILE` in config
+pub fn logfile() -> PathBuf
+{
+    try_config("LOGFILE").unwrap_or_else(|| format!("{}/gurret.log", RUN_DIR)).into()
+}
+
+fn alive(pid: u32) -> bool
+{
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+}
+
+// Whether `pid` runs this program, rather than something that was given its pid
+fn runs_gurret(pid: u32) -> bool
+{
+    let exe = std::fs::read_link(format!("/proc/{}/exe", pid));
+    alive(pid) && exe.is_ok_and(|exe| std::env::current_exe().is_ok_and(|ours| ours == exe))
+}
+
+// Whether another open file holds the lock on `file`
+fn locked(file: &File) -> bool
+{
+    match unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) }
+    {
+        0 =>
+        {
+            unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_UN) };
+            false
+        },
+        _ => Error::last_os_error().raw_os_error() == Some(libc::EWOULDBLOCK),
+    }
+}
+
+// The pid of the running mount, if there is one
+pub fn running() -> Option<u32>
+{
+    running_by(&pidfile())
+}
+
+// The pid in `pidfile`, if the mount that wrote it still holds it
+fn running_by(pidfile: &Path) -> Option<u32>
+{
+    let mut options = OpenOptions::new();
+    let mut file = options.read(true).custom_flags(libc::O_NOFOLLOW).open(pidfile).ok()?;
+    if !locked(&file)
+    {
+        return None;
+    }
+    let mut pid = String::new();
+    file.read_to_string(&mut pid).ok()?;
+    let pid = pid.trim().parse().ok()?;
+    runs_gurret(pid).then_some(pid)
+}
+
+// The pidfile of this mount, locked as long as it is kept and removed after
+#[derive(Debug)]
+pub struct Pidfile
+{
+    path:  PathBuf,
+    _file: File,
+}
+
+impl Drop for Pidfile
+{
+    fn drop(&mut self)
+    {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// Claim the pidfile for this process, unless another mount is running
+pub fn write_pidfile() -> std::io::Result<Pidfile>
+{
+    let path = pidfile();
+    root_owned_folder(&path)?;
+    claim(&path)
+}
+
+fn claim(path: &Path) -> std::io::Result<Pidfile>
+{
+    if let Some(pid) = running_by(path)
+    {
+        return Err(Error::new(ErrorKind::AlreadyExists, format!("already mounted by pid {}", pid)));
+    }
+
+    // What is left is stale, and made anew rather than written through
+    match std::fs::remove_file(path)
+    {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true).mode(0o644).custom_flags(libc::O_NOFOLLOW);
+    let mut file = options.open(path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0
+    {
+        return Err(Error::last_os_error());
+    }
+    writeln!(file, "{}", std::process::id())?;
+    Ok(Pidfile {
+        path:  path.to_path_buf(),
+        _file: file,
+    })
+}
+
+// Send `signal` to the running mount, if there is one, and give back its pid
+pub fn signal(signal: i32) -> Option<u32>
+{
+    let pid = running()?;
+    match unsafe { libc::kill(pid as libc::pid_t, signal) }
+    {
+        0 => Some(pid),
+        _ => None,
+    }
+}
+
+/*
+ * Wait, without using the CPU, until the mount is asked to stop by SIGINT or
+ * SIGTERM. A SIGHUP runs `reload` right away.
+ */
+pub fn wait_for_shutdown(reload: impl Fn()) -> std::io::Result<i32>
+{
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP])?;
+    for signal in signals.forever()
+    {
+        match signal
+        {
+            SIGHUP => reload(),
+            signal => return Ok(signal),
+        }
+    }
+    Err(Error::other("no more signals"))
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn folder_others_can_write_to_is_refused()
+    {
+        let root = tempfile::TempDir::new().unwrap();
+        let file = root.path().join("run").join("file");
+        root_owned_folder(&file).unwrap();
+        assert_eq!(std::fs::metadata(root.path().join("run")).unwrap().mode() & 0o777, 0o755);
+
+        let shared = root.path().join("shared");
+        std::fs::create_dir(&shared).unwrap();
+        std::fs::set_permissions(&shared, std::fs::Permissions::from_mode(0o1777)).unwrap();
+        let refused = root_owned_folder(&shared.join("file")).unwrap_err();
+        assert_eq!(refused.kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn pidfile_counts_while_it_is_held()
+    {
+        let root = tempfile::TempDir::new().unwrap();
+        let path = root.path().join("gurret.pid");
+
+        // A planted pid is not a mount
+        std::fs::write(&path, "1\n").unwrap();
+        assert_eq!(running_by(&path), None);
+
+        let pidfile = claim(&path).unwrap();
+        assert_eq!(running_by(&path), Some(std::process::id()));
+        assert_eq!(claim(&path).unwrap_err().kind(), ErrorKind::AlreadyExists);
+        drop(pidfile);
+        assert!(!path.exists());
+
+        // Nor is a link to somewhere else
+        std::os::unix::fs::symlink("/etc/passwd", &path).unwrap();
+        assert_eq!(running_by(&path), None);
+        drop(claim(&path).unwrap());
+        assert!(std::fs::symlink_metadata("/etc/passwd").unwrap().is_file());
+    }
+
+    #[test]
+    fn only_live_processes_run()
+    {
+        assert!(alive(std::process::id()));
+        assert!(!alive(i32::MAX as u32));
+    }
+}
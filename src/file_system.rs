@@ -1,20 +1,27 @@
 use std::{
     collections::{HashMap, HashSet},
-    ffi::{OsStr, OsString},
+    ffi::{CString, OsStr, OsString},
     io::ErrorKind,
     os::unix::{
         ffi::OsStrExt,
-        fs::{FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+        fs::{DirEntryExt, FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt},
     },
     path::Path,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
+    audit::{self, Decision},
+    inode::*,
     lattice::{Lattice, LatticePair, *},
     metadata::*,
     permission::{self, *},
+    policy::{inherit_labels, DEFAULT_LABEL_XATTR, LABEL_XATTR},
+    pool::*,
     table::*,
     BASE_PATH, TABLE,
 };
@@ -26,8 +33,8 @@ use fuser::{
     ReplyEntry, ReplyOpen, ReplyWrite, ReplyXattr, Request, TimeOrNow,
 };
 use libc::{
-    c_int, EINVAL, EIO, ENOENT, ENOSYS, EPERM, O_ACCMODE, O_APPEND, O_CREAT, O_EXCL, O_RDONLY,
-    O_RDWR, O_TRUNC, O_WRONLY,
+    c_int, EACCES, EINVAL, EIO, ENODATA, ENOENT, EPERM, O_ACCMODE, O_APPEND, O_CREAT, O_EXCL,
+    O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY,
 };
 use log::error;
 
@@ -46,6 +53,8 @@ pub struct DirInfo
 pub struct Program
 {
     pub program_name:    OsString,
+    // The process group it is in
+    pub group:           u32,
     pub resources:       HashSet<OsString>,
     pub integrity:       LatticePair,
     pub confidentiality: LatticePair,
@@ -54,94 +63,17 @@ pub struct Program
 
 
 use std::path::PathBuf;
-type MetadataId = u64;
-
-#[derive(Debug, Clone)]
-pub enum MSource
-{
-    Value(OsString),
-    Parent
-    {
-        parent_ids: MetadataId,
-        own:        OsString,
-    },
-}
-use MSource::*;
-
-#[derive(Default, Debug)]
-pub struct Broker
-{
-    pub available_id:    MetadataId,
-    pub id_to_metadata:  Vec<MSource>,
-    pub file_name_to_id: HashMap<OsString, MetadataId>,
-}
-
-impl Broker
-{
-    fn get_name(&self, id: MetadataId) -> &OsString
-    {
-        match self.id_to_metadata.get(id as usize)
-        {
-            Some(source) => match source
-            {
-                MSource::Value(ref p) => p,
-                MSource::Parent {
-                    parent_ids,
-                    own,
-                } => &own,
-            },
-            _ => unreachable!(),
-        }
-    }
-
-    fn map_name_to_id(&mut self, program_name: OsString, parent: Option<OsString>) -> MSource
-    {
-        let id = self.available_id;
-        self.available_id += 1;
-
-        self.file_name_to_id.insert(program_name.clone(), id);
-
-        let source = match parent
-        {
-            Some(parent) =>
-            {
-                let id = self.file_name_to_id.get(&parent).unwrap();
-                MSource::Parent {
-                    parent_ids: *id, own: program_name
-                }
-            },
-            _ => MSource::Value(program_name),
-        };
-
-        self.id_to_metadata.push(source.clone());
-        source
-    }
-
-    fn get_program(&mut self, program_name: OsString, parent: Option<OsString>) -> PProgram
-    {
-        PProgram {
-            m_source: self.map_name_to_id(program_name, parent)
-        }
-    }
-}
-
-
-#[derive(Debug)]
-pub struct PProgram
-{
-    pub m_source: MSource,
-}
-
 
 impl Program
 {
-    fn new(program_name: OsString, label: LabelResponse) -> Self
+    fn new(program_name: OsString, group: u32, label: LabelResponse) -> Self
     {
         match label
         {
             // If we read something with higher lattice, we get tainted
             LabelResponse::Confidentiality(label) => Self {
                 program_name,
+                group,
                 resources: HashSet::new(),
                 integrity: lattice_pair_default(),
                 confidentiality: label,
@@ -153,6 +85,7 @@ impl Program
                 confidentiality,
             } => Self {
                 program_name,
+                group,
                 resources: HashSet::new(),
                 integrity,
                 confidentiality,
@@ -201,76 +134,169 @@ impl Program
             self.resources.insert(file);
         }
     }
+
+    /*
+     * Add what `other`, another process of the group, has read. It reaches
+     * this one through pipes and the like, and taints it even if it is a
+     * gate, as only what a gate reads itself keeps its privileges.
+     */
+    fn merge(&mut self, other: &Program)
+    {
+        self.resources.extend(other.resources.iter().cloned());
+        let lattice = crate::lattice::create_lattice(&self.integrity.0);
+        if lattice.compare(&self.integrity.1, &other.integrity.1) == std::cmp::Ordering::Greater
+        {
+            self.integrity = other.integrity.clone();
+        }
+    }
 }
 
 
 unsafe impl Send for XmpFS {}
 pub struct XmpFS
 {
-    pub counter: u64,
-
-    pub inode_to_path: HashMap<u64, OsString>,
-    pub path_to_inode: HashMap<OsString, u64>,
+    pub next_handle: u64,
+    pub inodes:      Inodes,
 
     pub opened_directories: HashMap<u64, Vec<DirInfo>>,
-    pub opened_files:       HashMap<u64, std::fs::File>,
+    pub opened_files:       Handles,
     pub table:              Arc<Mutex<Table>>,
     pub known_programs:     Vec<OsString>,
 
-    pub programs: HashMap<u32, Program>,
-
-    pub pprograms: HashMap<OsString, PProgram>,
+    // What the processes read and write, for their lineage
+    pub tracked: Arc<Mutex<Tracked>>,
 
-    pub han: Broker,
+    // Where the backing folder is mounted, if it is known
+    mountpoint:      Option<PathBuf>,
+    // Whether files a process may not read are hidden from it
+    hide_unreadable: bool,
 
-    metadata_handler: Box<dyn MetadataHandler>,
+    pub metadata: MetadataEvents,
+    pool:         WorkerPool,
+    // Set by SIGHUP, to pick up what changed on disk
+    pub reload:   Arc<AtomicBool>,
 }
 
+// The open files by handle, shared with the workers that read and write them
+pub type Handles = Arc<RwLock<HashMap<u64, Arc<std::fs::File>>>>;
 
-impl XmpFS
+/*
+ * What the processes have read, and what they have open for writing, to
+ * derive one from the other. Shared with the workers, that record an open
+ * once it went through.
+ */
+#[derive(Default)]
+pub struct Tracked
 {
-    pub fn new() -> XmpFS
-    {
-        let mut map: HashMap<OsString, PProgram> = HashMap::new();
-        let mut han = Broker::default();
-
-
+    // What each process has read, by the pid
+    pub programs: HashMap<u32, Program>,
+    // The files open for writing, by handle
+    pub written:  HashMap<u64, Written>,
+}
 
+// A file open for writing, derived when it is closed
+pub struct Written
+{
+    // The process that opened it
+    pub pid:      u32,
+    pub path:     PathBuf,
+    // How it was run, if it is an attested program
+    pub attested: Option<Invocation>,
+}
 
-        let name: OsString = "/tmp/dropbox_folder/file0".into();
-        let pp = han.get_program(name.clone(), None);
-        map.insert(name.clone(), pp);
-        std::fs::File::create(name);
+impl Tracked
+{
+    // `pid` opened `path` for reading, what its group writes is derived from it
+    fn read(&mut self, pid: u32, path: &Path)
+    {
+        if !labelled(path)
+        {
+            return;
+        }
+        let label = LabelResponse::Confidentiality(file_label(path));
+        if let Some(program) = self.programs.get_mut(&pid)
+        {
+            program.open(path.as_os_str().to_os_string(), Some(label));
+        }
+    }
 
+    /*
+     * What `pid` has read, together with everything the rest of its process
+     * group read. A pipeline or a shell and the tools it runs share one group,
+     * so a file written by one process is derived from what the others read.
+     */
+    fn reads(&self, pid: u32) -> Option<Program>
+    {
+        let mut program = self.programs.get(&pid)?.clone();
+        let group = program.group;
+        let others = self.programs.iter().filter(|(other, _)| **other != pid);
+        for (_, other) in others.filter(|(_, other)| other.group == group)
+        {
+            program.merge(other);
+        }
+        Some(program)
+    }
+}
 
+/*
+ * Everything a metadata event needs, cheap to clone into a worker. The clones
+ * share the fields, so a reload reaches every one of them.
+ */
+#[derive(Clone)]
+pub struct MetadataEvents
+{
+    fields:    Arc<RwLock<Fields>>,
+    table:     Arc<Mutex<Table>>,
+    traversal: Traversal,
+}
 
+// The metadata fields of config and the builtins, loaded again as a whole
+#[derive(Clone)]
+struct Fields
+{
+    handler:  Arc<dyn MetadataHandler>,
+    builtins: Arc<Vec<Builtin>>,
+}
 
-        for i in 1..100
-        {
-            let name: OsString = format!("/tmp/dropbox_folder/file{i}").into();
-            let parent: OsString = format!("/tmp/dropbox_folder/file{}", i - 1).into();
-            let pp = han.get_program(name.clone(), Some(parent));
-            std::fs::File::create(&name);
+impl Fields
+{
+    fn from_config() -> std::io::Result<Self>
+    {
+        Ok(Self {
+            handler:  handler_from_config()?,
+            builtins: Arc::new(builtins_from_config()?),
+        })
+    }
+}
 
-            map.insert(name, pp);
-        }
 
+impl XmpFS
+{
+    // The file system as config has it, or what is wrong with the config
+    pub fn new() -> std::io::Result<XmpFS>
+    {
         let table = Arc::new(Mutex::new(Table::from_file().unwrap_or_else(|_| Table::default())));
-        XmpFS {
-            han,
-            counter: 1,
-            inode_to_path: HashMap::with_capacity(1024),
-            path_to_inode: HashMap::with_capacity(1024),
+        let metadata = MetadataEvents {
+            fields:    Arc::new(RwLock::new(Fields::from_config()?)),
+            table:     Arc::clone(&table),
+            traversal: traversal_from_config()?,
+        };
+        Ok(XmpFS {
+            next_handle: 1,
+            inodes: Inodes::new(OsStr::from_bytes(BASE_PATH.as_bytes())),
             opened_directories: HashMap::with_capacity(2),
-            opened_files: HashMap::with_capacity(2),
+            opened_files: Arc::new(RwLock::new(HashMap::with_capacity(2))),
             table,
             known_programs: Vec::new(),
-            programs: HashMap::new(),
-            pprograms: map,
+            tracked: Arc::new(Mutex::new(Tracked::default())),
             /*derive:             None,
              *dependency_map:     HashMap::new(), */
-            metadata_handler: Box::new(MockHandler::new()),
-        }
+            mountpoint: try_config("PATH").map(PathBuf::from),
+            hide_unreadable: try_config("HIDE_UNREADABLE").is_some_and(|hide| hide == "true"),
+            metadata,
+            pool: pool_from_config()?,
+            reload: Arc::new(AtomicBool::new(false)),
+        })
     }
 
     pub fn get_known_programs(&self) -> Vec<OsString>
@@ -283,87 +309,282 @@ impl XmpFS
             .collect()
     }
 
-    #[allow(dead_code)]
-    pub fn log_operation(&mut self, ino: u64, req: &Request, prefix: &str)
+    pub fn populate_root_dir(&mut self)
+    {
+        self.known_programs = self.get_known_programs();
+    }
+
+    // The backing file of `ino` is gone, so are its names
+    pub fn unregister_ino(&mut self, ino: u64)
+    {
+        self.inodes.unlink_all(ino);
+    }
+
+    fn next_handle(&mut self) -> u64
+    {
+        let fh = self.next_handle;
+        self.next_handle += 1;
+        fh
+    }
+
+    pub fn metadata_event(
+        &self,
+        path: &Path,
+        operation: Operation,
+        context: &Context,
+    ) -> Result<(), c_int>
     {
-        let cmdline = std::fs::read_to_string(format!("/proc/{}/cmdline", req.pid())).unwrap();
-        let (program, _) = get_name_and_file(&cmdline);
-        let full_path = self.inode_to_path.get(&ino).unwrap();
-        if full_path.to_str().unwrap().contains(".attested-programs")
+        self.metadata.event(path, operation, context)
+    }
+
+    /*
+     * The backing file a symlink made in `dir` points to, if it exists. A
+     * link to the mount is a link to the backing folder.
+     */
+    fn link_target(&self, dir: &Path, link: &Path) -> Option<PathBuf>
+    {
+        let link = match &self.mountpoint
         {
-            return;
+            Some(mountpoint) => rebase(link, mountpoint, Path::new(&*BASE_PATH)),
+            None => None,
         }
+        .unwrap_or_else(|| dir.join(link));
 
-        let temp_name = get_temp_name(full_path);
-        let path = Path::new(self.inode_to_path.get(&ino).unwrap());
-        let parent = path.parent().unwrap();
+        let target = std::fs::canonicalize(link).ok()?;
+        match target.starts_with(&*BASE_PATH)
+        {
+            true => Some(target),
+            false => None,
+        }
+    }
 
-        let entry_path = parent.join(temp_name);
+    // `path` in the backing folder, whether it is given there or on the mount
+    pub fn backing(&self, path: &Path) -> Option<PathBuf>
+    {
+        let base = Path::new(&*BASE_PATH);
+        match self.mountpoint.as_ref().and_then(|mountpoint| rebase(path, mountpoint, base))
+        {
+            Some(path) => Some(path),
+            None if path.starts_with(base) => Some(path.to_path_buf()),
+            None => None,
+        }
+    }
 
-        if !file_for_temp(entry_path.as_os_str().to_str().unwrap())
+    /*
+     * Pick up the attested programs and HIDE_UNREADABLE again, after a SIGHUP.
+     * The metadata fields and the table are shared with the other threads, and
+     * are reloaded right away, see `MetadataEvents::reload`.
+     */
+    fn reload_if_asked(&mut self)
+    {
+        if !self.reload.swap(false, Ordering::Relaxed)
         {
-            let mut file = std::fs::OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(entry_path)
-                .expect("opening file");
+            return;
+        }
+        self.known_programs = self.get_known_programs();
+        self.hide_unreadable = try_config("HIDE_UNREADABLE").is_some_and(|hide| hide == "true");
+    }
 
-            use std::io::prelude::*;
-            let content = format!("{} {} {}\n", prefix, program, log_time());
+    fn handle(&self, fh: u64) -> Option<Arc<std::fs::File>>
+    {
+        self.opened_files.read().expect("getting lock").get(&fh).cloned()
+    }
 
-            file.write_all(content.as_bytes()).unwrap();
+    /*
+     * `entry_path` was written by `program`, so it is derived from the
+     * datasets the program has read, and made at least as secret as them.
+     */
+    pub fn derive_data(&self, entry_path: &Path, program: &Program) -> std::io::Result<()>
+    {
+        let sources = program.resources.iter().filter(|file| Path::new(file) != entry_path);
+        for (i, file) in sources.enumerate()
+        {
+            if i == 0
+            {
+                taint(entry_path, &program.integrity)?;
+            }
+            derive_from_source(self, entry_path, file)?;
         }
+        Ok(())
     }
 
-    pub fn populate_root_dir(&mut self)
+    /*
+     * Track the reads of the process behind `req`, and give back its pid. A
+     * gate only has its privileges for itself, never for the rest of its group.
+     */
+    fn program(&self, req: &Request) -> u32
     {
-        let rootino = self.add_inode(OsStr::from_bytes(BASE_PATH.as_bytes()));
-        self.known_programs = self.get_known_programs();
-        assert_eq!(rootino, 1);
+        let (pid, group) = (req.pid(), get_process_group(req.pid()));
+        let mut tracked = self.tracked.lock().expect("getting lock");
+        if tracked.programs.get(&pid).is_none_or(|program| program.group != group)
+        {
+            /*
+             * Forget the processes that are gone, before their pid is reused,
+             * unless their group is still there to write what they read
+             */
+            let alive = |pid: &u32| Path::new(&format!("/proc/{}", pid)).exists();
+            let opened = self.opened_files.read().expect("getting lock");
+            tracked.written.retain(|fh, written| alive(&written.pid) || opened.contains_key(fh));
+            drop(opened);
+            let writing: HashSet<u32> =
+                tracked.written.values().map(|written| written.pid).collect();
+            tracked.programs.retain(|pid, program| {
+                alive(pid) || alive(&program.group) || writing.contains(pid)
+            });
+
+            let name = get_program_name(req).unwrap_or_default();
+            let label = self
+                .get_confidentiality_label(name.clone())
+                .unwrap_or_else(|| LabelResponse::Confidentiality(lattice_pair_default()));
+            tracked.programs.insert(pid, Program::new(name, group, label));
+        }
+        pid
     }
 
-    pub fn add_inode(&mut self, path: &OsStr) -> u64
+    // `req` opens `path` for writing, it is derived once it is released
+    fn writing(&self, req: &Request, path: &Path) -> Written
     {
-        let ino = self.counter;
-        self.counter += 1;
-        self.path_to_inode.insert(path.to_os_string(), ino);
-        self.inode_to_path.insert(ino, path.to_os_string());
-        ino
+        Written {
+            pid:      self.program(req),
+            path:     path.to_path_buf(),
+            attested: Invocation::of(self, req),
+        }
     }
 
-    pub fn add_or_create_inode(&mut self, path: impl AsRef<Path>) -> u64
+    /*
+     * Derive what was written as `fh` from what its process group read, and
+     * what an attested program wrote from its inputs, once it is closed.
+     */
+    fn derive_written(&self, fh: u64)
     {
-        let path = path.as_ref().as_os_str().to_os_string();
+        let mut tracked = self.tracked.lock().expect("getting lock");
+        let written = match tracked.written.remove(&fh)
+        {
+            Some(written) => written,
+            None => return,
+        };
+        let program = tracked.reads(written.pid);
+        drop(tracked);
 
-        if let Some(x) = self.path_to_inode.get(&path)
+        if let Some(program) = program
+        {
+            if let Err(e) = self.derive_data(&written.path, &program)
+            {
+                error!("deriving {} failed: {}", written.path.display(), e);
+            }
+        }
+        if let Some(invocation) = &written.attested
         {
-            return *x;
+            derive_from_toml(self, invocation);
         }
+    }
+}
 
-        self.add_inode(&path)
+impl MetadataEvents
+{
+    pub fn handler(&self) -> Arc<dyn MetadataHandler>
+    {
+        Arc::clone(&self.fields.read().expect("getting lock").handler)
     }
 
-    pub fn get_inode(&self, path: impl AsRef<Path>) -> Option<u64>
+    /*
+     * Load the metadata fields and the table again, for every thread at once.
+     * The table is swapped under its lock, so nothing saves the one it had
+     * over what is on disk. A broken config keeps what was loaded before.
+     */
+    pub fn reload(&self)
     {
-        self.path_to_inode.get(path.as_ref().as_os_str()).map(|x| *x)
+        match Fields::from_config()
+        {
+            Ok(fields) => *self.fields.write().expect("getting lock") = fields,
+            Err(e) => error!("reloading the metadata fields failed: {}", e),
+        }
+        let mut table = TABLE!(self.table);
+        match Table::from_file()
+        {
+            Ok(loaded) => *table = loaded,
+            Err(e) => error!("reloading the table failed: {}", e),
+        }
     }
 
-    pub fn unregister_ino(&mut self, ino: u64)
+    // Like `event`, with a refusal of the metadata check recorded in the audit log
+    pub fn checked(&self, path: &Path, operation: Operation, context: &Context)
+        -> Result<(), c_int>
     {
-        if !self.inode_to_path.contains_key(&ino)
+        let checked = self.event(path, operation, context);
+        if checked.is_err()
         {
-            return;
+            audit_check(context, path, operation, false);
         }
-        self.path_to_inode.remove(&self.inode_to_path[&ino]);
-        self.inode_to_path.remove(&ino);
+        checked
     }
 
-    pub fn derive_data(&self, _req: &Request, entry_path: &OsString, program: &Program)
+    /*
+     * Run the metadata fields of `path`, and of every file it was derived
+     * from, that want to act on `operation`. The checks of the lineage run in
+     * parallel, and what the handler remembers of a modified file is dropped
+     * first. So is what it remembers of a file whose metadata was updated.
+     */
+    pub fn event(&self, path: &Path, operation: Operation, context: &Context) -> Result<(), c_int>
     {
-        for file in program.resources.iter()
+        let Fields {
+            handler,
+            builtins,
+        } = self.fields.read().expect("getting lock").clone();
+
+        let mut updated = false;
+        for builtin in builtins.iter().filter(|b| b.triggered_by(operation))
+        {
+            match builtin.apply(path, context)
+            {
+                Ok(()) => updated = true,
+                Err(e) => error!("updating {} of {} failed: {}", builtin.name(), path.display(), e),
+            }
+        }
+
+        if operation.modifies() || updated
         {
-            derive_from_source(self, entry_path, file);
+            handler.invalidate(path);
+        }
+        if operation.modifies()
+        {
+            for path in context.old_path.iter().chain(&context.new_path)
+            {
+                handler.invalidate(path);
+            }
+        }
+
+        // Only long enough to take a snapshot, the checks of other events run meanwhile
+        let lineage = TABLE!(self.table).lineage();
+        let lineage = ancestors(path, |path| {
+            lineage.parents(path.as_os_str()).into_iter().map(PathBuf::from).collect()
+        });
+
+        let changes = self.traversal.changes(Arc::clone(&handler), &lineage, operation, context);
+        let changes = match changes
+        {
+            Ok(changes) => changes,
+            Err(e) =>
+            {
+                error!("metadata check for {} failed: {}", path.display(), e);
+                return Err(EIO);
+            },
+        };
+
+        for change in changes
+        {
+            match change.execute()
+            {
+                Ok(true) => handler.invalidate(&change.request.path),
+                Ok(false) => (),
+                Err(e) =>
+                {
+                    error!("metadata execute failed: {}", e);
+                    return Err(EIO);
+                },
+            }
         }
+        Ok(())
     }
 }
 
@@ -390,10 +611,15 @@ fn meta2attr(m: &std::fs::Metadata, ino: u64) -> FileAttr
         blocks: m.blocks(),
         atime: m.accessed().unwrap_or(UNIX_EPOCH),
         mtime: m.modified().unwrap_or(UNIX_EPOCH),
-        ctime: UNIX_EPOCH + Duration::from_secs(m.ctime().try_into().unwrap_or(0)),
+        ctime: UNIX_EPOCH
+            + Duration::new(
+                m.ctime().try_into().unwrap_or(0),
+                m.ctime_nsec().try_into().unwrap_or(0),
+            ),
         crtime: m.created().unwrap_or(UNIX_EPOCH),
         kind: ft2ft(m.file_type()),
-        perm: m.permissions().mode() as u16,
+        // Without the file type bits
+        perm: (m.mode() & 0o7777) as u16,
         nlink: m.nlink() as u32,
         uid: m.uid(),
         gid: m.gid(),
@@ -403,106 +629,408 @@ fn meta2attr(m: &std::fs::Metadata, ino: u64) -> FileAttr
     }
 }
 
-fn errhandle(e: std::io::Error, not_found: impl FnOnce() -> ()) -> libc::c_int
+fn c_path(path: &Path) -> std::io::Result<CString>
 {
-    match e.kind()
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e))
+}
+
+fn check(ret: c_int) -> std::io::Result<()>
+{
+    match ret
     {
-        ErrorKind::PermissionDenied => EPERM,
-        ErrorKind::NotFound =>
-        {
-            not_found();
-            ENOENT
-        },
-        e =>
-        {
-            error!("{:?}", e);
-            EIO
-        },
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
     }
 }
 
-impl Filesystem for XmpFS
+fn truncate(path: &Path, size: u64) -> std::io::Result<()>
+{
+    let path = c_path(path)?;
+    check(unsafe { libc::truncate(path.as_ptr(), size as libc::off_t) })
+}
+
+// Change the owner of `path` itself, not what it links to. `None` is left as is.
+fn lchown(path: &Path, uid: Option<u32>, gid: Option<u32>) -> std::io::Result<()>
 {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry)
+    let path = c_path(path)?;
+    let uid = uid.unwrap_or(u32::MAX) as libc::uid_t;
+    let gid = gid.unwrap_or(u32::MAX) as libc::gid_t;
+    check(unsafe { libc::lchown(path.as_ptr(), uid, gid) })
+}
+
+fn utimens(path: &Path, atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>) -> std::io::Result<()>
+{
+    let timespec = |time: Option<TimeOrNow>| match time
     {
-        if !self.inode_to_path.contains_key(&parent)
+        None => libc::timespec {
+            tv_sec:  0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+        Some(TimeOrNow::Now) => libc::timespec {
+            tv_sec:  0,
+            tv_nsec: libc::UTIME_NOW,
+        },
+        Some(TimeOrNow::SpecificTime(time)) =>
         {
-            return reply.error(ENOENT);
-        }
-
-        let parent_path = Path::new(&self.inode_to_path[&parent]);
-        let entry_path = parent_path.join(&name);
+            let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            libc::timespec {
+                tv_sec:  since.as_secs() as libc::time_t,
+                tv_nsec: since.subsec_nanos() as libc::c_long,
+            }
+        },
+    };
 
-        let entry_inode = self.get_inode(&entry_path);
+    let path = c_path(path)?;
+    let times = [timespec(atime), timespec(mtime)];
+    check(unsafe {
+        libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW)
+    })
+}
 
-        match std::fs::symlink_metadata(entry_path)
-        {
-            Err(e) =>
-            {
-                reply.error(errhandle(e, || {
-                    // if not found:
-                    if let Some(ino) = entry_inode
-                    {
-                        self.unregister_ino(ino);
-                    }
-                }));
-            },
-            Ok(m) =>
-            {
-                let ino = match entry_inode
-                {
-                    Some(x) => x,
-                    None =>
-                    {
-                        let parent_path = Path::new(&self.inode_to_path[&parent]);
-                        let entry_path = parent_path.join(&name);
-                        self.add_or_create_inode(entry_path)
-                    },
-                };
+// `path` moved from under `from` to under `to`, if it is under `from`
+fn rebase(path: &Path, from: &Path, to: &Path) -> Option<PathBuf>
+{
+    path.strip_prefix(from).ok().map(|rest| to.join(rest))
+}
 
-                let attr: FileAttr = meta2attr(&m, ino);
+/*
+ * A file the mount made is given to the caller that asked for it, as the
+ * kernel would have. In a setgid folder it keeps the group of the folder.
+ */
+fn give_to_caller(path: &Path, req: &Request) -> std::io::Result<()>
+{
+    let folder = path.parent().and_then(|folder| std::fs::metadata(folder).ok());
+    let gid = match folder.is_some_and(|folder| folder.mode() & libc::S_ISGID != 0)
+    {
+        true => None,
+        false => Some(req.gid()),
+    };
+    lchown(path, Some(req.uid()), gid)
+}
 
-                reply.entry(&TTL, &attr, 1);
-            },
-        }
+// Remove what was made for a request that then failed
+fn unmake(path: &Path)
+{
+    if let Err(e) = std::fs::remove_file(path)
+    {
+        error!("removing {} failed: {}", path.display(), e);
     }
+}
 
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr)
+// Like `unmake`, for a folder
+fn unmake_dir(path: &Path)
+{
+    if let Err(e) = std::fs::remove_dir(path)
     {
-        //println!("getattr");
-        if !self.inode_to_path.contains_key(&ino)
-        {
-            return reply.error(ENOENT);
-        }
-
+        error!("removing {} failed: {}", path.display(), e);
+    }
+}
 
-        let entry_path = Path::new(&self.inode_to_path[&ino]);
+// A FIFO, socket, device or regular file, by the type in `mode`
+fn make_node(path: &Path, mode: u32, rdev: u32) -> std::io::Result<()>
+{
+    let path = c_path(path)?;
+    check(unsafe { libc::mknod(path.as_ptr(), mode as libc::mode_t, rdev as libc::dev_t) })
+}
 
-        match std::fs::symlink_metadata(entry_path)
-        {
-            Err(e) =>
-            {
-                reply.error(errhandle(e, || {
-                    // if not found:
-                    self.unregister_ino(ino);
-                }));
-            },
-            Ok(m) =>
-            {
-                let attr: FileAttr = meta2attr(&m, ino);
-                reply.attr(&TTL, &attr);
-            },
-        }
+// Reply with the size of an xattr when asked for it, otherwise the xattr itself
+fn reply_xattr(data: &[u8], size: u32, reply: ReplyXattr)
+{
+    if size == 0
+    {
+        reply.size(data.len() as u32);
     }
-
-    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen)
+    else if data.len() <= size as usize
     {
-        if !self.inode_to_path.contains_key(&ino)
-        {
-            return reply.error(ENOENT);
-        }
+        reply.data(data);
+    }
+    else
+    {
+        reply.error(libc::ERANGE);
+    }
+}
+
+// What the metadata check decided about `operation` on `path`, for the audit log
+fn audit_check(context: &Context, path: &Path, operation: Operation, allowed: bool)
+{
+    let operation = operation.to_string().to_lowercase();
+    audit::record(Decision::new(context.pid, context.uid, path, &operation, allowed));
+}
+
+// The errno of a failed call, as it is
+fn errno(e: std::io::Error) -> c_int
+{
+    e.raw_os_error().unwrap_or(EIO)
+}
+
+/*
+ * Which extended attributes a caller may see. The metadata stored under
+ * `METADATA_PREFIX` is only shown to root, everyone else sees it through
+ * the `QUERY_PREFIX` names, as the access programs of the fields allow.
+ */
+fn xattr_visible(name: &OsStr, context: &Context) -> bool
+{
+    context.uid == 0 || !name.as_bytes().starts_with(METADATA_PREFIX.as_bytes())
+}
+
+/*
+ * Which extended attributes a caller may change. Labels, default labels and
+ * the metadata kept by the fields, are only changed by root, and the `QUERY_PREFIX`
+ * names are not stored anywhere.
+ */
+fn xattr_writable(name: &OsStr, context: &Context) -> bool
+{
+    let name = name.as_bytes();
+    if name.starts_with(QUERY_PREFIX.as_bytes())
+    {
+        return false;
+    }
+    let protected = name.starts_with(METADATA_PREFIX.as_bytes())
+        || name == LABEL_XATTR.as_bytes()
+        || name == DEFAULT_LABEL_XATTR.as_bytes();
+    context.uid == 0 || !protected
+}
+
+/*
+ * Who is asking, as far as the permission bits of a file go. The groups are
+ * the gid of the request and the supplementary groups of the process.
+ */
+struct Caller
+{
+    uid:    u32,
+    groups: Vec<u32>,
+}
+
+impl Caller
+{
+    fn of(req: &Request) -> Self
+    {
+        let status = std::fs::read_to_string(format!("/proc/{}/status", req.pid()));
+        let mut groups: Vec<u32> = status
+            .ok()
+            .and_then(|status| {
+                let groups = status.lines().find_map(|line| line.strip_prefix("Groups:"))?;
+                Some(groups.split_ascii_whitespace().filter_map(|g| g.parse().ok()).collect())
+            })
+            .unwrap_or_default();
+        groups.push(req.gid());
+        Self {
+            uid: req.uid(),
+            groups,
+        }
+    }
+
+    fn root(&self) -> bool
+    {
+        self.uid == 0
+    }
+
+    fn owns(&self, meta: &std::fs::Metadata) -> bool
+    {
+        self.root() || self.uid == meta.uid()
+    }
+
+    fn may_write(&self, meta: &std::fs::Metadata) -> bool
+    {
+        let bit = match ()
+        {
+            _ if self.root() => return true,
+            _ if self.uid == meta.uid() => 0o200,
+            _ if self.groups.contains(&meta.gid()) => 0o020,
+            _ => 0o002,
+        };
+        meta.mode() & bit != 0
+    }
+}
+
+/*
+ * Whether `caller` may change the attributes of the file of `meta` as asked,
+ * by the rules of chown(2), chmod(2), truncate(2) and utimensat(2). The mount
+ * runs as root, so the kernel does not check them for it. Only root gives a
+ * file to another user, and the owner may give it to one of their groups.
+ * Only the owner changes the mode or sets a time, anyone who may write the
+ * file sets the times to now, or truncates it by path.
+ *
+ * Returns the mode to set, without setgid if the group is not the caller's.
+ */
+fn setattr_permitted(
+    caller: &Caller,
+    meta: &std::fs::Metadata,
+    mode: Option<u32>,
+    (uid, gid): (Option<u32>, Option<u32>),
+    truncate: bool,
+    (atime, mtime): (Option<TimeOrNow>, Option<TimeOrNow>),
+) -> Result<Option<u32>, c_int>
+{
+    if (uid.is_some() || gid.is_some()) && !caller.root()
+    {
+        let given_away = uid.is_some_and(|uid| uid != meta.uid());
+        let own_group = gid.is_none_or(|gid| gid == meta.gid() || caller.groups.contains(&gid));
+        if given_away || !caller.owns(meta) || !own_group
+        {
+            return Err(EPERM);
+        }
+    }
+
+    if mode.is_some() && !caller.owns(meta)
+    {
+        return Err(EPERM);
+    }
+    let group = gid.unwrap_or(meta.gid());
+    let mode = match mode
+    {
+        Some(mode) if !caller.root() && !caller.groups.contains(&group) =>
+        {
+            Some(mode & !libc::S_ISGID)
+        },
+        mode => mode,
+    };
+
+    if truncate && !caller.may_write(meta)
+    {
+        return Err(EACCES);
+    }
+
+    let set = |time: Option<TimeOrNow>| matches!(time, Some(TimeOrNow::SpecificTime(_)));
+    let now = |time: Option<TimeOrNow>| matches!(time, Some(TimeOrNow::Now));
+    if (set(atime) || set(mtime)) && !caller.owns(meta)
+    {
+        return Err(EPERM);
+    }
+    if (now(atime) || now(mtime)) && !caller.owns(meta) && !caller.may_write(meta)
+    {
+        return Err(EACCES);
+    }
+    Ok(mode)
+}
+
+// `flags` is `XATTR_CREATE` or `XATTR_REPLACE` to fail if the attribute is there, or not
+fn set_xattr(path: &Path, name: &OsStr, value: &[u8], flags: c_int) -> std::io::Result<()>
+{
+    let path = c_path(path)?;
+    let name =
+        CString::new(name.as_bytes()).map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e))?;
+    check(unsafe {
+        libc::lsetxattr(path.as_ptr(), name.as_ptr(), value.as_ptr().cast(), value.len(), flags)
+    })
+}
+
+fn errhandle(e: std::io::Error, not_found: impl FnOnce() -> ()) -> libc::c_int
+{
+    match e.kind()
+    {
+        ErrorKind::PermissionDenied => EPERM,
+        ErrorKind::NotFound =>
+        {
+            not_found();
+            ENOENT
+        },
+        e =>
+        {
+            error!("{:?}", e);
+            EIO
+        },
+    }
+}
+
+impl Filesystem for XmpFS
+{
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry)
+    {
+        self.reload_if_asked();
+        if !self.inodes.contains(parent)
+        {
+            return reply.error(ENOENT);
+        }
+
+        let parent_path = Path::new(&self.inodes[parent]);
+        let entry_path = parent_path.join(&name);
+
+        match std::fs::symlink_metadata(&entry_path)
+        {
+            Err(e) =>
+            {
+                reply.error(errhandle(e, || {
+                    // if not found:
+                    self.inodes.unlink(&entry_path);
+                }));
+            },
+            Ok(m) =>
+            {
+                let hidden = || !may_read(req, &entry_path, "lookup", &self.clearance(req));
+                if self.hide_unreadable && hidden()
+                {
+                    return reply.error(ENOENT);
+                }
+
+                let ino = self.inodes.lookup(&entry_path, &m);
+                let attr: FileAttr = meta2attr(&m, ino);
+
+                reply.entry(&TTL, &attr, 1);
+            },
+        }
+    }
+
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64)
+    {
+        self.inodes.forget(ino, nlookup);
+    }
+
+    fn batch_forget(&mut self, _req: &Request, nodes: &[fuser::fuse_forget_one])
+    {
+        for node in nodes
+        {
+            self.inodes.forget(node.nodeid, node.nlookup);
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr)
+    {
+        self.reload_if_asked();
+        //println!("getattr");
+        if !self.inodes.contains(ino)
+        {
+            return reply.error(ENOENT);
+        }
+
+
+        let entry_path = Path::new(&self.inodes[ino]);
+
+        match std::fs::symlink_metadata(entry_path)
+        {
+            Err(e) =>
+            {
+                reply.error(errhandle(e, || {
+                    // if not found:
+                    self.unregister_ino(ino);
+                }));
+            },
+            Ok(m) =>
+            {
+                let attr: FileAttr = meta2attr(&m, ino);
+                reply.attr(&TTL, &attr);
+            },
+        }
+    }
+
+    fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen)
+    {
+        self.reload_if_asked();
+        if !self.inodes.contains(ino)
+        {
+            return reply.error(ENOENT);
+        }
+
+        let entry_path = Path::new(&self.inodes[ino]).to_owned();
+
+        // Unreadable files are not there, and the others are opened by whoever asks
+        let compared = self.hide_unreadable;
+        if compared && !may_read(req, &entry_path, "open", &self.clearance(req))
+        {
+            return reply.error(ENOENT);
+        }
 
-        let entry_path = Path::new(&self.inode_to_path[&ino]);
         /*if !self.programs.contains_key(&_req.pid())
         {
             // We do not track the current process, either it is an attested program opening
@@ -559,36 +1087,7 @@ impl Filesystem for XmpFS
         //
 
 
-        //let t = std::time::Instant::now();
-        let mut path = entry_path.to_path_buf();
-        //println!("path {}", path.display());
-
-        while let Some(p) = self.pprograms.get(path.as_os_str())
-        {
-            //println!("while path {}", path.display());
-            match &p.m_source
-            {
-                MSource::Value(_) =>
-                {
-                    self.metadata_handler.changes(&path, Operation::Open);
-                    break;
-                },
-
-                MSource::Parent {
-                    own,
-                    parent_ids,
-                } =>
-                {
-                    path.clear();
-                    path.push(own);
-                    self.metadata_handler.changes(&path, Operation::Open);
-                    let id = *parent_ids;
-
-                    let parent_path = self.han.get_name(id);
-                    path.push(parent_path);
-                },
-            }
-        }
+        let context = Context::from_request(req);
 
 
         /*
@@ -663,27 +1162,53 @@ impl Filesystem for XmpFS
         oo.append(fl & O_APPEND == O_APPEND);
         oo.truncate(fl & O_TRUNC == O_TRUNC);
 
-        match oo.open(entry_path)
-        {
-            Err(e) => reply.error(errhandle(e, || self.unregister_ino(ino))),
-            Ok(f) =>
+        // What is read and written is tracked once the open went through
+        let reading = fl & O_ACCMODE != O_WRONLY;
+        let pid = self.program(req);
+        let written = (fl & O_ACCMODE != O_RDONLY).then(|| self.writing(req, &entry_path));
+
+        let fh = self.next_handle();
+
+        // The checks and the open itself can be slow, so they run on a worker
+        let (metadata, handles) = (self.metadata.clone(), Arc::clone(&self.opened_files));
+        let tracked = Arc::clone(&self.tracked);
+        self.pool.execute(move || {
+            // The metadata check is audited too, unless it only agrees with the labels
+            let checked = metadata.event(&entry_path, Operation::Open, &context);
+            if checked.is_err() || !compared
             {
-                let fh = self.counter;
-                self.counter += 1;
+                audit_check(&context, &entry_path, Operation::Open, checked.is_ok());
+            }
+            if let Err(err) = checked
+            {
+                return reply.error(err);
+            }
 
-                /*if self.derive.is_none()
+            match oo.open(&entry_path)
+            {
+                Err(e) => reply.error(errhandle(e, || ())),
+                Ok(f) =>
                 {
-                    self.derive = get_derived(&self, &_req);
-                }*/
-                self.opened_files.insert(fh, f);
-                reply.opened(fh, 0);
-            },
-        }
+                    let mut tracked = tracked.lock().expect("getting lock");
+                    if reading
+                    {
+                        tracked.read(pid, &entry_path);
+                    }
+                    if let Some(written) = written
+                    {
+                        tracked.written.insert(fh, written);
+                    }
+                    drop(tracked);
+                    handles.write().expect("getting lock").insert(fh, Arc::new(f));
+                    reply.opened(fh, 0);
+                },
+            }
+        });
     }
 
     fn create(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         mode: u32,
@@ -692,10 +1217,13 @@ impl Filesystem for XmpFS
         reply: ReplyCreate,
     )
     {
-        let parent_path = Path::new(&self.inode_to_path[&parent]);
-        let entry_path = parent_path.join(name);
+        if !self.inodes.contains(parent)
+        {
+            return reply.error(ENOENT);
+        }
 
-        let ino = self.add_or_create_inode(&entry_path);
+        let parent_path = Path::new(&self.inodes[parent]);
+        let entry_path = parent_path.join(name);
 
         let mut oo = std::fs::OpenOptions::new();
 
@@ -726,24 +1254,52 @@ impl Filesystem for XmpFS
         oo.truncate(fl & O_TRUNC == O_TRUNC);
         oo.mode(mode);
 
+        // Only what this made is removed again if it can not be labelled
+        let existed = entry_path.exists();
         match oo.open(&entry_path)
         {
-            Err(e) => return reply.error(errhandle(e, || self.unregister_ino(ino))),
+            Err(e) => return reply.error(errhandle(e, || ())),
             Ok(f) =>
             {
-                let meta = match std::fs::symlink_metadata(entry_path)
+                if !existed
                 {
-                    Err(e) =>
+                    if let Err(e) = give_to_caller(&entry_path, req)
                     {
-                        return reply.error(errhandle(e, || self.unregister_ino(ino)));
-                    },
-                    Ok(m) => meta2attr(&m, ino),
+                        unmake(&entry_path);
+                        return reply.error(errno(e));
+                    }
+                }
+
+                let m = match f.metadata()
+                {
+                    Err(e) => return reply.error(errhandle(e, || ())),
+                    Ok(m) => m,
                 };
-                let fh = self.counter;
-                self.counter += 1;
+
+                if let Err(e) = inherit_labels(&entry_path)
+                {
+                    error!("labelling {} failed: {}", entry_path.display(), e);
+                    if !existed
+                    {
+                        unmake(&entry_path);
+                    }
+                    return reply.error(errno(e));
+                }
+
+                let context = Context::from_request(req);
+                if let Err(err) = self.metadata.checked(&entry_path, Operation::Create, &context)
+                {
+                    if !existed
+                    {
+                        unmake(&entry_path);
+                    }
+                    return reply.error(err);
+                }
+
+                let meta = meta2attr(&m, self.inodes.lookup(&entry_path, &m));
+                let fh = self.next_handle();
 
                 //check_and_record_derive(self, _req);
-                //self.log_operation(ino, _req, "create");
 
                 /*if !self.pprograms.contains_key(&entry_path)
                 {
@@ -787,7 +1343,12 @@ impl Filesystem for XmpFS
 
                 set_lattice_of_new_file(name, &program).expect("setting label");*/
 
-                self.opened_files.insert(fh, f);
+                if fl & O_ACCMODE != O_RDONLY
+                {
+                    let written = self.writing(req, &entry_path);
+                    self.tracked.lock().expect("getting lock").written.insert(fh, written);
+                }
+                self.opened_files.write().expect("getting lock").insert(fh, Arc::new(f));
                 reply.created(&TTL, &meta, 1, fh, 0);
             },
         }
@@ -795,8 +1356,8 @@ impl Filesystem for XmpFS
 
     fn read(
         &mut self,
-        _req: &Request,
-        _ino: u64,
+        req: &Request,
+        ino: u64,
         fh: u64,
         offset: i64,
         size: u32,
@@ -805,58 +1366,52 @@ impl Filesystem for XmpFS
         reply: ReplyData,
     )
     {
-        if !self.opened_files.contains_key(&fh)
+        let file = match self.handle(fh)
         {
-            return reply.error(EIO);
-        }
-        let size = size as usize;
-
-
-
-
-        let mut b = Vec::with_capacity(size);
-        b.resize(size, 0);
-
-        use std::os::unix::fs::FileExt;
-        let file = self.opened_files.get(&fh).unwrap();
+            Some(file) => file,
+            None => return reply.error(EIO),
+        };
 
+        let entry_path = self.inodes.path(ino).map(PathBuf::from);
+        let context = Context {
+            offset: Some(offset),
+            size: Some(size as u64),
+            ..Context::from_request(req)
+        };
+        let metadata = self.metadata.clone();
 
-        let file_size = file.metadata().unwrap().len();
-        // Could underflow if file length is less than local_start
-        let read_size = std::cmp::min(size, file_size.saturating_sub(offset as u64) as usize);
+        self.pool.execute(move || {
+            if let Some(entry_path) = entry_path
+            {
+                if let Err(err) = metadata.checked(&entry_path, Operation::Read, &context)
+                {
+                    return reply.error(err);
+                }
+            }
 
-        let mut buffer = vec![0; read_size as usize];
-        file.read_exact_at(&mut buffer, offset as u64).unwrap();
-        reply.data(&buffer);
+            use std::os::unix::fs::FileExt;
 
-        /*let mut bo = 0;
-        while bo < size
-        {
-            match f.read_at(&mut b[bo..], offset as u64)
+            // pread on the shared handle, so reads of one file do not wait on each other
+            let mut buffer = vec![0; size as usize];
+            let mut read = 0;
+            while read < buffer.len()
             {
-                Err(e) =>
-                {
-                    return reply.error(errhandle(e, || ()));
-                },
-                Ok(0) =>
+                match file.read_at(&mut buffer[read..], offset as u64 + read as u64)
                 {
-                    b.resize(bo, 0);
-                    break;
-                },
-                Ok(ret) =>
-                {
-                    bo += ret;
-                },
-            };
-        }
-
-        reply.data(&b[..]);*/
+                    Ok(0) => break,
+                    Ok(n) => read += n,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => return reply.error(errhandle(e, || ())),
+                }
+            }
+            reply.data(&buffer[..read]);
+        });
     }
 
     fn write(
         &mut self,
-        _req: &Request,
-        _ino: u64,
+        req: &Request,
+        ino: u64,
         fh: u64,
         offset: i64,
         data: &[u8],
@@ -866,63 +1421,55 @@ impl Filesystem for XmpFS
         reply: ReplyWrite,
     )
     {
-        if !self.opened_files.contains_key(&fh)
+        let file = match self.handle(fh)
         {
-            return reply.error(EIO);
-        }
-
-        let f = self.opened_files.get_mut(&fh).unwrap();
-
-        use std::os::unix::fs::FileExt;
-        let entry_path = self.inode_to_path.get(&_ino).unwrap();
+            Some(file) => file,
+            None => return reply.error(EIO),
+        };
 
+        let entry_path = self.inodes.path(ino).map(PathBuf::from);
+        let context = Context {
+            offset: Some(offset),
+            size: Some(data.len() as u64),
+            ..Context::from_request(req)
+        };
+        let metadata = self.metadata.clone();
+        let data = data.to_vec();
 
-        match f.write_all_at(data, offset as u64)
-        {
-            Err(e) => return reply.error(errhandle(e, || ())),
-            Ok(()) =>
+        self.pool.execute(move || {
+            if let Some(entry_path) = entry_path
             {
-                /*if let Some(program) = self.programs.get(&_req.pid())
+                if let Err(err) = metadata.checked(&entry_path, Operation::Write, &context)
                 {
-                    self.derive_data(_req, entry_path, program);
+                    return reply.error(err);
                 }
-                else
-                {
-                    if let Some(ppid) = get_parent_process(_req.pid())
-                    {
-                        let new_program =
-                            self.programs.get(&ppid).expect("no parent found").clone();
-                        self.derive_data(_req, entry_path, &new_program);
-                        self.programs.insert(ppid, new_program);
-                    }
-                    else
-                    {
-                        panic!("No parent, no process");
-                    }
-                }*/
+            }
 
-                reply.written(data.len() as u32);
-            },
-        };
+            use std::os::unix::fs::FileExt;
+
+            match file.write_all_at(&data, offset as u64)
+            {
+                Err(e) => reply.error(errhandle(e, || ())),
+                Ok(()) => reply.written(data.len() as u32),
+            }
+        });
     }
 
     fn fsync(&mut self, _req: &Request, _ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty)
     {
-        if !self.opened_files.contains_key(&fh)
+        let f = match self.handle(fh)
         {
-            return reply.error(EIO);
-        }
-
-        let f = self.opened_files.get_mut(&fh).unwrap();
+            Some(f) => f,
+            None => return reply.error(EIO),
+        };
 
-        match if datasync { f.sync_data() } else { f.sync_all() }
-        {
-            Err(e) => return reply.error(errhandle(e, || ())),
-            Ok(()) =>
+        self.pool.execute(move || {
+            match if datasync { f.sync_data() } else { f.sync_all() }
             {
-                reply.ok();
-            },
-        }
+                Err(e) => reply.error(errhandle(e, || ())),
+                Ok(()) => reply.ok(),
+            }
+        });
     }
 
     fn fsyncdir(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty)
@@ -933,8 +1480,8 @@ impl Filesystem for XmpFS
 
     fn release(
         &mut self,
-        _req: &Request,
-        _ino: u64,
+        req: &Request,
+        ino: u64,
         fh: u64,
         _flags: i32,
         _lock_owner: Option<u64>,
@@ -942,14 +1489,17 @@ impl Filesystem for XmpFS
         reply: ReplyEmpty,
     )
     {
-        if !self.opened_files.contains_key(&fh)
+        if self.opened_files.write().expect("getting lock").remove(&fh).is_none()
         {
             return reply.error(EIO);
         }
 
+        // What was written is derived from what it was written from
+        self.derive_written(fh);
+
 
         // remove dependencies
-        /*let entry_path = Path::new(&self.inode_to_path[&_ino]);
+        /*let entry_path = Path::new(&self.inodes[_ino]);
         let name = entry_path.file_name().unwrap().to_os_string();
         let mut buf = std::path::PathBuf::new();
         buf.push(&*BASE_PATH);
@@ -971,19 +1521,33 @@ impl Filesystem for XmpFS
 
 
 
-        self.opened_files.remove(&fh);
-        reply.ok();
+        // Workers still reading or writing keep their own reference to the file
+        let entry_path = self.inodes.path(ino).map(PathBuf::from);
+        let context = Context::from_request(req);
+        let metadata = self.metadata.clone();
+
+        self.pool.execute(move || {
+            if let Some(entry_path) = entry_path
+            {
+                if let Err(err) = metadata.event(&entry_path, Operation::Release, &context)
+                {
+                    return reply.error(err);
+                }
+            }
+            reply.ok();
+        });
     }
 
-    fn opendir(&mut self, _req: &Request, ino: u64, _flags: i32, reply: ReplyOpen)
+    fn opendir(&mut self, req: &Request, ino: u64, _flags: i32, reply: ReplyOpen)
     {
+        self.reload_if_asked();
         //println!("opendir");
-        if !self.inode_to_path.contains_key(&ino)
+        if !self.inodes.contains(ino)
         {
             return reply.error(ENOENT);
         }
 
-        let entry_path = Path::new(&self.inode_to_path[&ino]).to_owned();
+        let entry_path = Path::new(&self.inodes[ino]).to_owned();
         //println!("release {:?}", entry_path);
 
         match std::fs::read_dir(&entry_path)
@@ -995,6 +1559,8 @@ impl Filesystem for XmpFS
             Ok(x) =>
             {
                 let mut v: Vec<DirInfo> = Vec::with_capacity(x.size_hint().0);
+                let clearance = self.hide_unreadable.then(|| self.clearance(req));
+                let mut hidden = Vec::new();
 
                 let parent_ino: u64 = if ino == 1
                 {
@@ -1005,7 +1571,7 @@ impl Filesystem for XmpFS
                     match entry_path.parent()
                     {
                         None => ino,
-                        Some(x) => *self.path_to_inode.get(x.as_os_str()).unwrap_or(&ino),
+                        Some(x) => self.inodes.get(x).unwrap_or(ino),
                     }
                 };
 
@@ -1032,10 +1598,19 @@ impl Filesystem for XmpFS
                         Ok(de) =>
                         {
                             let name = de.file_name().to_os_string();
+                            if let Some(clearance) = &clearance
+                            {
+                                let (allowed, label) = readable(&de.path(), clearance);
+                                if !allowed
+                                {
+                                    hidden.push((de.path(), label));
+                                    continue;
+                                }
+                            }
 
                             let kind = de.file_type().map(ft2ft).unwrap_or(FileType::RegularFile);
-                            let jp = entry_path.join(&name);
-                            let ino = self.add_or_create_inode(jp);
+                            // Listing is not a lookup, unknown files show their backing inode
+                            let ino = self.inodes.get(entry_path.join(&name)).unwrap_or(de.ino());
                             v.push(DirInfo {
                                 ino,
                                 kind,
@@ -1044,9 +1619,24 @@ impl Filesystem for XmpFS
                         },
                     }
                 }
-                let fh = self.counter;
+                // Every entry left out is audited once, off the session thread
+                if let Some(clearance) = clearance.filter(|_| !hidden.is_empty())
+                {
+                    let (pid, uid) = (req.pid(), req.uid());
+                    self.pool.execute(move || {
+                        let program = audit::program_of(pid);
+                        let clearance = format!("{} {}", clearance.0, clearance.1);
+                        for (path, label) in hidden
+                        {
+                            let decision =
+                                Decision::by(program.clone(), pid, uid, &path, "readdir", false);
+                            audit::record(decision.compared(&clearance, label));
+                        }
+                    });
+                }
+
+                let fh = self.next_handle();
                 self.opened_directories.insert(fh, v);
-                self.counter += 1;
                 reply.opened(fh, 0);
             },
         }
@@ -1095,18 +1685,24 @@ impl Filesystem for XmpFS
     fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData)
     {
         //println!("readlink");
-        if !self.inode_to_path.contains_key(&ino)
+        if !self.inodes.contains(ino)
         {
             return reply.error(ENOENT);
         }
 
-        let entry_path = Path::new(&self.inode_to_path[&ino]);
+        let entry_path = Path::new(&self.inodes[ino]);
 
         match std::fs::read_link(entry_path)
         {
             Err(e) => reply.error(errhandle(e, || self.unregister_ino(ino))),
             Ok(x) =>
             {
+                // Following a link into the backing folder would get around the mount
+                let x = match &self.mountpoint
+                {
+                    Some(mountpoint) => rebase(&x, Path::new(&*BASE_PATH), mountpoint).unwrap_or(x),
+                    None => x,
+                };
                 reply.data(x.as_os_str().as_bytes());
             },
         }
@@ -1114,7 +1710,7 @@ impl Filesystem for XmpFS
 
     fn mkdir(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         _mode: u32,
@@ -1123,49 +1719,73 @@ impl Filesystem for XmpFS
     )
     {
         //println!("mkdir");
-        if !self.inode_to_path.contains_key(&parent)
+        if !self.inodes.contains(parent)
         {
             return reply.error(ENOENT);
         }
 
-        let parent_path = Path::new(&self.inode_to_path[&parent]);
+        let parent_path = Path::new(&self.inodes[parent]);
         let entry_path = parent_path.join(name);
 
-        let ino = self.add_or_create_inode(&entry_path);
         match std::fs::create_dir(&entry_path)
         {
             Err(e) => reply.error(errhandle(e, || ())),
             Ok(()) =>
             {
-                let attr = match std::fs::symlink_metadata(entry_path)
+                if let Err(e) = give_to_caller(&entry_path, req)
                 {
-                    Err(e) =>
-                    {
-                        return reply.error(errhandle(e, || self.unregister_ino(ino)));
-                    },
-                    Ok(m) => meta2attr(&m, ino),
+                    unmake_dir(&entry_path);
+                    return reply.error(errno(e));
+                }
+
+                let m = match std::fs::symlink_metadata(&entry_path)
+                {
+                    Err(e) => return reply.error(errhandle(e, || ())),
+                    Ok(m) => m,
                 };
 
+                if let Err(e) = inherit_labels(&entry_path)
+                {
+                    error!("labelling {} failed: {}", entry_path.display(), e);
+                    unmake_dir(&entry_path);
+                    return reply.error(errno(e));
+                }
+
+                let context = Context::from_request(req);
+                if let Err(err) = self.metadata_event(&entry_path, Operation::Mkdir, &context)
+                {
+                    unmake_dir(&entry_path);
+                    return reply.error(err);
+                }
+
+                let attr = meta2attr(&m, self.inodes.lookup(&entry_path, &m));
                 reply.entry(&TTL, &attr, 1);
             },
         }
     }
 
-    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty)
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty)
     {
-        if !self.inode_to_path.contains_key(&parent)
+        if !self.inodes.contains(parent)
         {
             return reply.error(ENOENT);
         }
 
-        let parent_path = Path::new(&self.inode_to_path[&parent]);
+        let parent_path = Path::new(&self.inodes[parent]);
         let entry_path = parent_path.join(name);
 
+        let context = Context::from_request(req);
+        if let Err(err) = self.metadata.checked(&entry_path, Operation::Unlink, &context)
+        {
+            return reply.error(err);
+        }
+
         match std::fs::remove_file(&entry_path)
         {
             Err(e) => reply.error(errhandle(e, || ())),
             Ok(()) =>
             {
+                self.inodes.unlink(&entry_path);
                 let mut table = TABLE!(self.table);
                 if table.delete(entry_path).is_ok()
                 {
@@ -1176,61 +1796,93 @@ impl Filesystem for XmpFS
         }
     }
 
-    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty)
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty)
     {
         //println!("rmdir");
-        if !self.inode_to_path.contains_key(&parent)
+        if !self.inodes.contains(parent)
         {
             return reply.error(ENOENT);
         }
 
-        let parent_path = Path::new(&self.inode_to_path[&parent]);
+        let parent_path = Path::new(&self.inodes[parent]);
         let entry_path = parent_path.join(name);
 
-        match std::fs::remove_dir(entry_path)
+        let context = Context::from_request(req);
+        if let Err(err) = self.metadata_event(&entry_path, Operation::Rmdir, &context)
+        {
+            return reply.error(err);
+        }
+
+        match std::fs::remove_dir(&entry_path)
         {
             Err(e) => reply.error(errhandle(e, || ())),
             Ok(()) =>
             {
+                self.inodes.unlink(&entry_path);
                 reply.ok();
             },
         }
     }
 
-    fn symlink(&mut self, _req: &Request, parent: u64, name: &OsStr, link: &Path, reply: ReplyEntry)
+    fn symlink(&mut self, req: &Request, parent: u64, name: &OsStr, link: &Path, reply: ReplyEntry)
     {
-        //println!("symlink");
-        if !self.inode_to_path.contains_key(&parent)
+        if !self.inodes.contains(parent)
         {
             return reply.error(ENOENT);
         }
 
-        let parent_path = Path::new(&self.inode_to_path[&parent]);
+        let parent_path = Path::new(&self.inodes[parent]).to_owned();
         let entry_path = parent_path.join(name);
-        let ino = self.add_or_create_inode(&entry_path);
 
-        match std::os::unix::fs::symlink(&entry_path, link)
+        if let Err(e) = std::os::unix::fs::symlink(link, &entry_path)
         {
-            Err(e) => reply.error(errhandle(e, || self.unregister_ino(ino))),
-            Ok(()) =>
+            return reply.error(errhandle(e, || ()));
+        }
+        if let Err(e) = give_to_caller(&entry_path, req)
+        {
+            unmake(&entry_path);
+            return reply.error(errno(e));
+        }
+        let m = match std::fs::symlink_metadata(&entry_path)
+        {
+            Err(e) => return reply.error(errhandle(e, || ())),
+            Ok(m) => m,
+        };
+
+        // The link is derived from what it points to, so it is checked like it
+        let target = self.link_target(&parent_path, link);
+        if let Some(target) = &target
+        {
+            let mut table = TABLE!(self.table);
+            if table.derive(&entry_path, target).is_ok()
             {
-                let attr = match std::fs::symlink_metadata(entry_path)
+                if let Err(e) = table.flush()
                 {
-                    Err(e) =>
-                    {
-                        return reply.error(errhandle(e, || self.unregister_ino(ino)));
-                    },
-                    Ok(m) => meta2attr(&m, ino),
-                };
+                    error!("saving the table failed: {}", e);
+                    drop(table);
+                    unmake(&entry_path);
+                    return reply.error(errno(e));
+                }
+            }
+        }
 
-                reply.entry(&TTL, &attr, 1);
-            },
+        let context = Context {
+            old_path: target,
+            ..Context::from_request(req)
+        };
+        if let Err(err) = self.metadata_event(&entry_path, Operation::Symlink, &context)
+        {
+            unmake(&entry_path);
+            return reply.error(err);
         }
+
+        let attr = meta2attr(&m, self.inodes.lookup(&entry_path, &m));
+        reply.entry(&TTL, &attr, 1);
     }
 
     fn rename(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         newparent: u64,
@@ -1239,18 +1891,17 @@ impl Filesystem for XmpFS
         reply: ReplyEmpty,
     )
     {
-        println!("rename");
-        if !self.inode_to_path.contains_key(&parent)
+        if !self.inodes.contains(parent)
         {
             return reply.error(ENOENT);
         }
-        if !self.inode_to_path.contains_key(&newparent)
+        if !self.inodes.contains(newparent)
         {
             return reply.error(ENOENT);
         }
 
-        let parent_path = Path::new(&self.inode_to_path[&parent]);
-        let newparent_path = Path::new(&self.inode_to_path[&newparent]);
+        let parent_path = Path::new(&self.inodes[parent]);
+        let newparent_path = Path::new(&self.inodes[newparent]);
         let entry_path = parent_path.join(name);
         let newentry_path = newparent_path.join(newname);
 
@@ -1259,24 +1910,41 @@ impl Filesystem for XmpFS
             return reply.ok();
         }
 
-        let ino = self.add_or_create_inode(&entry_path);
+        let context = Context {
+            old_path: Some(entry_path.clone()),
+            new_path: Some(newentry_path.clone()),
+            ..Context::from_request(req)
+        };
+        if let Err(err) = self.metadata_event(&entry_path, Operation::Rename, &context)
+        {
+            return reply.error(err);
+        }
+
+        // Moved into a more public folder, the file keeps the label it had
+        if let Err(e) = keep_bound(&entry_path, &newentry_path)
+        {
+            return reply.error(errno(e));
+        }
 
         match std::fs::rename(&entry_path, &newentry_path)
         {
-            Err(e) => reply.error(errhandle(e, || self.unregister_ino(ino))),
+            Err(e) => reply.error(errhandle(e, || self.inodes.unlink(&entry_path))),
             Ok(()) =>
             {
-                self.inode_to_path.insert(ino, newentry_path.as_os_str().to_os_string());
-                self.path_to_inode.remove(entry_path.as_os_str());
-                self.path_to_inode.insert(newentry_path.as_os_str().to_os_string(), ino);
-
-
+                self.inodes.rename(&entry_path, &newentry_path);
 
+                // The lineage follows the file
                 let mut table = TABLE!(self.table);
-                if table.contains(&entry_path)
+                let renamed = match table.rename_all(&newentry_path, &entry_path)
+                {
+                    Ok(0) => Ok(()),
+                    Ok(_) => table.flush(),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = renamed
                 {
-                    table.rename(&newentry_path, &entry_path).expect("renaming");
-                    table.flush().expect("flushing rename");
+                    error!("renaming {} in the table failed: {}", entry_path.display(), e);
+                    return reply.error(errno(e));
                 }
 
                 reply.ok();
@@ -1284,40 +1952,61 @@ impl Filesystem for XmpFS
         }
     }
 
-    fn link(&mut self, _req: &Request, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry)
+    fn link(&mut self, req: &Request, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry)
     {
-        // Not a true hardlink: new inode
         //println!("link");
 
-        if !self.inode_to_path.contains_key(&ino)
+        if !self.inodes.contains(ino)
         {
             return reply.error(ENOENT);
         }
-        if !self.inode_to_path.contains_key(&newparent)
+        if !self.inodes.contains(newparent)
         {
             return reply.error(ENOENT);
         }
 
-        let entry_path = Path::new(&self.inode_to_path[&ino]).to_owned();
-        let newparent_path = Path::new(&self.inode_to_path[&newparent]);
+        let entry_path = Path::new(&self.inodes[ino]).to_owned();
+        let newparent_path = Path::new(&self.inodes[newparent]);
         let newentry_path = newparent_path.join(newname);
 
-        let newino = self.add_or_create_inode(&newentry_path);
+        // Checked before anything is linked, so a refusal leaves nothing to undo
+        let context = Context {
+            old_path: Some(entry_path.clone()),
+            new_path: Some(newentry_path.clone()),
+            ..Context::from_request(req)
+        };
+        if let Err(err) = self.metadata_event(&entry_path, Operation::Link, &context)
+        {
+            return reply.error(err);
+        }
 
         match std::fs::hard_link(&entry_path, &newentry_path)
         {
             Err(e) => reply.error(errhandle(e, || self.unregister_ino(ino))),
             Ok(()) =>
             {
-                let attr = match std::fs::symlink_metadata(newentry_path)
+                let m = match std::fs::symlink_metadata(&newentry_path)
                 {
-                    Err(e) =>
-                    {
-                        return reply.error(errhandle(e, || self.unregister_ino(newino)));
-                    },
-                    Ok(m) => meta2attr(&m, newino),
+                    Err(e) => return reply.error(errhandle(e, || ())),
+                    Ok(m) => m,
                 };
 
+                // The new name is derived from the file, so it is checked like it
+                if labelled(&entry_path)
+                {
+                    let mut table = TABLE!(self.table);
+                    let derived = table.derive(&newentry_path, &entry_path);
+                    if let Err(e) = derived.and_then(|()| table.flush())
+                    {
+                        error!("deriving {} failed: {}", newentry_path.display(), e);
+                        drop(table);
+                        unmake(&newentry_path);
+                        return reply.error(errno(e));
+                    }
+                }
+
+                // The new name shares the inode of the file it links to
+                let attr = meta2attr(&m, self.inodes.lookup(&newentry_path, &m));
                 reply.entry(&TTL, &attr, 1);
             },
         }
@@ -1325,30 +2014,77 @@ impl Filesystem for XmpFS
 
     fn mknod(
         &mut self,
-        _req: &Request,
-        _parent: u64,
-        _name: &OsStr,
-        _mode: u32,
-        _umask: u32,
-        _rdev: u32,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
         reply: ReplyEntry,
     )
     {
-        //println!("mknod");
-        // no mknod lib libstd
-        reply.error(ENOSYS);
+        if !self.inodes.contains(parent)
+        {
+            return reply.error(ENOENT);
+        }
+
+        // Only root makes devices, the mount runs as root for everyone
+        let kind = mode & libc::S_IFMT;
+        if (kind == libc::S_IFBLK || kind == libc::S_IFCHR) && req.uid() != 0
+        {
+            return reply.error(EPERM);
+        }
+
+        let entry_path = Path::new(&self.inodes[parent]).join(name);
+        let perm = mode & !umask & 0o7777;
+
+        if let Err(e) = make_node(&entry_path, kind | perm, rdev)
+        {
+            return reply.error(errno(e));
+        }
+        // The mount has a umask of its own, so the mode is set after the owner
+        let owned = give_to_caller(&entry_path, req).and_then(|()| {
+            std::fs::set_permissions(&entry_path, std::fs::Permissions::from_mode(perm))
+        });
+        if let Err(e) = owned
+        {
+            unmake(&entry_path);
+            return reply.error(errno(e));
+        }
+        let m = match std::fs::symlink_metadata(&entry_path)
+        {
+            Err(e) => return reply.error(errhandle(e, || ())),
+            Ok(m) => m,
+        };
+
+        if let Err(e) = inherit_labels(&entry_path)
+        {
+            error!("labelling {} failed: {}", entry_path.display(), e);
+            unmake(&entry_path);
+            return reply.error(errno(e));
+        }
+
+        let context = Context::from_request(req);
+        if let Err(err) = self.metadata.checked(&entry_path, Operation::Create, &context)
+        {
+            unmake(&entry_path);
+            return reply.error(err);
+        }
+
+        let attr = meta2attr(&m, self.inodes.lookup(&entry_path, &m));
+        reply.entry(&TTL, &attr, 1);
     }
 
     fn setattr(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
         mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
         size: Option<u64>,
-        _atime: Option<TimeOrNow>,
-        _mtime: Option<TimeOrNow>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
         _ctime: Option<SystemTime>,
         fh: Option<u64>,
         _crtime: Option<SystemTime>,
@@ -1358,163 +2094,524 @@ impl Filesystem for XmpFS
         reply: ReplyAttr,
     )
     {
-        // Limited to setting file length only
-        //println!("setattr");
-
-        let (fh, sz) = match (fh, size)
+        let entry_path = match self.inodes.path(ino)
         {
-            (Some(x), Some(y)) => (x, y),
-            _ =>
-            {
-                // only partial for chmod +x, and not the good one
-
-                let entry_path = Path::new(&self.inode_to_path[&ino]).to_owned();
-
-                if let Some(mode) = mode
-                {
-                    use std::fs::Permissions;
-
-                    let perm = Permissions::from_mode(mode);
-                    match std::fs::set_permissions(&entry_path, perm)
-                    {
-                        Err(e) => return reply.error(errhandle(e, || self.unregister_ino(ino))),
-                        Ok(()) =>
-                        {
-                            let attr = match std::fs::symlink_metadata(entry_path)
-                            {
-                                Err(e) =>
-                                {
-                                    return reply.error(errhandle(e, || self.unregister_ino(ino)));
-                                },
-                                Ok(m) => meta2attr(&m, ino),
-                            };
-
-                            return reply.attr(&TTL, &attr);
-                        },
-                    }
-                }
-                else
-                {
-                    // Just try to do nothing, successfully.
-                    let attr = match std::fs::symlink_metadata(entry_path)
-                    {
-                        Err(e) =>
-                        {
-                            return reply.error(errhandle(e, || self.unregister_ino(ino)));
-                        },
-                        Ok(m) => meta2attr(&m, ino),
-                    };
+            Some(path) => PathBuf::from(path),
+            None => return reply.error(ENOENT),
+        };
 
-                    return reply.attr(&TTL, &attr);
-                }
-            },
+        let meta = match std::fs::symlink_metadata(&entry_path)
+        {
+            Ok(meta) => meta,
+            Err(e) => return reply.error(errhandle(e, || self.unregister_ino(ino))),
+        };
+        let by_path = size.is_some() && fh.is_none();
+        let (owner, times) = ((uid, gid), (atime, mtime));
+        let mode = match setattr_permitted(&Caller::of(req), &meta, mode, owner, by_path, times)
+        {
+            Ok(mode) => mode,
+            Err(err) => return reply.error(err),
         };
 
-        if !self.opened_files.contains_key(&fh)
+        // Every change is its own event, checked before anything is changed
+        let context = Context::from_request(req);
+        let mut events = Vec::new();
+        if uid.is_some() || gid.is_some()
         {
-            return reply.error(EIO);
+            events.push((Operation::Chown, context.clone()));
+        }
+        if mode.is_some()
+        {
+            events.push((Operation::Chmod, context.clone()));
+        }
+        if size.is_some()
+        {
+            events.push((Operation::Truncate, Context {
+                size,
+                ..context.clone()
+            }));
+        }
+        if atime.is_some() || mtime.is_some()
+        {
+            events.push((Operation::Utimens, context));
+        }
+        for (operation, context) in &events
+        {
+            if let Err(err) = self.metadata_event(&entry_path, *operation, context)
+            {
+                return reply.error(err);
+            }
         }
 
-        let f = self.opened_files.get_mut(&fh).unwrap();
-
-        match f.set_len(sz)
+        // The owner first, as a chown clears the setuid and setgid bits
+        if uid.is_some() || gid.is_some()
         {
-            Err(e) => reply.error(errhandle(e, || ())),
-            Ok(()) =>
+            if let Err(e) = lchown(&entry_path, uid, gid)
             {
-                // pull regular file metadata out of thin air
-
-                let attr = FileAttr {
-                    ino,
-                    size: sz,
-                    blocks: 1,
-                    atime: UNIX_EPOCH,
-                    mtime: UNIX_EPOCH,
-                    ctime: UNIX_EPOCH,
-                    crtime: UNIX_EPOCH,
-                    kind: FileType::RegularFile,
-                    perm: 0o644,
-                    nlink: 1,
-                    uid: 0,
-                    gid: 0,
-                    rdev: 0,
-                    flags: 0,
-                    blksize: BLOCK_SIZE,
-                };
+                return reply.error(errhandle(e, || self.unregister_ino(ino)));
+            }
+        }
+        if let Some(mode) = mode
+        {
+            let perm = std::fs::Permissions::from_mode(mode);
+            if let Err(e) = std::fs::set_permissions(&entry_path, perm)
+            {
+                return reply.error(errhandle(e, || self.unregister_ino(ino)));
+            }
+        }
+        if let Some(size) = size
+        {
+            // Through the open handle if there is one, the file may not be writable by path
+            let result = match fh.and_then(|fh| self.handle(fh))
+            {
+                Some(f) => f.set_len(size),
+                None => truncate(&entry_path, size),
+            };
+            if let Err(e) = result
+            {
+                return reply.error(errhandle(e, || self.unregister_ino(ino)));
+            }
+        }
+        if atime.is_some() || mtime.is_some()
+        {
+            if let Err(e) = utimens(&entry_path, atime, mtime)
+            {
+                return reply.error(errhandle(e, || self.unregister_ino(ino)));
+            }
+        }
 
-                reply.attr(&TTL, &attr);
-            },
+        match std::fs::symlink_metadata(&entry_path)
+        {
+            Err(e) => reply.error(errhandle(e, || self.unregister_ino(ino))),
+            Ok(m) => reply.attr(&TTL, &meta2attr(&m, ino)),
         }
     }
 
     fn setxattr(
         &mut self,
-        _req: &Request,
-        _ino: u64,
+        req: &Request,
+        ino: u64,
         name: &OsStr,
-        _value: &[u8],
-        _flags: i32,
+        value: &[u8],
+        flags: i32,
         _position: u32,
         reply: ReplyEmpty,
     )
     {
-        //println!("setxattr");
-        let path = match self.inode_to_path.get(&_ino)
+        let path = match self.inodes.path(ino)
         {
-            Some(f) => f,
-            _ =>
+            Some(f) => PathBuf::from(f),
+            None => return reply.error(ENOENT),
+        };
+
+        let context = Context::from_request(req);
+        if !xattr_writable(name, &context)
+        {
+            audit::record(Decision::new(req.pid(), req.uid(), &path, "setxattr", false));
+            return reply.error(EPERM);
+        }
+
+        // A file can not be made more public than its folders
+        let label = name == LABEL_XATTR;
+        if label
+        {
+            let value = String::from_utf8_lossy(value);
+            let allowed = within_bound(&path, &value);
+            let mut decision = Decision::new(req.pid(), req.uid(), &path, "relabel", allowed);
+            decision.label = Some(value.into_owned());
+            audit::record(decision);
+            if !allowed
             {
-                return reply.error(2);
+                return reply.error(EPERM);
+            }
+        }
+
+        match set_xattr(&path, name, value, flags)
+        {
+            Ok(()) if label =>
+            {
+                let mut table = TABLE!(self.table);
+                if table.relabel(&path).is_ok()
+                {
+                    if let Err(e) = table.flush()
+                    {
+                        error!("saving the relabel of {} failed: {}", path.display(), e);
+                        return reply.error(errno(e));
+                    }
+                }
+                drop(table);
+                match self.metadata_event(&path, Operation::Relabel, &context)
+                {
+                    Ok(()) => reply.ok(),
+                    Err(err) => reply.error(err),
+                }
             },
+            Ok(()) => match self.metadata_event(&path, Operation::SetXattr, &context)
+            {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err),
+            },
+            Err(e) => reply.error(errno(e)),
+        }
+    }
+
+    fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr)
+    {
+        let path = match self.inodes.path(ino)
+        {
+            Some(f) => PathBuf::from(f),
+            None => return reply.error(ENOENT),
         };
 
-        match xattr::set(path, name, _value)
+        let context = Context::from_request(req);
+
+        // The current metadata of the file, see `MetadataHandler::query`
+        if let Some(field) = name.to_str().and_then(|name| name.strip_prefix(QUERY_PREFIX))
+        {
+            // The query runs the access programs, which can be slow, so it runs on a worker
+            let (handler, field) = (self.metadata.handler(), field.to_string());
+            return self.pool.execute(move || match handler.query(&path, &context)
+            {
+                Ok(values) => match values.get(&field)
+                {
+                    Some(value) => reply_xattr(value.as_bytes(), size, reply),
+                    None => reply.error(ENODATA),
+                },
+                Err(e) =>
+                {
+                    error!("metadata query for {} failed: {}", path.display(), e);
+                    reply.error(EIO)
+                },
+            });
+        }
+
+        if !xattr_visible(name, &context)
         {
-            Ok(_) => reply.ok(),
-            Err(_) => reply.error(2),
+            return reply.error(ENODATA);
+        }
+
+        match xattr::get(&path, name)
+        {
+            Ok(Some(data)) => reply_xattr(&data, size, reply),
+            Ok(None) => reply.error(ENODATA),
+            Err(e) => reply.error(errno(e)),
         }
     }
 
-    // getxattr is a bit scuffed atm, look into this later..
-    fn getxattr(&mut self, _req: &Request, _ino: u64, _name: &OsStr, size: u32, reply: ReplyXattr)
+    // The stored xattrs the caller may see, then one `gurret.meta.<field>` per metadata field
+    fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr)
     {
-        //println!("getxattr");
-        let path = match self.inode_to_path.get(&_ino)
+        let path = match self.inodes.path(ino)
         {
-            Some(f) => f,
-            _ =>
-            {
-                return reply.error(2);
-            },
+            Some(f) => PathBuf::from(f),
+            None => return reply.error(ENOENT),
         };
 
-        match xattr::get(path, _name)
+        let context = Context::from_request(req);
+        let mut names = Vec::new();
+        match xattr::list(&path)
         {
-            Ok(Some(data)) =>
+            Ok(list) =>
             {
-                if size == 0
-                {
-                    reply.size(data.len() as u32);
-                }
-                else if data.len() <= size as usize
+                for name in list.filter(|name| xattr_visible(name, &context))
                 {
-                    reply.data(&data);
+                    names.extend_from_slice(name.as_bytes());
+                    names.push(0);
                 }
-                else
+            },
+            Err(e) => return reply.error(errno(e)),
+        }
+
+        // The fields come from the query, run on a worker like in getxattr
+        let handler = self.metadata.handler();
+        self.pool.execute(move || {
+            match handler.query(&path, &context)
+            {
+                Ok(values) =>
                 {
-                    reply.error(libc::ERANGE);
-                }
+                    for field in values.keys()
+                    {
+                        names.extend_from_slice(QUERY_PREFIX.as_bytes());
+                        names.extend_from_slice(field.as_bytes());
+                        names.push(0);
+                    }
+                },
+                Err(e) => error!("metadata query for {} failed: {}", path.display(), e),
+            }
+
+            reply_xattr(&names, size, reply);
+        });
+    }
+
+    fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty)
+    {
+        let path = match self.inodes.path(ino)
+        {
+            Some(f) => PathBuf::from(f),
+            None => return reply.error(ENOENT),
+        };
+
+        let context = Context::from_request(req);
+        if !xattr_writable(name, &context)
+        {
+            audit::record(Decision::new(req.pid(), req.uid(), &path, "removexattr", false));
+            return reply.error(EPERM);
+        }
+
+        match xattr::remove(&path, name)
+        {
+            Ok(()) => match self.metadata_event(&path, Operation::RemoveXattr, &context)
+            {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err),
             },
-            _ => reply.error(2),
+            Err(e) => reply.error(errno(e)),
         }
-        /*let foo = xattr::get(path, _name).unwrap().unwrap();
-        return reply.size(foo.len() as u32);*/
-        // look into this !!!
-        //reply.size(0);
     }
 
+    /*
+     * The mount is going down. Finish what the workers have, derive the files
+     * still open for writing, and save the table.
+     */
     fn destroy(&mut self)
     {
-        println!("destroy");
+        self.pool.join();
+
+        let written: Vec<u64> =
+            self.tracked.lock().expect("getting lock").written.keys().copied().collect();
+        for fh in written
+        {
+            self.derive_written(fh);
+        }
+        self.opened_files.write().expect("getting lock").clear();
+        self.opened_directories.clear();
+        self.tracked.lock().expect("getting lock").programs.clear();
+
+        if let Err(e) = TABLE!(self.table).flush()
+        {
+            error!("saving the table failed: {}", e);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn group_reads_are_merged_and_gates_keep_to_themselves()
+    {
+        let label = |value| (LatticeType::LinearNumber, LatticeValue::Number(value));
+        let public = || LabelResponse::Confidentiality(label(3));
+        let gate = LabelResponse::Gate {
+            integrity:       label(3),
+            confidentiality: label(1),
+        };
+
+        // A gate that started a pipeline, the cat in it and someone else
+        let mut tracked = Tracked::default();
+        tracked.programs.insert(10, Program::new("gate".into(), 10, gate));
+        tracked.programs.insert(11, Program::new("cat".into(), 10, public()));
+        tracked.programs.insert(20, Program::new("other".into(), 20, public()));
+        let secret = Some(LabelResponse::Confidentiality(label(1)));
+        tracked.programs.get_mut(&11).unwrap().open("/data/secret".into(), secret);
+        tracked.programs.get_mut(&20).unwrap().open("/data/other".into(), None);
+
+        let cat = tracked.reads(11).unwrap();
+        assert!(!cat.gate);
+        assert!(matches!(cat.integrity.1, LatticeValue::Number(1)));
+
+        // What cat read reaches the gate, and is not declassified by it
+        let gate = tracked.reads(10).unwrap();
+        assert!(gate.resources.contains(OsStr::new("/data/secret")));
+        assert!(!gate.resources.contains(OsStr::new("/data/other")));
+        assert!(matches!(gate.integrity.1, LatticeValue::Number(1)));
+        assert!(tracked.reads(12).is_none());
+    }
+
+    #[test]
+    fn truncate_by_path()
+    {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("a");
+        std::fs::write(&file, "0123456789").unwrap();
+
+        truncate(&file, 4).unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), b"0123");
+        truncate(&file, 6).unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), b"0123\0\0");
+        assert_eq!(truncate(&dir.path().join("b"), 0).unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn utimens_sets_or_leaves_times()
+    {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("a");
+        std::fs::write(&file, "").unwrap();
+
+        let time = UNIX_EPOCH + Duration::new(1_000_000_000, 500);
+        utimens(&file, None, Some(TimeOrNow::SpecificTime(time))).unwrap();
+        let m = std::fs::metadata(&file).unwrap();
+        assert_eq!(m.modified().unwrap(), time);
+        assert_ne!(m.accessed().unwrap(), time);
+
+        utimens(&file, Some(TimeOrNow::Now), None).unwrap();
+        let m = std::fs::metadata(&file).unwrap();
+        assert!(m.accessed().unwrap().elapsed().unwrap() < Duration::from_secs(60));
+        assert_eq!(m.modified().unwrap(), time);
+    }
+
+    #[test]
+    fn lchown_to_the_owner()
+    {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("a");
+        std::fs::write(&file, "").unwrap();
+        let m = std::fs::metadata(&file).unwrap();
+
+        lchown(&file, Some(m.uid()), None).unwrap();
+        lchown(&file, None, Some(m.gid())).unwrap();
+        let after = std::fs::metadata(&file).unwrap();
+        assert_eq!((after.uid(), after.gid()), (m.uid(), m.gid()));
+    }
+
+    #[test]
+    fn setattr_follows_the_posix_rules()
+    {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("a");
+        std::fs::write(&file, "").unwrap();
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o664)).unwrap();
+        lchown(&file, Some(1000), Some(1000)).unwrap();
+        let m = std::fs::metadata(&file).unwrap();
+
+        let caller = |uid, groups: &[u32]| Caller {
+            uid,
+            groups: groups.to_vec(),
+        };
+        let (root, owner, member, other) =
+            (caller(0, &[0]), caller(1000, &[1000]), caller(1001, &[1000]), caller(1002, &[]));
+        let permitted = |caller: &Caller, mode, owner, truncate, times| {
+            setattr_permitted(caller, &m, mode, owner, truncate, times)
+        };
+        let (now, set, none) = (
+            (Some(TimeOrNow::Now), None),
+            (None, Some(TimeOrNow::SpecificTime(UNIX_EPOCH))),
+            (None, None),
+        );
+
+        // Only root gives a file away, the owner only to their own group
+        assert_eq!(permitted(&root, None, (Some(0), Some(0)), false, none), Ok(None));
+        assert_eq!(permitted(&owner, None, (Some(1000), Some(1000)), false, none), Ok(None));
+        assert_eq!(permitted(&owner, None, (Some(1001), None), false, none), Err(EPERM));
+        assert_eq!(permitted(&owner, None, (None, Some(0)), false, none), Err(EPERM));
+        assert_eq!(permitted(&member, None, (None, Some(1000)), false, none), Err(EPERM));
+
+        // Only the owner changes the mode, and keeps setgid only for a group of theirs
+        assert_eq!(permitted(&owner, Some(0o2750), (None, None), false, none), Ok(Some(0o2750)));
+        let away = caller(1000, &[]);
+        assert_eq!(permitted(&away, Some(0o2750), (None, None), false, none), Ok(Some(0o750)));
+        assert_eq!(permitted(&member, Some(0o600), (None, None), false, none), Err(EPERM));
+
+        // Who may write truncates and sets the times to now, only the owner sets a time
+        assert_eq!(permitted(&member, None, (None, None), true, now), Ok(None));
+        assert_eq!(permitted(&member, None, (None, None), false, set), Err(EPERM));
+        assert_eq!(permitted(&owner, None, (None, None), false, set), Ok(None));
+        assert_eq!(permitted(&other, None, (None, None), true, none), Err(EACCES));
+        assert_eq!(permitted(&other, None, (None, None), false, now), Err(EACCES));
+    }
+
+    #[test]
+    fn attributes_are_those_of_the_backing_file()
+    {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("a");
+        std::fs::write(&file, "abc").unwrap();
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o640)).unwrap();
+
+        let m = std::fs::metadata(&file).unwrap();
+        let attr = meta2attr(&m, 7);
+        assert_eq!((attr.ino, attr.size, attr.perm), (7, 3, 0o640));
+        assert_eq!(attr.kind, FileType::RegularFile);
+        let ctime = attr.ctime.duration_since(UNIX_EPOCH).unwrap();
+        assert_eq!(ctime.subsec_nanos() as i64, m.ctime_nsec());
+    }
+
+    fn caller(uid: u32) -> Context
+    {
+        Context {
+            uid,
+            ..Context::default()
+        }
+    }
+
+    #[test]
+    fn metadata_and_labels_are_for_root()
+    {
+        let name = |name: &str| OsString::from(name);
+        let (user, root) = (caller(1000), caller(0));
+
+        assert!(xattr_visible(&name("user.gurret.owner"), &root));
+        assert!(!xattr_visible(&name("user.gurret.owner"), &user));
+        assert!(xattr_visible(&name(LABEL_XATTR), &user));
+        assert!(xattr_visible(&name("user.comment"), &user));
+
+        assert!(xattr_writable(&name("user.comment"), &user));
+        assert!(!xattr_writable(&name(LABEL_XATTR), &user));
+        assert!(xattr_writable(&name(LABEL_XATTR), &root));
+        assert!(!xattr_writable(&name("user.gurret.owner"), &user));
+        assert!(!xattr_writable(&name("gurret.meta.owner"), &root));
+    }
+
+    #[test]
+    fn set_xattr_honours_create_and_replace()
+    {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("a");
+        std::fs::write(&file, "").unwrap();
+        let name = OsStr::new("user.comment");
+
+        let err = set_xattr(&file, name, b"1", libc::XATTR_REPLACE).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(ENODATA));
+        set_xattr(&file, name, b"1", libc::XATTR_CREATE).unwrap();
+        let err = set_xattr(&file, name, b"2", libc::XATTR_CREATE).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EEXIST));
+        set_xattr(&file, name, b"3", libc::XATTR_REPLACE).unwrap();
+        set_xattr(&file, name, b"4", 0).unwrap();
+        assert_eq!(xattr::get(&file, name).unwrap(), Some(b"4".to_vec()));
+
+        xattr::remove(&file, name).unwrap();
+        assert_eq!(errno(xattr::remove(&file, name).unwrap_err()), ENODATA);
+    }
+
+    #[test]
+    fn rebase_only_under_the_prefix()
+    {
+        let (from, to) = (Path::new("/backing"), Path::new("/mnt"));
+        assert_eq!(rebase(Path::new("/backing/a/b"), from, to), Some(PathBuf::from("/mnt/a/b")));
+        assert_eq!(rebase(Path::new("/backingx/a"), from, to), None);
+        assert_eq!(rebase(Path::new("a/b"), from, to), None);
+    }
+
+    #[test]
+    fn make_node_of_every_kind()
+    {
+        let dir = TempDir::new().unwrap();
+        let (fifo, file) = (dir.path().join("fifo"), dir.path().join("file"));
+
+        make_node(&fifo, libc::S_IFIFO | 0o600, 0).unwrap();
+        make_node(&file, libc::S_IFREG | 0o640, 0).unwrap();
+        assert!(std::fs::symlink_metadata(&fifo).unwrap().file_type().is_fifo());
+        let m = std::fs::symlink_metadata(&file).unwrap();
+        assert!(m.is_file());
+        assert_eq!(meta2attr(&m, 2).kind, FileType::RegularFile);
+
+        let err = make_node(&file, libc::S_IFREG | 0o640, 0).unwrap_err();
+        assert_eq!(errno(err), libc::EEXIST);
     }
 }
@@ -0,0 +1,370 @@
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    ops::Index,
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+pub const ROOT_INODE: u64 = 1;
+
+// A file of the backing filesystem, its device and inode number
+type Backing = (u64, u64);
+
+struct Inode
+{
+    // Every name the file is known by, the last one is used to reach it
+    paths:   Vec<OsString>,
+    backing: Option<Backing>,
+    lookups: u64,
+}
+
+/*
+ * The inodes handed to the kernel. Every entry replied to the kernel is a
+ * lookup, and an inode lives until the kernel forgets as many lookups as it
+ * was given. Inodes are mapped to the backing (dev, ino), so the names of a
+ * hard link share one inode.
+ */
+pub struct Inodes
+{
+    next:       u64,
+    inodes:     HashMap<u64, Inode>,
+    by_path:    HashMap<OsString, u64>,
+    by_backing: HashMap<Backing, u64>,
+}
+
+impl Inodes
+{
+    pub fn new(root: &OsStr) -> Self
+    {
+        let mut inodes = Self {
+            next:       ROOT_INODE + 1,
+            inodes:     HashMap::with_capacity(1024),
+            by_path:    HashMap::with_capacity(1024),
+            by_backing: HashMap::with_capacity(1024),
+        };
+        let backing = std::fs::metadata(root).ok().map(|m| (m.dev(), m.ino()));
+        inodes.inodes.insert(ROOT_INODE, Inode {
+            paths: vec![root.to_os_string()],
+            backing,
+            lookups: 1,
+        });
+        inodes.by_path.insert(root.to_os_string(), ROOT_INODE);
+        if let Some(backing) = backing
+        {
+            inodes.by_backing.insert(backing, ROOT_INODE);
+        }
+        inodes
+    }
+
+    pub fn contains(&self, ino: u64) -> bool
+    {
+        self.path(ino).is_some()
+    }
+
+    pub fn path(&self, ino: u64) -> Option<&OsStr>
+    {
+        self.inodes.get(&ino).and_then(|inode| inode.paths.last()).map(OsString::as_os_str)
+    }
+
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<u64>
+    {
+        self.by_path.get(path.as_ref().as_os_str()).copied()
+    }
+
+    pub fn lookups(&self, ino: u64) -> u64
+    {
+        self.inodes.get(&ino).map(|inode| inode.lookups).unwrap_or(0)
+    }
+
+    /*
+     * The inode of `path`, given to the kernel once more. `meta` is that of
+     * the backing file, and a path that now names another backing file is
+     * moved over to its inode.
+     */
+    pub fn lookup(&mut self, path: impl AsRef<Path>, meta: &std::fs::Metadata) -> u64
+    {
+        let path = path.as_ref().as_os_str();
+        let backing = (meta.dev(), meta.ino());
+
+        let ino = match self.by_backing.get(&backing)
+        {
+            Some(ino) => *ino,
+            None =>
+            {
+                let ino = self.next;
+                self.next += 1;
+                self.inodes.insert(ino, Inode {
+                    paths:   Vec::new(),
+                    backing: Some(backing),
+                    lookups: 0,
+                });
+                self.by_backing.insert(backing, ino);
+                ino
+            },
+        };
+
+        if self.get(path) != Some(ino)
+        {
+            self.unlink(path);
+            self.by_path.insert(path.to_os_string(), ino);
+        }
+        let inode = self.inodes.get_mut(&ino).expect("inode is known");
+        inode.paths.retain(|p| p != path);
+        inode.paths.push(path.to_os_string());
+        inode.lookups += 1;
+        ino
+    }
+
+    // The kernel dropped `nlookup` of its lookups of `ino`
+    pub fn forget(&mut self, ino: u64, nlookup: u64)
+    {
+        if ino == ROOT_INODE
+        {
+            return;
+        }
+        let inode = match self.inodes.get_mut(&ino)
+        {
+            Some(inode) => inode,
+            None => return,
+        };
+        inode.lookups = inode.lookups.saturating_sub(nlookup);
+        if inode.lookups > 0
+        {
+            return;
+        }
+
+        let inode = self.inodes.remove(&ino).expect("inode is known");
+        for path in inode.paths
+        {
+            self.by_path.remove(&path);
+        }
+        if let Some(backing) = inode.backing
+        {
+            if self.by_backing.get(&backing) == Some(&ino)
+            {
+                self.by_backing.remove(&backing);
+            }
+        }
+    }
+
+    /*
+     * `path` no longer names a file. Its inode stays until it is forgotten,
+     * reachable through its other names if it has any. Without any, the
+     * backing inode number may be given to a new file, which gets an inode of
+     * its own.
+     */
+    pub fn unlink(&mut self, path: impl AsRef<Path>)
+    {
+        let path = path.as_ref().as_os_str();
+        if let Some(ino) = self.by_path.remove(path)
+        {
+            if let Some(inode) = self.inodes.get_mut(&ino)
+            {
+                inode.paths.retain(|p| p != path);
+            }
+            self.unmap_if_nameless(ino);
+        }
+    }
+
+    fn unmap_if_nameless(&mut self, ino: u64)
+    {
+        let backing = match self.inodes.get(&ino)
+        {
+            Some(inode) if inode.paths.is_empty() => inode.backing,
+            _ => return,
+        };
+        if let Some(backing) = backing
+        {
+            if self.by_backing.get(&backing) == Some(&ino)
+            {
+                self.by_backing.remove(&backing);
+            }
+        }
+    }
+
+    // Drop every name of `ino`, its backing file is gone
+    pub fn unlink_all(&mut self, ino: u64)
+    {
+        let paths = match self.inodes.get_mut(&ino)
+        {
+            Some(inode) if ino != ROOT_INODE => std::mem::take(&mut inode.paths),
+            _ => return,
+        };
+        for path in paths
+        {
+            self.by_path.remove(&path);
+        }
+        self.unmap_if_nameless(ino);
+    }
+
+    // `old` is now `new`, along with everything under it if it is a folder
+    pub fn rename(&mut self, old: impl AsRef<Path>, new: impl AsRef<Path>)
+    {
+        let (old, new) = (old.as_ref(), new.as_ref());
+        self.unlink(new);
+
+        let moved: Vec<(OsString, u64)> = self
+            .by_path
+            .iter()
+            .filter(|(path, _)| Path::new(path).starts_with(old))
+            .map(|(path, ino)| (path.clone(), *ino))
+            .collect();
+
+        for (path, ino) in moved
+        {
+            let renamed = match Path::new(&path).strip_prefix(old).expect("under old")
+            {
+                rest if rest.as_os_str().is_empty() => new.as_os_str().to_os_string(),
+                rest => new.join(rest).into_os_string(),
+            };
+
+            self.by_path.remove(&path);
+            self.by_path.insert(renamed.clone(), ino);
+            if let Some(inode) = self.inodes.get_mut(&ino)
+            {
+                for p in inode.paths.iter_mut().filter(|p| **p == path)
+                {
+                    *p = renamed.clone();
+                }
+            }
+        }
+    }
+}
+
+impl Index<u64> for Inodes
+{
+    type Output = OsStr;
+
+    fn index(&self, ino: u64) -> &OsStr
+    {
+        self.path(ino).expect("unknown inode")
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn lookup(inodes: &mut Inodes, path: &Path) -> u64
+    {
+        inodes.lookup(path, &fs::symlink_metadata(path).unwrap())
+    }
+
+    #[test]
+    fn forgotten_after_every_lookup()
+    {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("a");
+        fs::write(&file, "").unwrap();
+
+        let mut inodes = Inodes::new(dir.path().as_os_str());
+        let ino = lookup(&mut inodes, &file);
+        assert_eq!(lookup(&mut inodes, &file), ino);
+        assert_eq!(inodes.lookups(ino), 2);
+
+        inodes.forget(ino, 1);
+        assert_eq!(&inodes[ino], file.as_os_str());
+        inodes.forget(ino, 1);
+        assert!(!inodes.contains(ino));
+        assert_eq!(inodes.get(&file), None);
+        assert_eq!(inodes.inodes.len(), 1);
+
+        assert_ne!(lookup(&mut inodes, &file), ino);
+    }
+
+    #[test]
+    fn root_is_never_forgotten()
+    {
+        let dir = TempDir::new().unwrap();
+        let mut inodes = Inodes::new(dir.path().as_os_str());
+        inodes.forget(ROOT_INODE, 10);
+        inodes.unlink_all(ROOT_INODE);
+        assert_eq!(&inodes[ROOT_INODE], dir.path().as_os_str());
+    }
+
+    #[test]
+    fn hard_links_share_an_inode()
+    {
+        let dir = TempDir::new().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        fs::write(&a, "").unwrap();
+        fs::hard_link(&a, &b).unwrap();
+
+        let mut inodes = Inodes::new(dir.path().as_os_str());
+        let ino = lookup(&mut inodes, &a);
+        assert_eq!(lookup(&mut inodes, &b), ino);
+
+        inodes.unlink(&b);
+        assert_eq!(&inodes[ino], a.as_os_str());
+        inodes.forget(ino, 2);
+        assert_eq!(inodes.get(&a), None);
+    }
+
+    #[test]
+    fn unlinked_backing_is_free_for_a_new_file()
+    {
+        let dir = TempDir::new().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        fs::write(&a, "").unwrap();
+        fs::hard_link(&a, &b).unwrap();
+
+        let mut inodes = Inodes::new(dir.path().as_os_str());
+        let meta = fs::symlink_metadata(&a).unwrap();
+        let ino = inodes.lookup(&a, &meta);
+        assert_eq!(inodes.lookup(&b, &meta), ino);
+
+        inodes.unlink(&a);
+        assert_eq!(inodes.by_backing.len(), 2);
+        inodes.unlink(&b);
+        assert_eq!(inodes.by_backing.len(), 1);
+
+        // Still open, but a file given its backing inode number is another file
+        assert_eq!(inodes.lookups(ino), 2);
+        let c = dir.path().join("c");
+        assert_ne!(inodes.lookup(&c, &meta), ino);
+    }
+
+    #[test]
+    fn renamed_folder_moves_its_files()
+    {
+        let dir = TempDir::new().unwrap();
+        let (old, new) = (dir.path().join("old"), dir.path().join("new"));
+        fs::create_dir(&old).unwrap();
+        fs::write(old.join("a"), "").unwrap();
+
+        let mut inodes = Inodes::new(dir.path().as_os_str());
+        let folder = lookup(&mut inodes, &old);
+        let file = lookup(&mut inodes, &old.join("a"));
+
+        fs::rename(&old, &new).unwrap();
+        inodes.rename(&old, &new);
+        assert_eq!(inodes.get(&new), Some(folder));
+        assert_eq!(&inodes[file], new.join("a").as_os_str());
+        assert_eq!(inodes.get(old.join("a")), None);
+    }
+
+    #[test]
+    fn replaced_path_moves_to_the_new_file()
+    {
+        let dir = TempDir::new().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        fs::write(&a, "").unwrap();
+        fs::write(&b, "").unwrap();
+
+        let mut inodes = Inodes::new(dir.path().as_os_str());
+        let old = lookup(&mut inodes, &a);
+        fs::rename(&b, &a).unwrap();
+        let new = lookup(&mut inodes, &a);
+
+        assert_ne!(old, new);
+        assert!(!inodes.contains(old));
+        assert_eq!(inodes.lookups(old), 1);
+    }
+}
@@ -1,9 +1,17 @@
+pub mod argv;
+pub mod audit;
+pub mod broker;
+pub mod cli;
 pub mod config;
+pub mod daemon;
 pub mod file_system;
+pub mod inode;
 pub mod lattice;
+pub mod logging;
 pub mod metadata;
 pub mod mount;
 pub mod permission;
+pub mod pool;
 pub mod policy;
 pub mod socket;
 pub mod table;
@@ -19,6 +27,7 @@ pub use std::{
 };
 
 pub use config::*;
+use log::{error, info};
 pub use file_system::XmpFS;
 pub use lazy_static::lazy_static;
 lazy_static! {
@@ -32,7 +41,8 @@ macro_rules! TABLE {
     };
 }
 
-pub fn mount_file_system()
+// Mount TARGET on PATH until a signal asks to stop, or give back why it can not be mounted
+pub fn mount_file_system() -> std::io::Result<()>
 {
     let mountpoint = config("PATH");
     let _tmp_mountpoint = config("TARGET");
@@ -65,28 +75,55 @@ pub fn mount_file_system()
         MountOption::AllowOther,
     ];
 
-    let mut xmp = XmpFS::new();
+    policy::set_target(&*BASE_PATH);
+    audit::init()?;
+    let mut xmp = XmpFS::new()?;
     xmp.populate_root_dir();
 
     let state = Arc::clone(&xmp.table);
-    let fs_handle = fuser::spawn_mount2(xmp, mountpoint, &options).unwrap();
+    let events = xmp.metadata.clone();
+    let reload = Arc::clone(&xmp.reload);
+    let reloaded = xmp.metadata.clone();
 
+    let pidfile = daemon::write_pidfile()?;
+    let fs_handle = fuser::spawn_mount2(xmp, &mountpoint, &options)?;
+    info!("mounted {} on {}, pid {}", *BASE_PATH, mountpoint, std::process::id());
 
-    // Exit condition
+    // Exit condition, for the socket threads
     let term = Arc::new(AtomicBool::new(false));
-    // capture ctrl + c and set exit condition to true in the case
-    signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&term)).unwrap();
-
 
     let t2 = Arc::clone(&term);
+    let controller = Arc::clone(&state);
     let thread_handle = std::thread::spawn(move || {
-        socket::spawn(t2, state);
+        socket::spawn(t2, controller);
+    });
+    let t3 = Arc::clone(&term);
+    let control_handle = std::thread::spawn(move || {
+        if let Err(e) = socket::serve_control(t3, state, events)
+        {
+            error!("serving the control socket failed: {}", e);
+        }
     });
 
-    while !term.load(Ordering::Relaxed)
-    {}
+    // The metadata fields and the table at once, the rest when the file system is next used
+    let on_hangup = || {
+        info!("reloading on SIGHUP");
+        reloaded.reload();
+        reload.store(true, Ordering::Relaxed);
+    };
+    match daemon::wait_for_shutdown(on_hangup)
+    {
+        Ok(signal) => info!("unmounting {} on signal {}", mountpoint, signal),
+        Err(e) => error!("waiting for signals failed: {}, unmounting {}", e, mountpoint),
+    }
+
+    // Unmounting has the file system flush the table, see `XmpFS::destroy`
+    term.store(true, Ordering::Relaxed);
     drop(fs_handle);
     //mount::umount(&_tmp_mountpoint);
 
     let _ = thread_handle.join();
+    let _ = control_handle.join();
+    drop(pidfile);
+    Ok(())
 }
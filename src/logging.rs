@@ -0,0 +1,60 @@
+use std::io::Write;
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::parse_config;
+
+/*
+ * Everything logged, with the time, the level and the module it is from, to
+ * the standard error. A daemonised mount has it in its LOGFILE.
+ */
+struct Logger;
+
+static LOGGER: Logger = Logger;
+
+impl Log for Logger
+{
+    fn enabled(&self, metadata: &Metadata) -> bool
+    {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record)
+    {
+        if !self.enabled(record.metadata())
+        {
+            return;
+        }
+        let _ = writeln!(
+            std::io::stderr().lock(),
+            "{} {:<5} {}: {}",
+            chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%.3f"),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+    }
+
+    fn flush(&self)
+    {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+// The level to log at, `LOG_LEVEL` in config: off, error, warn, info, debug or trace
+pub fn level() -> std::io::Result<LevelFilter>
+{
+    let level = parse_config("LOG_LEVEL", "off, error, warn, info, debug or trace")?;
+    Ok(level.unwrap_or(LevelFilter::Info))
+}
+
+// Log through `LOGGER` from now on, once, or give back what is wrong with LOG_LEVEL
+pub fn init() -> std::io::Result<()>
+{
+    let level = level()?;
+    if log::set_logger(&LOGGER).is_ok()
+    {
+        log::set_max_level(level);
+    }
+    Ok(())
+}
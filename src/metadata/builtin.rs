@@ -0,0 +1,165 @@
+use std::path::Path;
+
+use crate::{
+    config::{get_program_name_by_pid, log_time},
+    metadata::{Context, Operation, METADATA_PREFIX},
+    policy::{read_tag, tag_file},
+};
+
+/*
+ * Bookkeeping fields that are maintained natively, instead of by a compiled
+ * program per field. The values are kept as xattrs on the file itself.
+ */
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum Builtin
+{
+    AccessTime,
+    AccessCount,
+    Accessor,
+    AccessorUid,
+}
+
+impl Builtin
+{
+    pub const ALL: [Builtin; 4] =
+        [Builtin::AccessTime, Builtin::AccessCount, Builtin::Accessor, Builtin::AccessorUid];
+
+    pub fn from_name(name: &str) -> Option<Self>
+    {
+        Self::ALL.into_iter().find(|b| b.name() == name)
+    }
+
+    // As used in the config
+    pub fn name(&self) -> &'static str
+    {
+        match self
+        {
+            Builtin::AccessTime => "atime",
+            Builtin::AccessCount => "access_count",
+            Builtin::Accessor => "accessor",
+            Builtin::AccessorUid => "accessor_uid",
+        }
+    }
+
+    pub fn xattr(&self) -> String
+    {
+        format!("{}{}", METADATA_PREFIX, self.name())
+    }
+
+    pub fn triggered_by(&self, operation: Operation) -> bool
+    {
+        match self
+        {
+            Builtin::AccessTime => matches!(
+                operation,
+                Operation::Open | Operation::Create | Operation::Read | Operation::Write
+            ),
+            Builtin::AccessCount | Builtin::Accessor | Builtin::AccessorUid =>
+            {
+                matches!(operation, Operation::Open | Operation::Create)
+            },
+        }
+    }
+
+    pub fn apply(&self, file: &Path, context: &Context) -> std::io::Result<()>
+    {
+        let value = match self
+        {
+            Builtin::AccessTime => log_time().to_string(),
+            Builtin::AccessCount =>
+            {
+                let count = read_tag(file, self.xattr())?.parse::<u64>().unwrap_or(0);
+                (count + 1).to_string()
+            },
+            Builtin::Accessor => get_program_name_by_pid(context.pid)
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            Builtin::AccessorUid => context.uid.to_string(),
+        };
+
+        tag_file(file, self.xattr(), value)
+    }
+}
+
+/*
+ * The builtins given as a comma separated list by `METADATA_BUILTINS`, or all
+ * of them if not set.
+ */
+pub fn builtins_from_config() -> std::io::Result<Vec<Builtin>>
+{
+    match crate::try_config("METADATA_BUILTINS")
+    {
+        None => Ok(Builtin::ALL.to_vec()),
+        Some(names) => names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                Builtin::from_name(name).ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("un-recognized builtin metadata {:?}", name),
+                    )
+                })
+            })
+            .collect(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    fn apply_all(file: &Path, operation: Operation, context: &Context)
+    {
+        for builtin in Builtin::ALL.iter().filter(|b| b.triggered_by(operation))
+        {
+            builtin.apply(file, context).unwrap();
+        }
+    }
+
+    #[test]
+    fn open_updates_every_field()
+    {
+        let file = NamedTempFile::new().unwrap();
+        let context = Context {
+            pid: std::process::id(),
+            uid: 1234,
+            ..Context::default()
+        };
+
+        apply_all(file.path(), Operation::Open, &context);
+        apply_all(file.path(), Operation::Open, &context);
+
+        let get = |b: Builtin| read_tag(file.path(), b.xattr()).unwrap();
+        assert_eq!(get(Builtin::AccessCount), "2");
+        assert_eq!(get(Builtin::AccessorUid), "1234");
+        assert!(!get(Builtin::Accessor).is_empty());
+        assert!(get(Builtin::AccessTime).parse::<u64>().unwrap() > 0);
+    }
+
+    #[test]
+    fn read_only_touches_access_time()
+    {
+        let file = NamedTempFile::new().unwrap();
+
+        apply_all(file.path(), Operation::Read, &Context::default());
+
+        assert!(!read_tag(file.path(), Builtin::AccessTime.xattr()).unwrap().is_empty());
+        assert_eq!(read_tag(file.path(), Builtin::AccessCount.xattr()).unwrap(), "");
+    }
+
+    #[test]
+    fn names_round_trip()
+    {
+        for builtin in Builtin::ALL
+        {
+            assert_eq!(Builtin::from_name(builtin.name()), Some(builtin));
+        }
+        assert_eq!(Builtin::from_name("nope"), None);
+    }
+}
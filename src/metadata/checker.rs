@@ -1,86 +1,75 @@
 use std::path::Path;
 
-use dynamic_exec::Executable;
+use dynamic_exec::{Cache, ExecError, Executable, Request, Response, Sandbox};
 
-use crate::metadata::{Metadata, Operation};
+use crate::metadata::{Manifest, Metadata};
 
-pub fn get_metadata_checker<P: AsRef<Path>>(path: P) -> Box<dyn Metadata<Item = ()>>
+pub fn get_metadata_checker(
+    manifest: &Manifest,
+    cache: &Cache,
+) -> std::io::Result<Box<dyn Metadata<Item = String>>>
 {
-    Box::new(RustChecker::new(path.as_ref()))
+    Ok(Box::new(FieldChecker::new(manifest, cache)?))
 }
 
 
-/*
-pub struct RustChecker(PathBuf);
-
-impl RustChecker
+// The `check` and (optional) `update` and `access` programs of a field
+pub struct FieldChecker
 {
-    // Expect the 'check' folder
-    pub fn new(path: &Path) -> Self
-    {
-        assert_eq!(path.file_name().unwrap(), std::ffi::OsStr::new("check"));
-        let mut buf = path.to_path_buf();
-        buf.push("main.rs");
-
-        assert!(buf.exists());
-
-        Self(buf)
-    }
+    check:  Box<dyn Executable>,
+    update: Option<Box<dyn Executable>>,
+    access: Option<Box<dyn Executable>>,
 }
 
-impl MetadataChecker for RustChecker
-{
-    fn check(&self, op: Operation) -> bool
-    {
-        let exec = Executable::from_path(&self.0).unwrap();
-        exec.exec::<bool>(Some(op.to_string())).unwrap()
-    }
-}*/
-
-
-pub struct RustChecker(Executable, Executable);
-
-impl RustChecker
+impl FieldChecker
 {
-    // Expect the 'check' folder
-    pub fn new(path: &Path) -> Self
+    pub fn new(manifest: &Manifest, cache: &Cache) -> std::io::Result<Self>
     {
-        assert_eq!(path.file_name().unwrap(), std::ffi::OsStr::new("check"));
-        let mut buf = path.to_path_buf();
-        buf.push("main.rs");
-
-        assert!(buf.exists());
-        let check = Executable::from_path(buf.clone()).unwrap();
+        let load = |path: &Path| manifest.load_program(path, cache);
 
-
-        buf.pop();
-        buf.pop();
-        buf.push("update");
-        buf.push("main.rs");
-
-        let update = Executable::from_path(buf).unwrap();
-
-        Self(check, update)
+        Ok(Self {
+            check:  load(&manifest.check)?,
+            update: manifest.update.as_deref().map(load).transpose()?,
+            access: manifest.access.as_deref().map(load).transpose()?,
+        })
     }
 }
 
-impl Metadata for RustChecker
+impl Metadata for FieldChecker
 {
-    type Item = ();
-
-    fn check(&self, op: Operation) -> bool
+    type Item = String;
+
+    fn check(
+        &self,
+        request: &Request,
+        env: &[(String, String)],
+        sandbox: &Sandbox,
+    ) -> Result<Response, ExecError>
     {
-        self.0.exec::<bool>(Some(op.to_string())).unwrap()
+        self.check.call(request, env, sandbox)
     }
 
     fn update(&self) -> std::io::Result<()>
     {
-        self.1.exec_void().unwrap();
-        Ok(())
+        match &self.update
+        {
+            Some(update) => Ok(update.exec_void()?),
+            None => Ok(()),
+        }
     }
 
-    fn access(&self) -> std::io::Result<Self::Item>
+    // Without an access program, the stored value is the current one
+    fn access(
+        &self,
+        request: &Request,
+        env: &[(String, String)],
+        sandbox: &Sandbox,
+    ) -> Result<Option<String>, ExecError>
     {
-        todo!()
+        match &self.access
+        {
+            Some(access) => Ok(access.call(request, env, sandbox)?.value),
+            None => Ok(None),
+        }
     }
 }
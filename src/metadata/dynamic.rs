@@ -1,89 +1,32 @@
-use std::collections::HashMap;
-/*
- * @TODO:
- * struct Access
-{
-    filename: Path,
-}
-
-impl Metadata for Access
-{
-    fn execute(&self)
-    {
-        /*
-        let time = // epoch
-        update_access_time(self.filename, time)
-        */
-    }
-}*/
-use std::path::PathBuf;
+use std::path::Path;
 
-use dynamic_exec::Executable;
+use dynamic_exec::{Cache, ExecError, Executable, Request, Response, Sandbox};
 
-use crate::metadata::Metadata;
+use crate::metadata::Manifest;
 
 
-static mut HASHMAP: Option<HashMap<PathBuf, Executable>> = None;
-
-pub struct DynamicMetadata(PathBuf);
+pub struct DynamicMetadata(Box<dyn Executable>);
 
 impl DynamicMetadata
 {
-    pub fn new(path: PathBuf) -> Self
+    // The `execute` program of a field
+    pub fn new(manifest: &Manifest, cache: &Cache) -> std::io::Result<Self>
     {
-        assert_eq!(path.file_name().unwrap(), std::ffi::OsStr::new("execute"));
-        let mut buf = path.to_path_buf();
-        buf.push("main.rs");
-        Self(buf)
+        Ok(Self(manifest.load_program(&manifest.execute, cache)?))
     }
-}
 
-/*impl Metadata for DynamicMetadata
-{
-    fn execute(&self) -> std::io::Result<()>
+    pub fn execute(
+        &self,
+        request: &Request,
+        env: &[(String, String)],
+        sandbox: &Sandbox,
+    ) -> Result<Response, ExecError>
     {
-        let map = unsafe
-        {
-            if HASHMAP.is_none()
-            {
-                HASHMAP = Some(HashMap::new());
-            }
-            HASHMAP.as_mut().unwrap()
-        };
-
-        if !map.contains_key(&self.0)
-        {
-            map.insert(self.0.clone(), Executable::from_path(self.0.clone()).unwrap());
-        }
-        let exec = map.get(&self.0).unwrap();
-        //let exec = Executable::from_path(&self.0).unwrap();
-        exec.exec_void()
+        self.0.call(request, env, sandbox)
     }
-}*/
-
-/*
-pub struct DynamicMetadata(Executable);
 
-impl DynamicMetadata
-{
-    pub fn new(path: PathBuf) -> Self
-    {
-        assert_eq!(path.file_name().unwrap(), std::ffi::OsStr::new("execute"));
-        let mut buf = path.to_path_buf();
-        buf.push("main.rs");
-
-
-        let exec = Executable::from_path(buf).unwrap();
-
-        Self(exec)
-    }
-}
-
-impl Metadata for DynamicMetadata
-{
-    fn execute(&self) -> std::io::Result<()>
+    pub fn path(&self) -> &Path
     {
-        self.0.exec_void()
+        self.0.path()
     }
 }
-*/
@@ -1,109 +1,762 @@
 use std::{
-    cell::RefCell,
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
+    ffi::OsString,
+    io::{Error, ErrorKind},
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Instant,
 };
 
-use crate::metadata::{
-    get_metadata_checker, DynamicMetadata, Metadata, MetadataHandler, Operation,
+use dynamic_exec::{Cache, Sandbox};
+use log::{debug, error};
+
+use crate::{
+    get_program_name_by_pid,
+    metadata::{
+        get_metadata_checker, log_response, Change, Context, DynamicMetadata, FieldEntry,
+        FieldTiming, Manifest, Metadata, MetadataHandler, Operation, Registry,
+    },
 };
 
 
-pub struct MockHandler
+/*
+ * Runs the fields the `Registry` gives for a file. Every program is loaded
+ * when the handler is made, so a broken field is found at startup rather than
+ * on the first open that reaches it.
+ *
+ * Every program runs in `sandbox`, where only the file and the folder of the
+ * field are visible. The binaries are compiled into `cache`.
+ *
+ * Which checks passed is remembered by file, operation, uid, program and the
+ * paths of a rename, so a check must not depend on the pid or the part of
+ * the file asked for. The memo of a file is dropped when it or its metadata
+ * is modified. When a file of a field is, all of it is, and the field is
+ * loaded again before its next check. At most `MEMO_SIZE` checks are
+ * remembered.
+ */
+pub struct RegistryHandler
 {
-    root_folder: PathBuf,
-    check_cache: HashMap<(PathBuf, Operation), Box<dyn Metadata<Item = ()>>>,
+    sandbox:    Sandbox,
+    cache:      Cache,
+    fields:     Vec<LoadedField>,
+    memo:       Mutex<HashMap<MemoKey, Vec<usize>>>,
+    generation: AtomicU64,
+    timings:    Mutex<BTreeMap<String, FieldTiming>>,
+}
+
+const MEMO_SIZE: usize = 4096;
 
-    exec_cache: HashMap<(PathBuf, Operation), DynamicMetadata>,
+// The context is left without its pid, offset and size
+type MemoKey = (PathBuf, Operation, Option<OsString>, Context);
 
-    directory_cache: HashMap<PathBuf, Vec<std::fs::DirEntry>>,
+struct LoadedField
+{
+    entry:    FieldEntry,
+    programs: RwLock<Programs>,
+    // A file of the field changed since the programs were loaded
+    stale:    AtomicBool,
 }
 
-impl MockHandler
+struct Programs
 {
-    pub fn new() -> Self
-    {
-        let mut root_folder = PathBuf::new();
-        // @TODO: SHould be in config
-        root_folder.push("/tmp/dropbox_folder/metadata/");
+    checker: Box<dyn Metadata<Item = String>>,
+    execute: Arc<DynamicMetadata>,
+}
 
-        Self {
-            root_folder,
-            check_cache: HashMap::new(),
-            exec_cache: HashMap::new(),
+impl Programs
+{
+    fn load(manifest: &Manifest, cache: &Cache) -> std::io::Result<Self>
+    {
+        Ok(Self {
+            checker: get_metadata_checker(manifest, cache)?,
+            execute: Arc::new(DynamicMetadata::new(manifest, cache)?),
+        })
+    }
+}
 
-            directory_cache: HashMap::new(),
+impl LoadedField
+{
+    /*
+     * The programs of the field, loaded again first if it changed. If they
+     * no longer load the old ones are kept, until the field is fixed.
+     */
+    fn programs(&self, cache: &Cache) -> std::sync::RwLockReadGuard<'_, Programs>
+    {
+        if self.stale.swap(false, Ordering::SeqCst)
+        {
+            match Manifest::load(&self.entry.folder).and_then(|m| Programs::load(&m, cache))
+            {
+                Ok(programs) => *self.programs.write().expect("getting lock") = programs,
+                Err(e) => error!("reloading field {:?} failed: {}", self.entry.name, e),
+            }
         }
+        self.programs.read().expect("getting lock")
     }
 }
 
-/*use lazy_static::lazy_static;
-lazy_static! {
-    static mut HASHMAP: HashMap<PathBuf, DynamicMetadata> = HashMap::new();
-}*/
-static mut HASHMAP: Option<HashMap<PathBuf, DynamicMetadata>> = None; //HashMap::new();
-
-pub fn run<'m>(mock: &'m mut MockHandler, file: &Path, operation: Operation)
-    -> Vec<DynamicMetadata>
+impl RegistryHandler
 {
-    let file_stem = file.file_stem().unwrap();
-    let mut root = mock.root_folder.clone();
-    root.push(file_stem);
+    pub fn new(registry: Registry, sandbox: Sandbox, cache: &Cache) -> std::io::Result<Self>
+    {
+        let mut fields = Vec::new();
+        let mut problems = Vec::new();
 
+        for entry in registry.fields
+        {
+            match Programs::load(&entry.manifest, cache)
+            {
+                Ok(programs) => fields.push(LoadedField {
+                    entry,
+                    programs: RwLock::new(programs),
+                    stale: AtomicBool::new(false),
+                }),
+                Err(e) => problems.push(format!("field {:?}: {}", entry.name, e)),
+            }
+        }
 
-    std::fs::read_dir(root.clone())
-        .unwrap()
-        .flatten()
-        .map(|entry| {
-            let mut check = entry.path();
-            check.push("check");
+        if !problems.is_empty()
+        {
+            return Err(Error::new(ErrorKind::InvalidData, problems.join("\n")));
+        }
+        Ok(Self {
+            sandbox,
+            cache: cache.clone(),
+            fields,
+            memo: Mutex::new(HashMap::new()),
+            generation: AtomicU64::new(0),
+            timings: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    fn sandbox(&self, file: &Path, field: &FieldEntry) -> Sandbox
+    {
+        let sandbox = self.sandbox.clone().visible(&field.folder);
+        match file.exists()
+        {
+            true => sandbox.visible(file),
+            false => sandbox,
+        }
+    }
 
-            let checker = mock
-                .check_cache
-                .entry((check.clone(), operation))
-                .or_insert_with(|| get_metadata_checker(&check));
+    // The indexes of the fields whose check passed
+    fn allowed(
+        &self,
+        file: &Path,
+        operation: Operation,
+        context: &Context,
+    ) -> std::io::Result<Vec<usize>>
+    {
+        let program = get_program_name_by_pid(context.pid);
+        let asker = Context {
+            pid: 0,
+            offset: None,
+            size: None,
+            ..context.clone()
+        };
+        let key = (file.to_path_buf(), operation, program, asker);
+        if let Some(allowed) = self.memo.lock().expect("getting lock").get(&key)
+        {
+            let mut timings = self.timings.lock().expect("getting lock");
+            for field in self.fields.iter().filter(|f| f.entry.applies_to(file))
+            {
+                timings.entry(field.entry.name.clone()).or_default().cached += 1;
+            }
+            return Ok(allowed.clone());
+        }
 
-            //let checker = get_metadata_checker(&check);
-            let ans = checker.check(operation);
+        // A check that raced with `invalidate` may have seen the old file
+        let generation = self.generation.load(Ordering::SeqCst);
+        let request = context.request(file, operation);
+        let env = context.env(file, operation);
 
-            //ans.then(|| {
-            let mut exec = entry.path();
-            exec.push("execute");
+        let mut allowed = Vec::new();
+        for (i, field) in self.fields.iter().enumerate().filter(|(_, f)| f.entry.applies_to(file))
+        {
+            let start = Instant::now();
+            let sandbox = self.sandbox(file, &field.entry);
+            let response = field.programs(&self.cache).checker.check(&request, &env, &sandbox);
+            let elapsed = start.elapsed();
+            debug!("check of {} for {} took {:?}", field.entry.name, file.display(), elapsed);
 
+            let mut timings = self.timings.lock().expect("getting lock");
+            let timing = timings.entry(field.entry.name.clone()).or_default();
+            timing.checks += 1;
+            timing.total += elapsed;
+            drop(timings);
 
-            /*let map = unsafe
+            let response = response?;
+            log_response(&field.entry.folder, &response);
+            if response.allow
             {
-                if HASHMAP.is_none()
-                {
-                    HASHMAP = Some(HashMap::new());
-                }
-                HASHMAP.as_mut().unwrap()
-            };
+                allowed.push(i);
+            }
+        }
 
-            if !map.contains_key(&exec)
+        let mut memo = self.memo.lock().expect("getting lock");
+        if self.generation.load(Ordering::SeqCst) == generation
+        {
+            if memo.len() >= MEMO_SIZE
             {
-                map.insert(exec.clone(), DynamicMetadata::new(exec.clone()));
+                memo.clear();
             }
-            map.get(&exec).unwrap()*/
+            memo.insert(key, allowed.clone());
+        }
+        Ok(allowed)
+    }
+}
+
+pub fn run(
+    handler: &RegistryHandler,
+    file: &Path,
+    operation: Operation,
+    context: &Context,
+) -> std::io::Result<Vec<Change>>
+{
+    let allowed = handler.allowed(file, operation, context)?;
+    if allowed.is_empty()
+    {
+        return Ok(Vec::new());
+    }
 
-            DynamicMetadata::new(exec.clone())
+    let request = context.request(file, operation);
+    let env = context.env(file, operation);
+
+    Ok(allowed
+        .into_iter()
+        .map(|i| &handler.fields[i])
+        .map(|field| Change {
+            metadata: Arc::clone(&field.programs(&handler.cache).execute),
+            request:  request.clone(),
+            env:      env.clone(),
+            sandbox:  handler.sandbox(file, &field.entry),
         })
-        .collect()
+        .collect())
 }
 
 
-impl MetadataHandler for MockHandler
+impl MetadataHandler for RegistryHandler
 {
-    fn changes(&mut self, file: &Path, operation: Operation) -> Vec<DynamicMetadata>
+    fn changes(
+        &self,
+        file: &Path,
+        operation: Operation,
+        context: &Context,
+    ) -> std::io::Result<Vec<Change>>
+    {
+        run(self, file, operation, context)
+    }
+
+    fn query(&self, file: &Path, context: &Context) -> std::io::Result<BTreeMap<String, String>>
     {
-        let res = run(self, file, operation);
+        let request = context.request(file, Operation::Access);
+        let env = context.env(file, Operation::Access);
+        let mut values = request.metadata.clone();
 
-        res
-        //cache_everything(self, file, operation)
+        for field in self.fields.iter().filter(|f| f.entry.applies_to(file))
+        {
+            let sandbox = self.sandbox(file, &field.entry);
+            let programs = field.programs(&self.cache);
+            if let Some(value) = programs.checker.access(&request, &env, &sandbox)?
+            {
+                values.insert(field.entry.name.clone(), value);
+            }
+        }
+        Ok(values)
+    }
+
+    fn invalidate(&self, file: &Path)
+    {
+        let mut memo = self.memo.lock().expect("getting lock");
+        self.generation.fetch_add(1, Ordering::SeqCst);
+
+        let mut changed = false;
+        for field in self.fields.iter().filter(|f| file.starts_with(&f.entry.folder))
+        {
+            field.stale.store(true, Ordering::SeqCst);
+            changed = true;
+        }
+        match changed
+        {
+            true => memo.clear(),
+            false => memo.retain(|(path, ..), _| path != file),
+        }
+    }
+
+    fn timings(&self) -> BTreeMap<String, FieldTiming>
+    {
+        self.timings.lock().expect("getting lock").clone()
     }
 
     fn update_remote(&self)
     {
-        todo!()
+        // Nothing is batched up yet
+    }
+}
+
+
+/*
+ * For mounting without any metadata, every operation is a no-op.
+ */
+pub struct NoopHandler;
+
+impl MetadataHandler for NoopHandler
+{
+    fn changes(
+        &self,
+        _file: &Path,
+        _operation: Operation,
+        _context: &Context,
+    ) -> std::io::Result<Vec<Change>>
+    {
+        Ok(Vec::new())
+    }
+
+    fn update_remote(&self) {}
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use std::os::unix::fs::PermissionsExt;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    // Creates `<root>/<name>/` whose check passes only for `op`, and whose
+    // execute appends the field name to `<root>/log`
+    fn field(root: &Path, name: &str, op: Operation)
+    {
+        let dir = root.join(name);
+        let log = root.join("log");
+
+        let programs = [
+            (
+                "check",
+                r#"fn main(){
+                    println!("{{\"allow\": {}}}", std::env::args().nth(1).unwrap() == "OP")
+                }"#
+                .replace("OP", &op.to_string()),
+            ),
+            ("update", "fn main(){}".to_string()),
+            (
+                "execute",
+                format!(
+                    "use std::io::Write;
+                    fn main(){{
+                        let mut f = std::fs::OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open({:?})
+                            .unwrap();
+                        writeln!(f, \"{}\").unwrap();
+                    }}",
+                    log, name
+                ),
+            ),
+        ];
+
+        for (folder, source) in programs
+        {
+            std::fs::create_dir_all(dir.join(folder)).unwrap();
+            std::fs::write(dir.join(folder).join("main.rs"), source).unwrap();
+        }
+    }
+
+    // Registers the fields by name, each for a single pattern
+    fn registry(root: &Path, fields: &[(&str, &str)])
+    {
+        let toml = fields
+            .iter()
+            .map(|(name, files)| format!("[[field]]\nname = {:?}\nfiles = [{:?}]\n", name, files))
+            .collect::<String>();
+        std::fs::write(root.join(crate::metadata::REGISTRY), toml).unwrap();
+    }
+
+    fn handler(root: &Path) -> RegistryHandler
+    {
+        let registry = Registry::load(root, Path::new("/data")).unwrap();
+        RegistryHandler::new(registry, Sandbox::disabled(), &Cache::new(root.join(".cache")))
+            .unwrap()
+    }
+
+    fn executed(handler: &RegistryHandler, root: &Path, file: &Path, op: Operation) -> String
+    {
+        let log = root.join("log");
+        let _ = std::fs::remove_file(&log);
+        let context = Context::default();
+        for change in handler.changes(file, op, &context).unwrap()
+        {
+            change.execute().unwrap();
+        }
+        std::fs::read_to_string(log).unwrap_or_default()
+    }
+
+    #[test]
+    fn file_without_fields_is_noop()
+    {
+        let root = TempDir::new().unwrap();
+        field(root.path(), "on_open", Operation::Open);
+        registry(root.path(), &[("on_open", "*.json")]);
+        let handler = handler(root.path());
+
+        for op in [Operation::Open, Operation::Read, Operation::Write, Operation::Create]
+        {
+            let changes = handler.changes(Path::new("/data/a.csv"), op, &Context::default());
+            assert!(changes.unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn missing_root_is_noop()
+    {
+        let handler = handler(Path::new("/this/path/does/not/exist"));
+        let changes =
+            handler.changes(Path::new("/data/a.csv"), Operation::Open, &Context::default());
+        assert!(changes.unwrap().is_empty());
+    }
+
+    #[test]
+    fn check_gates_execute()
+    {
+        let root = TempDir::new().unwrap();
+        field(root.path(), "on_open", Operation::Open);
+        field(root.path(), "on_write", Operation::Write);
+        registry(root.path(), &[("on_open", "a.csv"), ("on_write", "*.csv")]);
+        let handler = handler(root.path());
+        let (root, file) = (root.path(), Path::new("/data/a.csv"));
+
+        assert_eq!(executed(&handler, root, file, Operation::Open), "on_open\n");
+        assert_eq!(executed(&handler, root, file, Operation::Write), "on_write\n");
+        assert_eq!(executed(&handler, root, file, Operation::Read), "");
+        assert_eq!(executed(&handler, root, file, Operation::Create), "");
+    }
+
+    #[test]
+    fn same_stem_is_another_file()
+    {
+        let root = TempDir::new().unwrap();
+        field(root.path(), "on_open", Operation::Open);
+        registry(root.path(), &[("on_open", "a.csv")]);
+        let handler = handler(root.path());
+        let root = root.path();
+
+        let opened = |file: &str| executed(&handler, root, Path::new(file), Operation::Open);
+        assert_eq!(opened("/data/a.csv"), "on_open\n");
+        assert_eq!(opened("/data/a.json"), "");
+        assert_eq!(opened("/data/x/a.csv"), "");
+    }
+
+    #[test]
+    fn context_reaches_check()
+    {
+        let root = TempDir::new().unwrap();
+        field(root.path(), "on_open", Operation::Open);
+        registry(root.path(), &[("on_open", "a.csv")]);
+        let check = root.path().join("on_open").join("check").join("main.rs");
+        std::fs::write(
+            check,
+            r#"use std::io::Read;
+            fn main(){
+                let mut request = String::new();
+                std::io::stdin().read_to_string(&mut request).unwrap();
+                let allow = std::env::var("GURRET_NEW_PATH").is_ok()
+                    && request.contains("/data/b.csv");
+                println!("{{\"allow\": {}}}", allow);
+            }"#,
+        )
+        .unwrap();
+
+        let handler = handler(root.path());
+        let file = Path::new("/data/a.csv");
+        let context = Context {
+            new_path: Some("/data/b.csv".into()),
+            ..Context::default()
+        };
+
+        assert!(handler.changes(file, Operation::Rename, &Context::default()).unwrap().is_empty());
+        assert_eq!(handler.changes(file, Operation::Rename, &context).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn wasm_field_runs_in_process()
+    {
+        let root = TempDir::new().unwrap();
+        let file = root.path().join("a.csv");
+        std::fs::write(&file, "").unwrap();
+
+        let module = |body: &str| {
+            format!(
+                r#"(module
+                    (import "gurret" "print" (func $print (param i32 i32)))
+                    (memory (export "memory") 1)
+                    (data (i32.const 0) "{{\"allow\":true}}")
+                    (data (i32.const 16) "{{\"updates\":{{\"seen\":\"yes\"}}}}")
+                    (func (export "main") {}))"#,
+                body
+            )
+        };
+        let programs = [
+            ("check", module("(call $print (i32.const 0) (i32.const 14))")),
+            ("update", module("")),
+            ("execute", module("(call $print (i32.const 16) (i32.const 26))")),
+        ];
+        for (folder, source) in programs
+        {
+            let dir = root.path().join("field").join(folder);
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("main.wat"), source).unwrap();
+        }
+        registry(root.path(), &[("field", file.to_str().unwrap())]);
+
+        let handler = handler(root.path());
+        for change in handler.changes(&file, Operation::Open, &Context::default()).unwrap()
+        {
+            change.execute().unwrap();
+        }
+        assert_eq!(xattr::get(&file, "user.gurret.seen").unwrap(), Some(b"yes".to_vec()));
+    }
+
+    #[test]
+    fn request_carries_metadata_and_updates_are_stored()
+    {
+        let root = TempDir::new().unwrap();
+        let file = root.path().join("a.csv");
+        std::fs::write(&file, "").unwrap();
+        xattr::set(&file, "user.label", b"owner = \"alice\"").unwrap();
+        xattr::set(&file, "user.gurret.count", b"1").unwrap();
+
+        field(root.path(), "count", Operation::Open);
+        registry(root.path(), &[("count", file.to_str().unwrap())]);
+        let dir = root.path().join("count");
+        std::fs::write(
+            dir.join("check").join("main.rs"),
+            r#"use std::io::Read;
+            fn main(){
+                let mut request = String::new();
+                std::io::stdin().read_to_string(&mut request).unwrap();
+                let allow = request.contains("\"count\":\"1\"") && request.contains("alice");
+                println!("{{\"allow\": {}}}", allow);
+            }"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("execute").join("main.rs"),
+            r#"fn main(){
+                println!("{{\"updates\": {{\"count\": \"2\"}}, \"log\": [\"counted\"]}}")
+            }"#,
+        )
+        .unwrap();
+
+        let handler = handler(root.path());
+        let changes = handler.changes(&file, Operation::Open, &Context::default()).unwrap();
+        assert_eq!(changes.len(), 1);
+        changes[0].execute().unwrap();
+
+        assert_eq!(xattr::get(&file, "user.gurret.count").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn sandboxed_updates_are_stored_by_the_daemon()
+    {
+        let root = TempDir::new().unwrap();
+        let file = root.path().join("a.csv");
+        std::fs::write(&file, "").unwrap();
+        // `nobody` has to be able to reach the programs, but not to write the file
+        std::fs::set_permissions(root.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        registry(root.path(), &[("stamp", file.to_str().unwrap())]);
+        let field = root.path().join("stamp");
+        std::fs::create_dir_all(&field).unwrap();
+        let manifest = "check = \"check.sh\"\nexecute = \"execute.sh\"\n";
+        std::fs::write(field.join("field.toml"), manifest).unwrap();
+        std::fs::write(field.join("check.sh"), "echo '{\"allow\": true}'\n").unwrap();
+        let execute = "echo '{\"updates\": {\"stamp\": \"1\"}}'\n";
+        std::fs::write(field.join("execute.sh"), execute).unwrap();
+
+        let registry = Registry::load(root.path(), Path::new("/data")).unwrap();
+        let cache = Cache::new(root.path().join(".cache"));
+        let handler = RegistryHandler::new(registry, Sandbox::default(), &cache).unwrap();
+        let changes = handler.changes(&file, Operation::Open, &Context::default()).unwrap();
+        assert_eq!(changes.len(), 1);
+        changes[0].execute().unwrap();
+
+        assert_eq!(xattr::get(&file, "user.gurret.stamp").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn manifest_field_in_python()
+    {
+        let root = TempDir::new().unwrap();
+        let file = root.path().join("a.csv");
+        std::fs::write(&file, "").unwrap();
+
+        registry(root.path(), &[("owner", file.to_str().unwrap())]);
+        let field = root.path().join("owner");
+        std::fs::create_dir_all(&field).unwrap();
+        std::fs::write(field.join("field.toml"), "check = \"check.py\"\nexecute = \"execute.py\"\n")
+            .unwrap();
+        std::fs::write(
+            field.join("check.py"),
+            r#"import json, sys
+print(json.dumps({"allow": json.load(sys.stdin)["operation"] == "Create"}))
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            field.join("execute.py"),
+            r#"import json, sys
+print(json.dumps({"updates": {"owner": str(json.load(sys.stdin)["uid"])}}))
+"#,
+        )
+        .unwrap();
+
+        let handler = handler(root.path());
+        let context = Context {
+            uid: 1000,
+            ..Context::default()
+        };
+        assert!(handler.changes(&file, Operation::Open, &context).unwrap().is_empty());
+        for change in handler.changes(&file, Operation::Create, &context).unwrap()
+        {
+            change.execute().unwrap();
+        }
+        assert_eq!(xattr::get(&file, "user.gurret.owner").unwrap(), Some(b"1000".to_vec()));
+    }
+
+    #[test]
+    fn query_asks_access_programs()
+    {
+        let root = TempDir::new().unwrap();
+        let file = root.path().join("a.csv");
+        std::fs::write(&file, "").unwrap();
+        xattr::set(&file, "user.gurret.owner", b"bob").unwrap();
+        xattr::set(&file, "user.gurret.rows", b"10").unwrap();
+
+        registry(root.path(), &[("owner", file.to_str().unwrap())]);
+        let field = root.path().join("owner");
+        std::fs::create_dir_all(&field).unwrap();
+        std::fs::write(
+            field.join("field.toml"),
+            "check = \"check.sh\"\nexecute = \"check.sh\"\naccess = \"access.sh\"\n",
+        )
+        .unwrap();
+        std::fs::write(field.join("check.sh"), "").unwrap();
+        std::fs::write(field.join("access.sh"), "echo '{\"value\": \"alice\"}'\n").unwrap();
+
+        let values = handler(root.path()).query(&file, &Context::default()).unwrap();
+        assert_eq!(values["owner"], "alice");
+        assert_eq!(values["rows"], "10");
+
+        let values = NoopHandler.query(&file, &Context::default()).unwrap();
+        assert_eq!(values["owner"], "bob");
+    }
+
+    #[test]
+    fn checks_are_remembered_until_invalidated()
+    {
+        let root = TempDir::new().unwrap();
+        let file = root.path().join("a.csv");
+        let log = root.path().join("checks");
+
+        registry(root.path(), &[("seen", file.to_str().unwrap())]);
+        let field = root.path().join("seen");
+        std::fs::create_dir_all(&field).unwrap();
+        std::fs::write(field.join("field.toml"), "check = \"check.sh\"\nexecute = \"check.sh\"\n")
+            .unwrap();
+        let check = format!("echo >> {}\necho '{{\"allow\": true}}'\n", log.display());
+        std::fs::write(field.join("check.sh"), check).unwrap();
+
+        let handler = handler(root.path());
+        let checks = || std::fs::read_to_string(&log).unwrap_or_default().lines().count();
+        let open = |uid, offset| {
+            let context = Context {
+                pid: std::process::id(),
+                uid,
+                offset: Some(offset),
+                ..Context::default()
+            };
+            assert_eq!(handler.changes(&file, Operation::Open, &context).unwrap().len(), 1);
+        };
+
+        open(0, 0);
+        open(0, 4096);
+        assert_eq!(checks(), 1);
+        open(1000, 0);
+        assert_eq!(checks(), 2);
+
+        handler.invalidate(&file);
+        open(0, 0);
+        assert_eq!(checks(), 3);
+
+        handler.invalidate(&field.join("check.sh"));
+        open(0, 0);
+        assert_eq!(checks(), 4);
+
+        let timing = handler.timings()["seen"];
+        assert_eq!((timing.checks, timing.cached), (4, 1));
+    }
+
+    #[test]
+    fn changed_field_is_loaded_again()
+    {
+        let root = TempDir::new().unwrap();
+        let file = root.path().join("a.csv");
+
+        registry(root.path(), &[("field", file.to_str().unwrap())]);
+        let field = root.path().join("field");
+        std::fs::create_dir_all(&field).unwrap();
+        let manifest = |check: &str| {
+            let toml = format!("check = {:?}\nexecute = \"allow.sh\"\n", check);
+            std::fs::write(field.join("field.toml"), toml).unwrap();
+        };
+        manifest("allow.sh");
+        std::fs::write(field.join("allow.sh"), "echo '{\"allow\": true}'\n").unwrap();
+        std::fs::write(field.join("deny.sh"), "echo '{\"allow\": false}'\n").unwrap();
+
+        let handler = handler(root.path());
+        let changes = || handler.changes(&file, Operation::Open, &Context::default()).unwrap();
+        assert_eq!(changes().len(), 1);
+
+        manifest("deny.sh");
+        assert_eq!(changes().len(), 1);
+        handler.invalidate(&field.join("field.toml"));
+        assert!(changes().is_empty());
+
+        // A field that no longer loads keeps what it had
+        manifest("missing.sh");
+        handler.invalidate(&field.join("field.toml"));
+        assert!(changes().is_empty());
+    }
+
+    #[test]
+    fn broken_field_is_an_error()
+    {
+        let root = TempDir::new().unwrap();
+        std::fs::create_dir_all(root.path().join("field").join("execute")).unwrap();
+        registry(root.path(), &[("field", "*.csv")]);
+
+        let err = Registry::load(root.path(), Path::new("/data")).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn broken_program_fails_at_startup()
+    {
+        let root = TempDir::new().unwrap();
+        field(root.path(), "broken", Operation::Open);
+        let check = root.path().join("broken").join("check").join("main.rs");
+        std::fs::write(check, "fn main(){").unwrap();
+        registry(root.path(), &[("broken", "*.csv")]);
+
+        let registry = Registry::load(root.path(), Path::new("/data")).unwrap();
+        let err = RegistryHandler::new(registry, Sandbox::disabled(), &Cache::new(root.path()))
+            .err()
+            .unwrap();
+        assert!(err.to_string().starts_with("field \"broken\""), "{}", err);
     }
 }
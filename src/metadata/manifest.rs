@@ -0,0 +1,194 @@
+use std::{
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use dynamic_exec::{Cache, Executable, Runtime};
+use serde::Deserialize;
+
+pub const MANIFEST: &str = "field.toml";
+
+/*
+ * How the programs of a field are run, from `<field>/field.toml`:
+ *
+ *   runtime = "python"     # rust, binary, shell, python or wasm
+ *   check   = "check.py"
+ *   update  = "update.py"  # optional
+ *   execute = "execute.py"
+ *   access  = "access.py"  # optional
+ *
+ * Paths are relative to the field, and without a runtime it is guessed from
+ * the extension of every program. Fields without a manifest have a `check`,
+ * `update` and `execute` folder, and optionally an `access` folder, each with
+ * a `main.rs`, `main.wasm` or `main.wat`.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest
+{
+    pub runtime: Option<Runtime>,
+    pub check:   PathBuf,
+    pub update:  Option<PathBuf>,
+    pub execute: PathBuf,
+    pub access:  Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FieldToml
+{
+    runtime: Option<Runtime>,
+    check:   PathBuf,
+    update:  Option<PathBuf>,
+    execute: PathBuf,
+    access:  Option<PathBuf>,
+}
+
+impl Manifest
+{
+    pub fn load(field: &Path) -> std::io::Result<Self>
+    {
+        let manifest = field.join(MANIFEST);
+        if manifest.exists()
+        {
+            let toml: FieldToml = toml::from_str(&std::fs::read_to_string(&manifest)?)
+                .map_err(|e| {
+                    Error::new(ErrorKind::InvalidData, format!("{}: {}", manifest.display(), e))
+                })?;
+
+            let resolve = |path: PathBuf| existing(field.join(path));
+            return Ok(Self {
+                runtime: toml.runtime,
+                check:   resolve(toml.check)?,
+                update:  toml.update.map(resolve).transpose()?,
+                execute: resolve(toml.execute)?,
+                access:  toml.access.map(resolve).transpose()?,
+            });
+        }
+
+        if !field.join("check").is_dir()
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("metadata field {} has no {} or check", field.display(), MANIFEST),
+            ));
+        }
+        Ok(Self {
+            runtime: None,
+            check:   program(&field.join("check"))?,
+            update:  Some(program(&field.join("update"))?),
+            execute: program(&field.join("execute"))?,
+            access:  match field.join("access").is_dir()
+            {
+                true => Some(program(&field.join("access"))?),
+                false => None,
+            },
+        })
+    }
+
+    pub fn load_program(&self, path: &Path, cache: &Cache) -> std::io::Result<Box<dyn Executable>>
+    {
+        match self.runtime
+        {
+            Some(runtime) => dynamic_exec::load_with(runtime, path, cache),
+            None => dynamic_exec::load(path, cache),
+        }
+    }
+}
+
+fn existing(path: PathBuf) -> std::io::Result<PathBuf>
+{
+    match path.exists()
+    {
+        true => Ok(path),
+        false => Err(Error::new(ErrorKind::NotFound, format!("{} is missing", path.display()))),
+    }
+}
+
+// The program in a `check`, `update` or `execute` folder
+fn program(folder: &Path) -> std::io::Result<PathBuf>
+{
+    ["main.rs", "main.wasm", "main.wat"]
+        .iter()
+        .map(|name| folder.join(name))
+        .find(|path| path.exists())
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("{} has no main.rs, main.wasm or main.wat", folder.display()),
+            )
+        })
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn manifest_paths_are_relative_to_the_field()
+    {
+        let field = TempDir::new().unwrap();
+        std::fs::write(field.path().join("check.py"), "").unwrap();
+        std::fs::write(field.path().join("execute.sh"), "").unwrap();
+        std::fs::write(
+            field.path().join(MANIFEST),
+            "check = \"check.py\"\nexecute = \"execute.sh\"\n",
+        )
+        .unwrap();
+
+        let manifest = Manifest::load(field.path()).unwrap();
+        assert_eq!(manifest.runtime, None);
+        assert_eq!(manifest.check, field.path().join("check.py"));
+        assert_eq!(manifest.update, None);
+        assert_eq!(manifest.execute, field.path().join("execute.sh"));
+        assert_eq!(manifest.access, None);
+    }
+
+    #[test]
+    fn manifest_runtime()
+    {
+        let field = TempDir::new().unwrap();
+        std::fs::write(field.path().join("check"), "").unwrap();
+        std::fs::write(
+            field.path().join(MANIFEST),
+            "runtime = \"python\"\ncheck = \"check\"\nexecute = \"check\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(Manifest::load(field.path()).unwrap().runtime, Some(Runtime::Python));
+    }
+
+    #[test]
+    fn bad_manifests()
+    {
+        let field = TempDir::new().unwrap();
+        let manifest = field.path().join(MANIFEST);
+
+        std::fs::write(&manifest, "check = \"check.py\"\nexecute = \"check.py\"\n").unwrap();
+        assert_eq!(Manifest::load(field.path()).unwrap_err().kind(), ErrorKind::NotFound);
+
+        std::fs::write(field.path().join("check.py"), "").unwrap();
+        let perl = "runtime = \"perl\"\ncheck = \"check.py\"\nexecute = \"check.py\"\n";
+        std::fs::write(&manifest, perl).unwrap();
+        assert_eq!(Manifest::load(field.path()).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn folders_without_a_manifest()
+    {
+        let field = TempDir::new().unwrap();
+        for folder in ["check", "update", "execute"]
+        {
+            std::fs::create_dir(field.path().join(folder)).unwrap();
+            std::fs::write(field.path().join(folder).join("main.rs"), "fn main(){}").unwrap();
+        }
+
+        let manifest = Manifest::load(field.path()).unwrap();
+        assert_eq!(manifest.check, field.path().join("check").join("main.rs"));
+        assert_eq!(manifest.update, Some(field.path().join("update").join("main.rs")));
+        assert_eq!(manifest.access, None);
+    }
+}
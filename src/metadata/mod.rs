@@ -1,22 +1,72 @@
 mod handler;
-pub use handler::MockHandler;
+pub use handler::{NoopHandler, RegistryHandler};
+
+mod registry;
+pub use registry::{FieldEntry, Registry, REGISTRY};
 
 mod checker;
-pub use checker::{get_metadata_checker, RustChecker};
+pub use checker::{get_metadata_checker, FieldChecker};
+
+mod builtin;
+pub use builtin::{builtins_from_config, Builtin};
+
+mod manifest;
+pub use manifest::{Manifest, MANIFEST};
 
 mod dynamic;
-use std::path::Path;
+mod traversal;
+use std::{
+    collections::BTreeMap,
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 pub use dynamic::DynamicMetadata;
+pub use traversal::{ancestors, traversal_from_config, Traversal};
+pub use dynamic_exec::METADATA_PREFIX;
+use dynamic_exec::{Cache, ExecError, Request, Response, Sandbox};
+use log::info;
+
+use crate::{parse_config, policy::tag_file, try_config, BASE_PATH};
+
+// The current metadata values are readable as these xattrs on the mount
+pub const QUERY_PREFIX: &str = "gurret.meta.";
 
 #[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
 pub enum Operation
 {
-    Write,
-    Read,
     Open,
+    Read,
+    Write,
     Create,
-    // etc.
+    Rename,
+    Unlink,
+    Chmod,
+    Chown,
+    Truncate,
+    Utimens,
+    SetXattr,
+    RemoveXattr,
+    Link,
+    Symlink,
+    Mkdir,
+    Rmdir,
+    // The labels of the file were changed, see `permission::relabel`
+    Relabel,
+    Release,
+    // Asking for the current metadata of a file
+    Access,
+}
+
+impl Operation
+{
+    // Whether the operation may change the file, or what it is derived from
+    pub fn modifies(&self) -> bool
+    {
+        !matches!(self, Operation::Open | Operation::Read | Operation::Release | Operation::Access)
+    }
 }
 
 impl std::fmt::Display for Operation
@@ -27,34 +77,209 @@ impl std::fmt::Display for Operation
     }
 }
 
+/*
+ * Who triggered an operation, and on what part of the file. Fields that do
+ * not apply to the operation are left as `None`.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Context
+{
+    pub pid:      u32,
+    pub uid:      u32,
+    pub offset:   Option<i64>,
+    pub size:     Option<u64>,
+    pub old_path: Option<PathBuf>,
+    pub new_path: Option<PathBuf>,
+}
+
+impl Context
+{
+    pub fn from_request(req: &fuser::Request) -> Self
+    {
+        Self {
+            pid: req.pid(),
+            uid: req.uid(),
+            ..Self::default()
+        }
+    }
+
+    // The environment given to the metadata programs
+    pub fn env(&self, file: &Path, operation: Operation) -> Vec<(String, String)>
+    {
+        let mut env = vec![
+            ("GURRET_OPERATION".to_string(), operation.to_string()),
+            ("GURRET_PATH".to_string(), file.display().to_string()),
+            ("GURRET_PID".to_string(), self.pid.to_string()),
+            ("GURRET_UID".to_string(), self.uid.to_string()),
+        ];
+
+        if let Some(offset) = self.offset
+        {
+            env.push(("GURRET_OFFSET".to_string(), offset.to_string()));
+        }
+        if let Some(size) = self.size
+        {
+            env.push(("GURRET_SIZE".to_string(), size.to_string()));
+        }
+        if let Some(old_path) = &self.old_path
+        {
+            env.push(("GURRET_OLD_PATH".to_string(), old_path.display().to_string()));
+        }
+        if let Some(new_path) = &self.new_path
+        {
+            env.push(("GURRET_NEW_PATH".to_string(), new_path.display().to_string()));
+        }
+        env
+    }
+
+    /*
+     * The JSON request given to the metadata programs. A file that is gone,
+     * or unreadable, is sent without labels and metadata.
+     */
+    pub fn request(&self, file: &Path, operation: Operation) -> Request
+    {
+        let labels = crate::policy::read_tag(file, crate::policy::LABEL_XATTR)
+            .ok()
+            .and_then(|labels| labels.parse::<toml::Value>().ok())
+            .and_then(|labels| serde_json::to_value(labels).ok())
+            .unwrap_or_default();
+
+        let metadata = xattr::list(file)
+            .into_iter()
+            .flatten()
+            .filter_map(|name| {
+                let field = name.to_str()?.strip_prefix(METADATA_PREFIX)?.to_string();
+                let value = xattr::get(file, &name).ok()??;
+                Some((field, String::from_utf8_lossy(&value).into_owned()))
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        Request {
+            operation: operation.to_string(),
+            path: file.to_path_buf(),
+            pid: self.pid,
+            uid: self.uid,
+            offset: self.offset,
+            size: self.size,
+            old_path: self.old_path.clone(),
+            new_path: self.new_path.clone(),
+            labels,
+            metadata,
+        }
+    }
+}
+
 
-pub trait Metadata
+/*
+ * Programs get a JSON `Request` on stdin and answer with a JSON `Response`.
+ * The operation name is also passed as argv[1], and the context through the
+ * `GURRET_*` environment variables.
+ */
+pub trait Metadata: Send + Sync
 {
     type Item;
-    fn check(&self, op: Operation) -> bool;
+    fn check(
+        &self,
+        request: &Request,
+        env: &[(String, String)],
+        sandbox: &Sandbox,
+    ) -> Result<Response, ExecError>;
     fn update(&self) -> std::io::Result<()>;
-    fn access(&self) -> std::io::Result<Self::Item>;
+    // The current value of the field, if it is computed rather than stored
+    fn access(
+        &self,
+        request: &Request,
+        env: &[(String, String)],
+        sandbox: &Sandbox,
+    ) -> Result<Option<Self::Item>, ExecError>;
+}
+
+
+/*
+ * A field whose check passed, executed with the same request, environment and
+ * sandbox as the check.
+ */
+pub struct Change
+{
+    pub metadata: Arc<DynamicMetadata>,
+    pub request:  Request,
+    pub env:      Vec<(String, String)>,
+    pub sandbox:  Sandbox,
+}
+
+impl Change
+{
+    // Run the program, and store the updates it answered with on the file, if any
+    pub fn execute(&self) -> std::io::Result<bool>
+    {
+        let response = self.metadata.execute(&self.request, &self.env, &self.sandbox)?;
+        log_response(self.metadata.path(), &response);
+
+        let updated = !response.updates.is_empty();
+        for (field, value) in response.updates
+        {
+            tag_file(&self.request.path, format!("{}{}", METADATA_PREFIX, field), value)?;
+        }
+        Ok(updated)
+    }
 }
 
+pub fn log_response(program: &Path, response: &Response)
+{
+    for line in &response.log
+    {
+        info!("{}: {}", program.display(), line);
+    }
+}
 
-//
-//type RetType<'a> = Vec<Box<dyn Metadata>;
-type RetType<'a> = Vec<DynamicMetadata>;
-//type RetType = Vec<()>;
-//type RetType<'a> = FilterMap<slice::Iter<'a, RetIn>, &'static fn(&DirEntry)
-// -> RetT>;
 
+/*
+ * How long the checks of a field have taken so far, and how many of them were
+ * answered from the memo instead.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FieldTiming
+{
+    pub checks: u64,
+    pub cached: u64,
+    pub total:  Duration,
+}
 
-pub trait MetadataHandler
+/*
+ * Handlers are shared by every thread of the mount, so any state they keep
+ * must be behind a lock.
+ */
+pub trait MetadataHandler: Send + Sync
 {
     /*
      * Return the metadata changes (if any) given an operation and a file.
      * The handler should run the `check` function for each field in addition
-     * to some standard metadatas, like access(time).
+     * to some standard metadatas, like access(time). Only the fields whose
+     * check passed are returned, a file without any fields gives an empty vec.
      */
-    //fn changes(&self, file: &Path, operation: Operation) -> Option<Vec<Box<dyn
-    // Metadata>>>;
-    fn changes(&mut self, file: &Path, operation: Operation) -> Vec<DynamicMetadata>;
+    fn changes(
+        &self,
+        file: &Path,
+        operation: Operation,
+        context: &Context,
+    ) -> std::io::Result<Vec<Change>>;
+
+    /*
+     * The current metadata of a file, by field. These are the values stored
+     * on the file, where the fields with an access program are asked instead.
+     */
+    fn query(&self, file: &Path, context: &Context) -> std::io::Result<BTreeMap<String, String>>
+    {
+        Ok(context.request(file, Operation::Access).metadata)
+    }
+
+    // Forget what is remembered about `file`, as it or a field has changed
+    fn invalidate(&self, _file: &Path) {}
+
+    fn timings(&self) -> BTreeMap<String, FieldTiming>
+    {
+        BTreeMap::new()
+    }
 
 
     /*
@@ -63,3 +288,64 @@ pub trait MetadataHandler
      */
     fn update_remote(&self);
 }
+
+/*
+ * Pick the handler given by `METADATA_HANDLER` in the config, either
+ * `registry` (the default) or `none`. The registry is read from
+ * `<METADATA>/metadata.toml`, with `METADATA` defaulting to `<TARGET>/metadata`,
+ * and every field is compiled into `COMPILE_CACHE` before mounting. A broken
+ * registry or field is an error, and stops the mount.
+ */
+pub fn handler_from_config() -> std::io::Result<Arc<dyn MetadataHandler>>
+{
+    match try_config("METADATA_HANDLER").as_deref()
+    {
+        None | Some("registry") =>
+        {
+            let root = try_config("METADATA").unwrap_or_else(|| format!("{}/metadata", *BASE_PATH));
+            let registry = Registry::load(Path::new(&root), Path::new(&*BASE_PATH)).map_err(|e| {
+                Error::new(e.kind(), format!("could not load the metadata registry: {}", e))
+            })?;
+            let sandbox = sandbox_from_config()?;
+            Ok(Arc::new(RegistryHandler::new(registry, sandbox, &cache_from_config())?))
+        },
+        Some("none") => Ok(Arc::new(NoopHandler)),
+        Some(other) => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("un-recognized METADATA_HANDLER {:?}", other),
+        )),
+    }
+}
+
+/*
+ * Metadata programs are sandboxed unless `SANDBOX=off`. `SANDBOX_UID` and
+ * `SANDBOX_TIMEOUT` (in seconds) override the defaults.
+ */
+pub fn sandbox_from_config() -> std::io::Result<Sandbox>
+{
+    if try_config("SANDBOX").as_deref() == Some("off")
+    {
+        return Ok(Sandbox::disabled());
+    }
+
+    let mut sandbox = Sandbox::default();
+    if let Some(uid) = parse_config("SANDBOX_UID", "a uid")?
+    {
+        sandbox.uid = Some(uid);
+        sandbox.gid = Some(uid);
+    }
+    if let Some(timeout) = parse_config("SANDBOX_TIMEOUT", "a number of seconds")?
+    {
+        sandbox.timeout = Some(Duration::from_secs(timeout));
+    }
+    Ok(sandbox)
+}
+
+/*
+ * Where compiled metadata programs are kept between mounts, `COMPILE_CACHE` or
+ * the user's cache directory.
+ */
+pub fn cache_from_config() -> Cache
+{
+    try_config("COMPILE_CACHE").map(Cache::new).unwrap_or_default()
+}
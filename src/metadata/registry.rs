@@ -0,0 +1,289 @@
+use std::{
+    collections::HashSet,
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::Deserialize;
+
+use crate::metadata::Manifest;
+
+pub const REGISTRY: &str = "metadata.toml";
+
+/*
+ * Which fields apply to which files, from `<root>/metadata.toml`:
+ *
+ *   [[field]]
+ *   name  = "owner"
+ *   files = ["*.csv", "datasets/census.json"]
+ *   path  = "owner"   # the folder of the field, defaults to the name
+ *
+ * Patterns are globs matched against the whole path of a file, relative to
+ * `base` unless they are absolute, where `*` stays within a folder and `**`
+ * does not. A field can be shared by many files, and `a.csv` is never
+ * mistaken for `a.json`. Fields apply in the order given.
+ */
+pub struct Registry
+{
+    pub fields: Vec<FieldEntry>,
+}
+
+pub struct FieldEntry
+{
+    pub name:     String,
+    pub folder:   PathBuf,
+    pub manifest: Manifest,
+    files:        GlobSet,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegistryToml
+{
+    #[serde(default)]
+    field: Vec<FieldToml>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FieldToml
+{
+    name:  String,
+    files: Vec<String>,
+    path:  Option<PathBuf>,
+}
+
+impl Registry
+{
+    pub fn empty() -> Self
+    {
+        Self {
+            fields: Vec::new()
+        }
+    }
+
+    /*
+     * A missing registry has no fields. Otherwise every problem with it is
+     * reported at once, one per line.
+     */
+    pub fn load(root: &Path, base: &Path) -> std::io::Result<Self>
+    {
+        let path = root.join(REGISTRY);
+        if !path.exists()
+        {
+            return Ok(Self::empty());
+        }
+
+        let toml: RegistryToml = toml::from_str(&std::fs::read_to_string(&path)?)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+
+        let mut fields = Vec::new();
+        let mut problems = Vec::new();
+        let mut names = HashSet::new();
+
+        for field in toml.field
+        {
+            if !names.insert(field.name.clone())
+            {
+                problems.push(format!("field {:?} is declared twice", field.name));
+                continue;
+            }
+            match FieldEntry::new(root, base, field)
+            {
+                Ok(entry) => fields.push(entry),
+                Err(problem) => problems.push(problem),
+            }
+        }
+
+        match problems.is_empty()
+        {
+            true => Ok(Self {
+                fields,
+            }),
+            false => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{}:\n  {}", path.display(), problems.join("\n  ")),
+            )),
+        }
+    }
+
+    pub fn fields_of<'a>(&'a self, file: &'a Path) -> impl Iterator<Item = &'a FieldEntry> + 'a
+    {
+        self.fields.iter().filter(move |field| field.applies_to(file))
+    }
+}
+
+impl FieldEntry
+{
+    fn new(root: &Path, base: &Path, field: FieldToml) -> Result<Self, String>
+    {
+        let problem = |e: &dyn std::fmt::Display| format!("field {:?}: {}", field.name, e);
+
+        if field.files.is_empty()
+        {
+            return Err(problem(&"matches no files"));
+        }
+
+        let mut files = GlobSetBuilder::new();
+        for pattern in &field.files
+        {
+            let pattern = match Path::new(pattern).is_absolute()
+            {
+                true => pattern.clone(),
+                false => format!("{}/{}", globset::escape(&base.to_string_lossy()), pattern),
+            };
+            // `*` stays within a folder, `**` does not
+            let glob = GlobBuilder::new(&pattern)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| problem(&e))?;
+            files.add(glob);
+        }
+        let files = files.build().map_err(|e| problem(&e))?;
+
+        let folder = root.join(field.path.as_deref().unwrap_or_else(|| Path::new(&field.name)));
+        if !folder.is_dir()
+        {
+            return Err(problem(&format!("{} is not a folder", folder.display())));
+        }
+        let manifest = Manifest::load(&folder).map_err(|e| problem(&e))?;
+
+        Ok(Self {
+            name: field.name,
+            folder,
+            manifest,
+            files,
+        })
+    }
+
+    pub fn applies_to(&self, file: &Path) -> bool
+    {
+        self.files.is_match(file)
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn field(root: &Path, name: &str)
+    {
+        let folder = root.join(name);
+        std::fs::create_dir_all(&folder).unwrap();
+        std::fs::write(folder.join("check.sh"), "").unwrap();
+        std::fs::write(folder.join("field.toml"), "check = \"check.sh\"\nexecute = \"check.sh\"\n")
+            .unwrap();
+    }
+
+    fn names<'a>(registry: &'a Registry, file: &'a str) -> Vec<&'a str>
+    {
+        registry.fields_of(Path::new(file)).map(|f| f.name.as_str()).collect()
+    }
+
+    #[test]
+    fn missing_registry_is_empty()
+    {
+        let root = TempDir::new().unwrap();
+        assert!(Registry::load(root.path(), Path::new("/data")).unwrap().fields.is_empty());
+    }
+
+    #[test]
+    fn matches_whole_paths()
+    {
+        let root = TempDir::new().unwrap();
+        field(root.path(), "csv");
+        field(root.path(), "reports");
+        field(root.path(), "census");
+        std::fs::write(
+            root.path().join(REGISTRY),
+            r#"
+            [[field]]
+            name = "csv"
+            files = ["*.csv"]
+
+            [[field]]
+            name = "reports"
+            files = ["reports/**"]
+
+            [[field]]
+            name = "census"
+            files = ["/data/census.json", "reports/a.csv"]
+            "#,
+        )
+        .unwrap();
+
+        let registry = Registry::load(root.path(), Path::new("/data")).unwrap();
+        assert_eq!(names(&registry, "/data/a.csv"), ["csv"]);
+        assert!(names(&registry, "/data/a.json").is_empty());
+        assert!(names(&registry, "/data/sub/a.csv").is_empty());
+        assert_eq!(names(&registry, "/data/reports/2023/q1.txt"), ["reports"]);
+        assert_eq!(names(&registry, "/data/reports/a.csv"), ["reports", "census"]);
+        assert_eq!(names(&registry, "/data/census.json"), ["census"]);
+    }
+
+    #[test]
+    fn shared_folder()
+    {
+        let root = TempDir::new().unwrap();
+        field(root.path(), "shared");
+        std::fs::write(
+            root.path().join(REGISTRY),
+            r#"
+            [[field]]
+            name = "a"
+            files = ["a.csv"]
+            path = "shared"
+
+            [[field]]
+            name = "b"
+            files = ["b.csv"]
+            path = "shared"
+            "#,
+        )
+        .unwrap();
+
+        let registry = Registry::load(root.path(), Path::new("/data")).unwrap();
+        assert_eq!(registry.fields[0].folder, registry.fields[1].folder);
+    }
+
+    #[test]
+    fn every_problem_is_reported()
+    {
+        let root = TempDir::new().unwrap();
+        field(root.path(), "ok");
+        std::fs::write(
+            root.path().join(REGISTRY),
+            r#"
+            [[field]]
+            name = "ok"
+            files = ["*.csv"]
+
+            [[field]]
+            name = "ok"
+            files = ["*.json"]
+
+            [[field]]
+            name = "missing"
+            files = ["*.txt"]
+
+            [[field]]
+            name = "nothing"
+            files = []
+            path = "ok"
+            "#,
+        )
+        .unwrap();
+
+        let err = Registry::load(root.path(), Path::new("/data")).err().unwrap();
+        let message = err.to_string();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(message.contains("\"ok\" is declared twice"), "{}", message);
+        assert!(message.contains("\"missing\""), "{}", message);
+        assert!(message.contains("\"nothing\": matches no files"), "{}", message);
+    }
+}
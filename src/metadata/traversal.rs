@@ -0,0 +1,284 @@
+use std::{
+    collections::HashSet,
+    io::Error,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
+    time::Instant,
+};
+
+use log::debug;
+
+use crate::{
+    metadata::{Change, Context, MetadataHandler, Operation},
+    parse_config,
+    pool::WorkerPool,
+};
+
+/*
+ * `file` followed by everything it was derived from, nearest first, where
+ * `parents` gives what a file was derived from directly. Every file is only
+ * given once, even when it is reached through many parents or a loop.
+ */
+pub fn ancestors(file: &Path, mut parents: impl FnMut(&Path) -> Vec<PathBuf>) -> Vec<PathBuf>
+{
+    let mut seen = HashSet::from([file.to_path_buf()]);
+    let mut lineage = vec![file.to_path_buf()];
+
+    let mut i = 0;
+    while let Some(file) = lineage.get(i).cloned()
+    {
+        for parent in parents(&file)
+        {
+            if seen.insert(parent.clone())
+            {
+                lineage.push(parent);
+            }
+        }
+        i += 1;
+    }
+    lineage
+}
+
+
+/*
+ * Runs the checks of a whole lineage on a pool of `workers` threads, started
+ * once and shared by every event. The checks of one file do not depend on
+ * those of another, so the files are handed out to the workers as they become
+ * free, and the changes come back in the order of the files.
+ */
+#[derive(Clone)]
+pub struct Traversal
+{
+    pool: Arc<WorkerPool>,
+}
+
+impl Traversal
+{
+    pub fn new(workers: usize) -> Self
+    {
+        Self {
+            pool: Arc::new(WorkerPool::new(workers)),
+        }
+    }
+
+    pub fn changes(
+        &self,
+        handler: Arc<dyn MetadataHandler>,
+        files: &[PathBuf],
+        operation: Operation,
+        context: &Context,
+    ) -> std::io::Result<Vec<Change>>
+    {
+        let start = Instant::now();
+        let workers = self.pool.size().min(files.len());
+
+        let results = match workers
+        {
+            0 | 1 => files.iter().map(|file| handler.changes(file, operation, context)).collect(),
+            _ =>
+            {
+                let (sender, receiver) = mpsc::channel();
+                for (i, file) in files.iter().enumerate()
+                {
+                    let (sender, handler) = (sender.clone(), Arc::clone(&handler));
+                    let (file, context) = (file.clone(), context.clone());
+                    self.pool.execute(move || {
+                        let _ = sender.send((i, handler.changes(&file, operation, &context)));
+                    });
+                }
+                drop(sender);
+
+                // A check that panicked never sends, and is missing once the others are in
+                let mut results = files.iter().map(|_| None).collect::<Vec<_>>();
+                for (i, changes) in receiver
+                {
+                    results[i] = Some(changes);
+                }
+                results
+                    .into_iter()
+                    .zip(files)
+                    .map(|(result, file)| {
+                        result.unwrap_or_else(|| {
+                            Err(Error::other(format!("checking {} panicked", file.display())))
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            },
+        };
+
+        let mut changes = Vec::new();
+        for result in results
+        {
+            changes.extend(result?);
+        }
+        debug!(
+            "{} of {} and {} ancestors took {:?} on {} workers",
+            operation,
+            files.first().map(|f| f.display().to_string()).unwrap_or_default(),
+            files.len().saturating_sub(1),
+            start.elapsed(),
+            workers
+        );
+        Ok(changes)
+    }
+}
+
+/*
+ * The number of workers is `METADATA_WORKERS`, or one per CPU if not set.
+ */
+pub fn traversal_from_config() -> std::io::Result<Traversal>
+{
+    let workers = match parse_config("METADATA_WORKERS", "a number")?
+    {
+        Some(workers) => workers,
+        None => std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+    };
+    Ok(Traversal::new(workers))
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use std::{
+        io::ErrorKind,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+        time::Duration,
+    };
+
+    use super::*;
+
+    // Takes a while to check, and remembers how many checks ran at once
+    #[derive(Default)]
+    struct SlowHandler
+    {
+        running: AtomicUsize,
+        most:    AtomicUsize,
+        checked: Mutex<Vec<PathBuf>>,
+        threads: Mutex<HashSet<std::thread::ThreadId>>,
+    }
+
+    impl MetadataHandler for SlowHandler
+    {
+        fn changes(
+            &self,
+            file: &Path,
+            _operation: Operation,
+            _context: &Context,
+        ) -> std::io::Result<Vec<Change>>
+        {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most.fetch_max(running, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(20));
+            self.running.fetch_sub(1, Ordering::SeqCst);
+
+            self.checked.lock().unwrap().push(file.to_path_buf());
+            self.threads.lock().unwrap().insert(std::thread::current().id());
+            match file.ends_with("broken")
+            {
+                true => Err(Error::new(ErrorKind::InvalidData, "broken")),
+                false => Ok(Vec::new()),
+            }
+        }
+
+        fn update_remote(&self) {}
+    }
+
+    fn chain(files: &[&str]) -> Vec<PathBuf>
+    {
+        files.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn ancestors_follow_parents()
+    {
+        let parents = |file: &Path| match file.to_str().unwrap()
+        {
+            "/c" => chain(&["/b"]),
+            "/b" => chain(&["/a"]),
+            _ => Vec::new(),
+        };
+        assert_eq!(ancestors(Path::new("/c"), parents), chain(&["/c", "/b", "/a"]));
+        assert_eq!(ancestors(Path::new("/a"), parents), chain(&["/a"]));
+    }
+
+    #[test]
+    fn shared_ancestors_are_given_once()
+    {
+        let parents = |file: &Path| match file.to_str().unwrap()
+        {
+            "/d" => chain(&["/b", "/c"]),
+            "/b" | "/c" => chain(&["/a"]),
+            _ => Vec::new(),
+        };
+        assert_eq!(ancestors(Path::new("/d"), parents), chain(&["/d", "/b", "/c", "/a"]));
+    }
+
+    #[test]
+    fn ancestors_stop_at_a_loop()
+    {
+        let parents = |file: &Path| match file.to_str().unwrap()
+        {
+            "/a" => chain(&["/b"]),
+            _ => chain(&["/a"]),
+        };
+        assert_eq!(ancestors(Path::new("/a"), parents), chain(&["/a", "/b"]));
+    }
+
+    #[test]
+    fn lineage_is_checked_in_parallel()
+    {
+        let files = chain(&["/d", "/c", "/b", "/a"]);
+        let handler = Arc::new(SlowHandler::default());
+
+        let traversal = Traversal::new(4);
+        traversal.changes(handler.clone(), &files, Operation::Open, &Context::default()).unwrap();
+        assert!(handler.most.load(Ordering::SeqCst) > 1);
+
+        let mut checked = handler.checked.lock().unwrap().clone();
+        checked.sort();
+        assert_eq!(checked, chain(&["/a", "/b", "/c", "/d"]));
+    }
+
+    #[test]
+    fn one_worker_is_serial()
+    {
+        let files = chain(&["/b", "/a"]);
+        let handler = Arc::new(SlowHandler::default());
+
+        let traversal = Traversal::new(1);
+        traversal.changes(handler.clone(), &files, Operation::Open, &Context::default()).unwrap();
+        assert_eq!(handler.most.load(Ordering::SeqCst), 1);
+        assert_eq!(*handler.checked.lock().unwrap(), files);
+    }
+
+    #[test]
+    fn workers_are_kept_between_events()
+    {
+        let files = chain(&["/d", "/c", "/b", "/a"]);
+        let handler = Arc::new(SlowHandler::default());
+
+        let traversal = Traversal::new(2);
+        let context = Context::default();
+        for _ in 0..3
+        {
+            traversal.changes(handler.clone(), &files, Operation::Open, &context).unwrap();
+        }
+        assert_eq!(handler.checked.lock().unwrap().len(), 12);
+        assert!(handler.threads.lock().unwrap().len() <= 2);
+    }
+
+    #[test]
+    fn failing_ancestor_fails_the_traversal()
+    {
+        let files = chain(&["/c", "/broken", "/a"]);
+        let err = Traversal::new(2)
+            .changes(Arc::new(SlowHandler::default()), &files, Operation::Open, &Context::default())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -15,40 +15,243 @@ const MAX_FILE_NAME_LENGTH: usize = 255;
 
 use std::{ffi::OsString, path::Path};
 
+use log::{debug, trace};
+
 //use serde_derive::Deserialize;
 use toml::Value;
 
-use crate::{lattice::*, policy::*, XmpFS};
+use crate::{
+    audit::{self, Decision},
+    config::get_program_name,
+    lattice::*,
+    policy::*,
+    XmpFS,
+};
 
-fn get_file_lvalue(path: &str, ltype: &LatticeType) -> Result<LatticeValue, i32>
+// The label in a `user.label`, if it has one that is understood
+fn lvalue_of(tag: &Value) -> Option<LatticeValue>
 {
-    let tag = match read_tag(path, "user.label").map(|s| s.parse::<Value>())
+    // Either a list, or the one label as written by `set_output_label`
+    let table = match tag.get("labels")
+    {
+        // just pick first for now
+        Some(Value::Array(array)) => array.first(),
+        Some(table @ Value::Table(_)) => Some(table),
+        _ => None,
+    }?;
+
+    match table.get("name").and_then(Value::as_str)
     {
-        Ok(Ok(tag)) => tag,
+        Some("linear") => table.get("value").and_then(Value::as_integer).map(LatticeValue::Number),
+        name =>
+        {
+            debug!("label {:?} is not understood", name);
+            None
+        },
+    }
+}
+
+// The label `path` has itself
+fn own_lvalue(path: &Path) -> Option<LatticeValue>
+{
+    match read_tag(path, LABEL_XATTR).map(|s| s.parse::<Value>())
+    {
+        Ok(Ok(tag)) => lvalue_of(&tag),
         e =>
         {
-            println!("no label {:?}", e);
-            return Ok(ltype.default());
+            trace!("no label on {}: {:?}", path.display(), e);
+            None
         },
+    }
+}
+
+// The strictest label of `paths`, if any of them has one
+fn strictest(
+    paths: impl Iterator<Item = impl AsRef<Path>>,
+    ltype: &LatticeType,
+) -> Option<LatticeValue>
+{
+    let lattice = create_lattice(ltype);
+    paths.filter_map(|path| own_lvalue(path.as_ref())).reduce(|bound, label| {
+        match lattice.compare(&bound, &label).is_le()
+        {
+            true => bound,
+            false => label,
+        }
+    })
+}
+
+/*
+ * A file is never more public than the folders it is in. Without a label of
+ * its own it has that of the nearest labelled folder, and with no labels
+ * anywhere it has the default.
+ */
+fn get_file_lvalue(path: &str, ltype: &LatticeType) -> Result<LatticeValue, i32>
+{
+    Ok(strictest(up_to_target(Path::new(path)), ltype).unwrap_or_else(|| ltype.default()))
+}
+
+// Whether `path`, or a folder it is in, has a label
+pub fn labelled(path: impl AsRef<Path>) -> bool
+{
+    strictest(up_to_target(path.as_ref()), &LatticeType::LinearNumber).is_some()
+}
+
+// The label `path` has, from itself or its folders
+pub fn file_label(path: impl AsRef<Path>) -> LatticePair
+{
+    let ltype = LatticeType::LinearNumber;
+    let label = strictest(up_to_target(path.as_ref()), &ltype).unwrap_or_else(|| ltype.default());
+    (ltype, label)
+}
+
+/*
+ * `path` was written with data of label `from`, so it is made at least as
+ * secret as that.
+ */
+pub fn taint(path: impl AsRef<Path>, (ltype, from): &LatticePair) -> std::io::Result<()>
+{
+    let path = path.as_ref();
+    let (_, label) = file_label(path);
+    match create_lattice(ltype).compare(from, &label).is_lt()
+    {
+        true => set_policy(path, format!("labels = {{name=\"{}\",value={}}}", ltype, from)),
+        false => Ok(()),
+    }
+}
+
+// The bound the folders above `path` put on its label
+fn folder_bound(path: &Path) -> Option<LatticeValue>
+{
+    let folders = up_to_target(path).skip(1);
+    strictest(folders, &LatticeType::LinearNumber)
+}
+
+// Whether `label` may be given to `path`, without making it more public than its folders
+pub fn within_bound(path: impl AsRef<Path>, label: &str) -> bool
+{
+    let label = match label.parse::<Value>().ok().as_ref().and_then(lvalue_of)
+    {
+        Some(label) => label,
+        None => return false,
     };
+    match folder_bound(path.as_ref())
+    {
+        Some(bound) => create_lattice(&LatticeType::LinearNumber).compare(&label, &bound).is_le(),
+        None => true,
+    }
+}
 
+/*
+ * Before `from` is moved to `to`, give it the label it has from its folders
+ * if those it moves into are more public, so that moving a file never makes
+ * it more public.
+ */
+pub fn keep_bound(from: impl AsRef<Path>, to: impl AsRef<Path>) -> std::io::Result<()>
+{
+    let ltype = LatticeType::LinearNumber;
+    let (from, to) = (from.as_ref(), to.as_ref());
+    let lattice = create_lattice(&ltype);
 
-    if let Some(Value::Array(array)) = tag.get("labels")
+    let label = match strictest(up_to_target(from), &ltype)
     {
-        // just pick first for now
-        let table = &array[0];
-        let name = table.get("name").unwrap().as_str().unwrap();
-        match name
+        Some(label) => label,
+        None => return Ok(()),
+    };
+    let moved = [own_lvalue(from), folder_bound(to)].into_iter().flatten().reduce(|a, b| {
+        match lattice.compare(&a, &b).is_le()
         {
-            "linear" => Ok(LatticeValue::Number(table.get("value").unwrap().as_integer().unwrap())),
-            _ => panic!("un-recognized lattice type"),
+            true => a,
+            false => b,
         }
+    });
+
+    match moved
+    {
+        Some(moved) if lattice.compare(&moved, &label).is_le() => Ok(()),
+        _ => set_policy(from, format!("labels = {{name=\"{}\",value={}}}", ltype, label)),
     }
-    else
+}
+
+/*
+ * Give `path` the labels in `label`, and everything in it as well if it is a
+ * folder and `recursive` is set. The paths that were relabelled are given
+ * back, folders before what is in them.
+ */
+pub fn relabel(
+    path: impl AsRef<Path>,
+    label: &str,
+    recursive: bool,
+) -> std::io::Result<Vec<std::path::PathBuf>>
+{
+    use std::io::{Error, ErrorKind};
+
+    let path = path.as_ref();
+    if label.parse::<Value>().ok().as_ref().and_then(lvalue_of).is_none()
     {
-        // No lattice present, just assume linear lattice with val = 3
-        Ok(LatticeValue::Number(3))
+        return Err(Error::new(ErrorKind::InvalidInput, format!("not a label: {:?}", label)));
     }
+    if !within_bound(path, label)
+    {
+        return Err(Error::new(ErrorKind::PermissionDenied, "more public than its folder"));
+    }
+
+    let mut relabelled = vec![path.to_path_buf()];
+    let mut i = 0;
+    while let Some(path) = relabelled.get(i).cloned()
+    {
+        set_policy(&path, label.to_string())?;
+        if recursive && std::fs::symlink_metadata(&path)?.is_dir()
+        {
+            for entry in std::fs::read_dir(&path)?
+            {
+                let entry = entry?;
+                let kind = entry.file_type()?;
+                if kind.is_file() || kind.is_dir()
+                {
+                    relabelled.push(entry.path());
+                }
+            }
+        }
+        i += 1;
+    }
+    Ok(relabelled)
+}
+
+
+// Whether a program of `clearance` may read `path`
+pub fn can_read(path: impl AsRef<Path>, (ltype, clearance): &LatticePair) -> bool
+{
+    let path = match path.as_ref().to_str()
+    {
+        Some(path) => path,
+        None => return false,
+    };
+    match get_file_lvalue(path, ltype)
+    {
+        Ok(label) => create_lattice(ltype).compare(clearance, &label).is_le(),
+        Err(_) => false,
+    }
+}
+
+// Whether `clearance` may read `path`, and the label of `path` it was compared with
+pub fn readable(path: &Path, (ltype, clearance): &LatticePair) -> (bool, LatticeValue)
+{
+    let label = strictest(up_to_target(path), ltype).unwrap_or_else(|| ltype.default());
+    (create_lattice(ltype).compare(clearance, &label).is_le(), label)
+}
+
+/*
+ * Whether the process behind `req`, of `clearance`, may read `path` for
+ * `operation`, as it is recorded in the audit log.
+ */
+pub fn may_read(req: &fuser::Request, path: &Path, operation: &str, clearance: &LatticePair)
+    -> bool
+{
+    let (allowed, label) = readable(path, clearance);
+    let decision = Decision::new(req.pid(), req.uid(), path, operation, allowed);
+    audit::record(decision.compared(format!("{} {}", clearance.0, clearance.1), label));
+    allowed
 }
 
 
@@ -174,14 +377,15 @@ impl XmpFS
         _request_type: RequestType,
     ) -> Result<i32, i32>
     {
-        let program = self.programs.get(&req.pid()).unwrap();
+        let tracked = self.tracked.lock().expect("getting lock");
+        let program = tracked.programs.get(&req.pid()).unwrap();
 
         let program_label = &program.confidentiality;
         let lattice_type = &program_label.0;
 
         let lattice = create_lattice(lattice_type);
 
-        let full_path = self.inode_to_path.get(&ino).unwrap();
+        let full_path = self.inodes.path(ino).unwrap();
         let file_label = get_file_lvalue(full_path.to_str().unwrap(), lattice_type)?;
 
 
@@ -192,6 +396,20 @@ impl XmpFS
         }
     }
 
+    // The clearance of the process behind `req`, by the program it runs
+    pub fn clearance(&self, req: &fuser::Request) -> LatticePair
+    {
+        let label = get_program_name(req).and_then(|name| self.get_confidentiality_label(name));
+        match label
+        {
+            Some(LabelResponse::Confidentiality(pair)) => pair,
+            Some(LabelResponse::Gate {
+                confidentiality, ..
+            }) => confidentiality,
+            None => lattice_pair_default(),
+        }
+    }
+
     pub fn get_confidentiality_label(&self, program: OsString) -> Option<LabelResponse>
     {
         if self.known_programs.contains(&program)
@@ -214,13 +432,13 @@ impl XmpFS
     pub fn get_file_label(&self, path: impl AsRef<Path>) -> Option<LabelResponse>
     {
         let path = path.as_ref().file_stem().unwrap().to_os_string();
-        println!("{:?}", path);
+        debug!("label of program {:?}", path);
         self.get_confidentiality_label(path)
     }
 
     fn _get_file_label(&self, path: impl AsRef<Path>) -> Option<LabelResponse>
     {
-        let tag = match read_tag(path, "user.label").map(|s| s.parse::<Value>())
+        let tag = match read_tag(path, LABEL_XATTR).map(|s| s.parse::<Value>())
         {
             Ok(Ok(tag)) => tag,
             _ =>
@@ -254,3 +472,151 @@ impl XmpFS
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests
+{
+    use std::io::ErrorKind;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn clearance_reaches_down_to_its_level()
+    {
+        let root = TempDir::new().unwrap();
+        let (secret, public, plain) =
+            (root.path().join("secret"), root.path().join("public"), root.path().join("plain"));
+        for file in [&secret, &public, &plain]
+        {
+            std::fs::write(file, "").unwrap();
+        }
+        set_output_label(&secret, (LatticeType::LinearNumber, LatticeValue::Number(1)));
+        tag_file(&public, LABEL_XATTR, "labels = [{name=\"linear\",value=5}]").unwrap();
+
+        let clearance = |n| (LatticeType::LinearNumber, LatticeValue::Number(n));
+        assert!(can_read(&secret, &clearance(1)));
+        assert!(!can_read(&secret, &clearance(3)));
+        assert!(can_read(&public, &clearance(3)));
+        assert!(can_read(&plain, &clearance(3)));
+        assert!(!can_read(&plain, &clearance(4)));
+    }
+
+    fn label(n: i64) -> String
+    {
+        format!("labels = {{name=\"linear\",value={}}}", n)
+    }
+
+    #[test]
+    fn folders_bound_what_is_in_them()
+    {
+        let root = TempDir::new().unwrap();
+        let folder = root.path().join("folder");
+        std::fs::create_dir(&folder).unwrap();
+        let file = folder.join("file");
+        std::fs::write(&file, "").unwrap();
+        set_policy(&folder, label(2)).unwrap();
+
+        let clearance = |n| (LatticeType::LinearNumber, LatticeValue::Number(n));
+        assert!(!can_read(&file, &clearance(3)));
+        assert!(can_read(&file, &clearance(2)));
+
+        // A label of its own can make it more secret, but not more public
+        assert!(within_bound(&file, &label(1)));
+        assert!(!within_bound(&file, &label(3)));
+        assert!(!within_bound(&file, "labels = 1"));
+        set_policy(&file, label(3)).unwrap();
+        assert!(!can_read(&file, &clearance(3)));
+    }
+
+    #[test]
+    fn folders_above_the_target_and_odd_labels_do_not_count()
+    {
+        let root = TempDir::new().unwrap();
+        let target = root.path().join("target");
+        std::fs::create_dir(&target).unwrap();
+        let file = target.join("file");
+        std::fs::write(&file, "").unwrap();
+        set_policy(root.path(), label(1)).unwrap();
+        set_target(&target);
+
+        assert!(!labelled(&file));
+        assert!(within_bound(&file, &label(3)));
+
+        for odd in ["labels = {name=\"other\",value=1}", "labels = {name=\"linear\"}", "labels = 1"]
+        {
+            set_policy(&file, odd.to_string()).unwrap();
+            assert!(!labelled(&file));
+            assert!(!within_bound(&file, odd));
+        }
+    }
+
+    #[test]
+    fn moved_files_keep_their_label()
+    {
+        let root = TempDir::new().unwrap();
+        let (secret, public) = (root.path().join("secret"), root.path().join("public"));
+        std::fs::create_dir(&secret).unwrap();
+        std::fs::create_dir(&public).unwrap();
+        set_policy(&secret, label(1)).unwrap();
+        let file = secret.join("file");
+        std::fs::write(&file, "").unwrap();
+
+        let moved = public.join("file");
+        keep_bound(&file, &moved).unwrap();
+        std::fs::rename(&file, &moved).unwrap();
+        assert!(!can_read(&moved, &(LatticeType::LinearNumber, LatticeValue::Number(3))));
+
+        // Nothing to keep when moving into a folder at least as secret
+        let back = secret.join("back");
+        std::fs::write(&back, "").unwrap();
+        keep_bound(&back, secret.join("again")).unwrap();
+        assert_eq!(read_tag(&back, LABEL_XATTR).unwrap(), "");
+    }
+
+    #[test]
+    fn relabel_recursively()
+    {
+        let root = TempDir::new().unwrap();
+        let folder = root.path().join("folder");
+        std::fs::create_dir_all(folder.join("sub")).unwrap();
+        std::fs::write(folder.join("sub").join("file"), "").unwrap();
+        std::os::unix::fs::symlink("/nowhere", folder.join("link")).unwrap();
+
+        assert_eq!(relabel(&folder, &label(2), false).unwrap(), [folder.clone()]);
+        let relabelled = relabel(&folder, &label(1), true).unwrap();
+        let file = folder.join("sub").join("file");
+        assert_eq!(relabelled, [folder.clone(), folder.join("sub"), file]);
+        for path in &relabelled
+        {
+            assert_eq!(read_tag(path, LABEL_XATTR).unwrap(), label(1));
+        }
+
+        let kind = |e: std::io::Error| e.kind();
+        let sub = folder.join("sub");
+        assert_eq!(relabel(&sub, &label(3), true).map_err(kind), Err(ErrorKind::PermissionDenied));
+        assert_eq!(relabel(&sub, "labels", true).map_err(kind), Err(ErrorKind::InvalidInput));
+    }
+
+    #[test]
+    fn written_files_are_tainted()
+    {
+        let root = TempDir::new().unwrap();
+        let (file, plain) = (root.path().join("file"), root.path().join("plain"));
+        std::fs::write(&file, "").unwrap();
+        std::fs::write(&plain, "").unwrap();
+        set_policy(&plain, label(2)).unwrap();
+
+        assert!(!labelled(&file));
+        let secret = (LatticeType::LinearNumber, LatticeValue::Number(1));
+        taint(&file, &secret).unwrap();
+        assert!(labelled(&file));
+        assert!(!can_read(&file, &(LatticeType::LinearNumber, LatticeValue::Number(2))));
+
+        // Never made more public
+        taint(&plain, &lattice_pair_default()).unwrap();
+        assert_eq!(read_tag(&plain, LABEL_XATTR).unwrap(), label(2));
+    }
+}
@@ -1,4 +1,31 @@
-use std::{ffi::OsStr, path::Path};
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+// The extended attribute holding the labels of a file
+pub const LABEL_XATTR: &str = "user.label";
+// The labels given to what is made in a folder
+pub const DEFAULT_LABEL_XATTR: &str = "user.label.default";
+
+static TARGET: OnceLock<PathBuf> = OnceLock::new();
+
+// The folder the labels are kept in, the TARGET, once it is known
+pub fn set_target(target: impl AsRef<Path>)
+{
+    let _ = TARGET.set(target.as_ref().to_path_buf());
+}
+
+/*
+ * `path` and the folders it is in, as far up as the TARGET. The folders above
+ * it are not labelled by the mount, and do not bound what is in it.
+ */
+pub fn up_to_target(path: &Path) -> impl Iterator<Item = &Path>
+{
+    let target = TARGET.get().filter(|target| path.starts_with(target));
+    path.ancestors().take_while(move |folder| target.is_none_or(|t| folder.starts_with(t)))
+}
 
 
 /*const POLICY_STR: &str = "user.policy";
@@ -28,7 +55,37 @@ pub fn tag_file(
 
 pub fn set_policy(file: impl AsRef<Path>, labels: String) -> std::io::Result<()>
 {
-    tag_file(file, "user.label", labels)
+    tag_file(file, LABEL_XATTR, labels)
+}
+
+/*
+ * Give `new` the default labels of its folder, if it has any. A new folder
+ * passes them on, in turn, to what is made in it.
+ */
+pub fn inherit_labels(new: impl AsRef<Path>) -> std::io::Result<()>
+{
+    let new = new.as_ref();
+
+    // Only files and folders can have labels
+    let kind = std::fs::symlink_metadata(new)?.file_type();
+    if !(kind.is_file() || kind.is_dir())
+    {
+        return Ok(());
+    }
+
+    let default = match new.parent().map(|parent| xattr::get(parent, DEFAULT_LABEL_XATTR))
+    {
+        Some(Ok(Some(default))) => default,
+        Some(Err(e)) => return Err(e),
+        _ => return Ok(()),
+    };
+
+    tag_file(new, LABEL_XATTR, &default)?;
+    if new.is_dir()
+    {
+        tag_file(new, DEFAULT_LABEL_XATTR, &default)?;
+    }
+    Ok(())
 }
 
 #[allow(dead_code)]
@@ -58,3 +115,42 @@ impl Policy for Dummy
 }
 */
 // https://docs.rs/meval/0.2.0/meval/
+
+
+#[cfg(test)]
+mod tests
+{
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn new_entries_inherit_default_labels()
+    {
+        let root = TempDir::new().unwrap();
+        let (folder, file) = (root.path().join("folder"), root.path().join("folder/sub/a"));
+        std::fs::create_dir(&folder).unwrap();
+        tag_file(&folder, DEFAULT_LABEL_XATTR, "labels = {name=\"linear\",value=1}").unwrap();
+
+        std::fs::create_dir(folder.join("sub")).unwrap();
+        inherit_labels(folder.join("sub")).unwrap();
+        std::fs::write(&file, "").unwrap();
+        inherit_labels(&file).unwrap();
+
+        let label = read_tag(&file, LABEL_XATTR).unwrap();
+        assert_eq!(label, "labels = {name=\"linear\",value=1}");
+        assert_eq!(read_tag(folder.join("sub"), LABEL_XATTR).unwrap(), label);
+        assert_eq!(read_tag(&file, DEFAULT_LABEL_XATTR).unwrap(), "");
+    }
+
+    #[test]
+    fn nothing_to_inherit()
+    {
+        let root = TempDir::new().unwrap();
+        let file = root.path().join("a");
+        std::fs::write(&file, "").unwrap();
+
+        inherit_labels(&file).unwrap();
+        assert_eq!(read_tag(&file, LABEL_XATTR).unwrap(), "");
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex},
+    thread::JoinHandle,
+};
+
+use log::error;
+
+use crate::parse_config;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/*
+ * A fixed number of threads taking jobs off a shared queue. The FUSE session
+ * thread hands the slow requests to it and goes on with the next one, and the
+ * workers reply when they are done. Dropping the pool waits for the queued
+ * jobs to finish. A job that panics is logged, and its worker goes on with the
+ * next one; a reply it left unsent is sent as EIO when it is dropped.
+ */
+pub struct WorkerPool
+{
+    sender:  Option<mpsc::Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool
+{
+    pub fn new(workers: usize) -> Self
+    {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..workers.max(1))
+            .map(|i| {
+                let receiver = Arc::clone(&receiver);
+                std::thread::Builder::new()
+                    .name(format!("gurret-worker-{}", i))
+                    .spawn(move || loop
+                    {
+                        let job = receiver.lock().expect("getting lock").recv();
+                        match job
+                        {
+                            Ok(job) =>
+                            {
+                                if let Err(panic) = catch_unwind(AssertUnwindSafe(job))
+                                {
+                                    error!("worker job panicked: {}", panic_message(&*panic));
+                                }
+                            },
+                            Err(_) => break,
+                        }
+                    })
+                    .expect("spawning worker")
+            })
+            .collect();
+
+        Self {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    pub fn size(&self) -> usize
+    {
+        self.workers.len()
+    }
+
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static)
+    {
+        self.sender
+            .as_ref()
+            .expect("pool is running")
+            .send(Box::new(job))
+            .expect("workers are alive");
+    }
+
+    // Wait for the jobs already given to finish, and stop the workers
+    pub fn join(&mut self)
+    {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..)
+        {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for WorkerPool
+{
+    fn drop(&mut self)
+    {
+        self.join();
+    }
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str
+{
+    match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>())
+    {
+        (Some(message), _) => message,
+        (_, Some(message)) => message,
+        _ => "without a message",
+    }
+}
+
+/*
+ * The number of workers is `FUSE_WORKERS`, or one per CPU if not set.
+ */
+pub fn pool_from_config() -> std::io::Result<WorkerPool>
+{
+    let workers = match parse_config("FUSE_WORKERS", "a number")?
+    {
+        Some(workers) => workers,
+        None => std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+    };
+    Ok(WorkerPool::new(workers))
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn every_job_runs_before_drop()
+    {
+        let done = Arc::new(AtomicUsize::new(0));
+        let pool = WorkerPool::new(3);
+        assert_eq!(pool.size(), 3);
+
+        for _ in 0..100
+        {
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        drop(pool);
+        assert_eq!(done.load(Ordering::SeqCst), 100);
+    }
+
+    #[test]
+    fn panicking_job_leaves_the_worker()
+    {
+        let pool = WorkerPool::new(1);
+        let (tx, rx) = mpsc::channel();
+        pool.execute(|| panic!("job"));
+        pool.execute(move || tx.send(()).unwrap());
+        rx.recv_timeout(std::time::Duration::from_secs(10)).unwrap();
+    }
+
+    #[test]
+    fn jobs_run_concurrently()
+    {
+        let pool = WorkerPool::new(2);
+        let (tx, rx) = mpsc::channel();
+        let barrier = Arc::new(std::sync::Barrier::new(2));
+
+        // Both only finish if they are waiting at the same time
+        for _ in 0..2
+        {
+            let (tx, barrier) = (tx.clone(), Arc::clone(&barrier));
+            pool.execute(move || {
+                barrier.wait();
+                tx.send(()).unwrap();
+            });
+        }
+        for _ in 0..2
+        {
+            rx.recv_timeout(std::time::Duration::from_secs(10)).unwrap();
+        }
+    }
+}
@@ -1,59 +1,219 @@
 use std::{
-    io::{self, Read},
+    io::{self, Read, Write},
     net::TcpStream,
+    os::unix::{
+        fs::PermissionsExt,
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc, Mutex,
     },
+    time::Duration,
+};
+
+use log::error;
+use serde_json::{json, Value};
+
+use crate::{
+    daemon,
+    file_system::MetadataEvents,
+    metadata::{Context, Operation},
+    permission,
+    table::Table,
+    BASE_PATH, TABLE,
 };
 
-use crate::{table::Table, TABLE};
+// The longest request taken on the control socket
+const MAX_REQUEST: usize = 1 << 16;
 
-fn read_stream(stream: &mut TcpStream, buf: &mut [u8], exit_condition: &Arc<AtomicBool>) -> bool
+// Fill `buf` from the stream, unless it closes or the mount is going down first
+fn read_stream(stream: &mut impl Read, buf: &mut [u8], exit_condition: &Arc<AtomicBool>) -> bool
 {
-    loop
+    let mut read = 0;
+    while read < buf.len()
     {
-        match stream.read_exact(buf)
+        match stream.read(&mut buf[read..])
         {
-            Ok(()) =>
-            {
-                return true;
-            },
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock =>
+            Ok(0) => return false,
+            Ok(n) => read += n,
+            // Timed out, to see if the mount is going down
+            Err(ref e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) =>
             {
                 if exit_condition.load(Ordering::Relaxed)
                 {
                     return false;
                 }
-                continue;
             },
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
             Err(_) => return false,
         };
     }
+    true
+}
+
+// Messages both ways are a big endian u32 length followed by that many bytes
+fn write_message(stream: &mut impl Write, message: &[u8]) -> io::Result<()>
+{
+    stream.write_all(&(message.len() as u32).to_be_bytes())?;
+    stream.write_all(message)
 }
 
 //fn parse_message<'a>(s: &'a str) -> Option<(&'a str,
 
+// `file` in the TARGET, refused if it is not there once `..` and links are followed
+fn on_target(file: &str) -> io::Result<PathBuf>
+{
+    let target = Path::new(&*BASE_PATH).canonicalize()?;
+    let path = target.join(file).canonicalize()?;
+    match path.starts_with(&target)
+    {
+        true => Ok(path),
+        false => Err(io::Error::new(io::ErrorKind::PermissionDenied, "not in the TARGET")),
+    }
+}
+
+// `revoke <dataset>` from the controller revokes a dataset, if it is in the TARGET
 fn handle_message(s: &str, state: &Arc<Mutex<Table>>)
+{
+    let mut iter = s.split_ascii_whitespace();
+    if let (Some("revoke"), Some(dataset)) = (iter.next(), iter.next())
+    {
+        let revoked = on_target(dataset).and_then(|path| {
+            let mut table = TABLE!(state);
+            table.revoke(&path)?;
+            table.flush()
+        });
+        if let Err(e) = revoked
+        {
+            error!("revoking {} failed: {}", dataset, e);
+        }
+    }
+}
+
+/*
+ * What root asks of the mount on the control socket, answered with a JSON
+ * object. Files are relative to the TARGET unless absolute, and have to be in
+ * it.
+ *
+ * `metadata <file>` is answered with the current metadata of the file, asked
+ * for as `caller`.
+ *
+ * `relabel [-r] <file> <labels>` gives the file the labels, a `user.label`
+ * TOML such as `labels = {name="linear",value=1}`, and with `-r` everything
+ * in it as well. It is answered with the paths that were relabelled.
+ *
+ * `checkout <file>` has the table track the file, and `revoke <file>` revokes
+ * it and everything derived from it. The table of the mount is the one that
+ * is changed and saved, so it is not overwritten by what the mount has.
+ */
+fn control(s: &str, caller: &Context, state: &Arc<Mutex<Table>>, events: &MetadataEvents)
+    -> Value
 {
     let s = s.trim();
     let mut iter = s.split_ascii_whitespace();
     match (iter.next(), iter.next())
     {
-        (Some(statement), Some(dataset)) =>
+        _ if caller.uid != 0 => json!({ "error": "only root may control the mount" }),
+        (Some("metadata"), Some(file)) =>
         {
-            if statement == "revoke"
+            let values = on_target(file).and_then(|path| {
+                let values = events.handler().query(&path, caller)?;
+                Ok((path, values))
+            });
+            match values
             {
+                Ok((path, values)) => json!({ "path": path, "metadata": values }),
+                Err(e) => json!({ "path": file, "error": e.to_string() }),
+            }
+        },
+        (Some("relabel"), Some(_)) =>
+        {
+            let args = s["relabel".len()..].trim_start();
+            let (recursive, args) = match args.strip_prefix("-r ")
+            {
+                Some(args) => (true, args.trim_start()),
+                None => (false, args),
+            };
+            let (file, label) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+
+            let relabelled = on_target(file).and_then(|path| {
+                let relabelled = relabel(&path, label.trim(), recursive, caller, state, events)?;
+                Ok((path, relabelled))
+            });
+            match relabelled
+            {
+                Ok((path, relabelled)) => json!({ "path": path, "relabelled": relabelled }),
+                Err(e) => json!({ "path": file, "error": e.to_string() }),
+            }
+        },
+        (Some(command @ ("checkout" | "revoke")), Some(file)) =>
+        {
+            let changed = on_target(file).and_then(|path| {
                 let mut table = TABLE!(state);
-                table.revoke(dataset).expect("revoking");
-                table.flush().expect("flushing");
+                match command
+                {
+                    "checkout" => table.insert(&path)?,
+                    _ => table.revoke(&path)?,
+                }
+                table.flush()?;
+                Ok(path)
+            });
+            match (changed, command)
+            {
+                (Ok(path), "checkout") => json!({ "checkout": path }),
+                (Ok(path), _) => json!({ "revoked": path }),
+                (Err(e), _) => json!({ "path": file, "error": e.to_string() }),
             }
         },
-        _ =>
-        {},
-    };
+        _ => json!({ "error": format!("not understood: {:?}", s) }),
+    }
 }
 
+// Relabel `file`, and bring the table and metadata of what was relabelled up to date
+fn relabel(
+    file: &Path,
+    label: &str,
+    recursive: bool,
+    caller: &Context,
+    state: &Arc<Mutex<Table>>,
+    events: &MetadataEvents,
+) -> io::Result<Vec<PathBuf>>
+{
+    let relabelled = permission::relabel(file, label, recursive)?;
+
+    let mut table = TABLE!(state);
+    for path in &relabelled
+    {
+        if table.contains(path)
+        {
+            table.relabel(path)?;
+        }
+    }
+    table.flush()?;
+    drop(table);
+
+    for path in &relabelled
+    {
+        if let Err(err) = events.event(path, Operation::Relabel, caller)
+        {
+            error!("relabel of {} refused by metadata: {}", path.display(), err);
+        }
+    }
+    Ok(relabelled)
+}
+
+
+// The connection is gone, and the mount goes down with it
+fn hang_up(exit_condition: &AtomicBool)
+{
+    if !exit_condition.swap(true, Ordering::Relaxed)
+    {
+        let _ = signal_hook::low_level::raise(signal_hook::consts::SIGTERM);
+    }
+}
 
 pub fn spawn(exit_condition: Arc<AtomicBool>, state: Arc<Mutex<Table>>)
 {
@@ -66,21 +226,20 @@ pub fn spawn(exit_condition: Arc<AtomicBool>, state: Arc<Mutex<Table>>)
 
         if exit_condition.load(Ordering::Relaxed)
         {
-            std::process::exit(0);
+            return;
         }
 
         std::thread::sleep(std::time::Duration::from_millis(1000));
     };
 
-    stream.set_nonblocking(true).expect("set_nonblocking call failed");
+    stream.set_read_timeout(Some(Duration::from_millis(200))).expect("setting read timeout");
 
     loop
     {
         let mut len: [u8; 4] = [0; 4];
         if !read_stream(&mut stream, &mut len, &exit_condition)
         {
-            exit_condition.store(true, Ordering::Relaxed);
-            return;
+            return hang_up(&exit_condition);
         }
         let message_length = u32::from_be_bytes(len);
 
@@ -88,11 +247,142 @@ pub fn spawn(exit_condition: Arc<AtomicBool>, state: Arc<Mutex<Table>>)
 
         if !read_stream(&mut stream, buf.as_mut_slice(), &exit_condition)
         {
-            exit_condition.store(true, Ordering::Relaxed);
-            return;
+            return hang_up(&exit_condition);
         }
 
         let s = std::str::from_utf8(&buf).expect("turing into str");
         handle_message(s, &state);
     }
 }
+
+// Who is on the other end of a control connection, as the kernel tells it
+fn peer(stream: &UnixStream) -> io::Result<Context>
+{
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let found = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    match found
+    {
+        0 => Ok(Context {
+            pid: cred.pid as u32,
+            uid: cred.uid,
+            ..Context::default()
+        }),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+// One request on the control socket, and its answer
+fn answer(
+    mut stream: UnixStream,
+    exit_condition: &Arc<AtomicBool>,
+    state: &Arc<Mutex<Table>>,
+    events: &MetadataEvents,
+) -> io::Result<()>
+{
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_millis(200)))?;
+    let caller = peer(&stream)?;
+
+    let mut len = [0; 4];
+    if !read_stream(&mut stream, &mut len, exit_condition)
+    {
+        return Ok(());
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_REQUEST
+    {
+        return write_message(&mut stream, json!({ "error": "too long" }).to_string().as_bytes());
+    }
+    let mut buf = vec![0; len];
+    if !read_stream(&mut stream, &mut buf, exit_condition)
+    {
+        return Ok(());
+    }
+
+    let reply = control(&String::from_utf8_lossy(&buf), &caller, state, events);
+    write_message(&mut stream, reply.to_string().as_bytes())
+}
+
+/*
+ * Ask the running mount for `request` on its control socket, see `control`,
+ * and give back its answer. An answer with an `error` is given back as one.
+ */
+pub fn ask(request: &str) -> io::Result<Value>
+{
+    let mut stream = UnixStream::connect(daemon::control_socket())?;
+    write_message(&mut stream, request.as_bytes())?;
+
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let mut buf = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut buf)?;
+
+    let answer: Value = serde_json::from_slice(&buf)?;
+    match answer.get("error").and_then(Value::as_str)
+    {
+        Some(error) => Err(io::Error::other(error.to_string())),
+        None => Ok(answer),
+    }
+}
+
+/*
+ * Serve the control socket, `daemon::control_socket`, until the mount goes
+ * down. Only root can connect to it, and the kernel tells who did, so what it
+ * asks for is done as them. Every connection is one request, framed like the
+ * messages of the controller, and its answer.
+ */
+pub fn serve_control(
+    exit_condition: Arc<AtomicBool>,
+    state: Arc<Mutex<Table>>,
+    events: MetadataEvents,
+) -> io::Result<()>
+{
+    let path = daemon::control_socket();
+    daemon::root_owned_folder(&path)?;
+    match std::fs::remove_file(&path)
+    {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    let listener = UnixListener::bind(&path)?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    listener.set_nonblocking(true)?;
+
+    while !exit_condition.load(Ordering::Relaxed)
+    {
+        match listener.accept()
+        {
+            Ok((stream, _)) =>
+            {
+                let (exit_condition, state) = (Arc::clone(&exit_condition), Arc::clone(&state));
+                let events = events.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = answer(stream, &exit_condition, &state, &events)
+                    {
+                        error!("answering on the control socket failed: {}", e);
+                    }
+                });
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock =>
+            {
+                std::thread::sleep(Duration::from_millis(100))
+            },
+            Err(e) => return Err(e),
+        }
+    }
+    let _ = std::fs::remove_file(&path);
+    Ok(())
+}
@@ -1,15 +1,17 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
     io::{Error, ErrorKind, Write},
     path::Path,
     rc::Rc,
+    sync::Arc,
 };
 
 use colored::Colorize;
 use serde::{Deserialize, Serialize};
 use toml::Value;
 
-use crate::policy::*;
+use crate::{broker::Broker, policy::*};
 
 #[allow(dead_code)]
 type Node = Rc<RefCell<TableEntry>>;
@@ -33,7 +35,9 @@ pub struct Table
 {
     //map:   HashMap<String, Rc<RefCell<TableEntry>>>,
     //#[serde(skip)]
-    table: HashMap<String, Rc<RefCell<TableEntry>>>,
+    table:  HashMap<String, Rc<RefCell<TableEntry>>>,
+    // The same lineage, for propagating metadata, copied when changed while it is shared
+    broker: Arc<Broker>,
 }
 
 unsafe impl Send for Table {}
@@ -44,42 +48,18 @@ impl TableEntry
     {
         // Multiple?
         let path = path.as_ref();
-        let toml = read_tag(path, "user.label")?.parse::<Value>()?;
-        let labels = &toml["labels"];
-        /*if !labels.is_array()
-        {
-            return Err(Error::new(ErrorKind::InvalidInput, "un-recognizedformat, not table"));
-        }*/
-
-
-        /*let labels = labels.as_array().unwrap();
-        let mut vec = Vec::with_capacity(labels.len());
-
-        for label in labels
-        {
-            if !label.is_table()
-            {
-                return Err(Error::new(ErrorKind::InvalidInput, "un-recognizedformat, not object"));
-            }
-            let map = label.as_table().unwrap();
 
-            let name = map["name"].as_str().expect("getting string").to_owned();
-            let value = map["value"].to_string();
-            vec.push(format!("{} {}", name, value));
-        }*/
-
-        if !labels.is_table()
+        // A symlink has no labels of its own, it has those of what it points to
+        let labelled = match path.is_symlink()
         {
-            return Err(Error::new(ErrorKind::InvalidInput, "un-recognizedformat, not object"));
-        }
-        let map = labels.as_table().unwrap();
-
-        let label_name = map["name"].as_str().expect("getting string").to_owned();
-        let label_value = map["value"].to_string();
+            true => std::fs::canonicalize(path)?,
+            false => path.to_path_buf(),
+        };
+        let labels = read_labels(&labelled)?;
 
         let name = path.file_name().unwrap().to_os_string().to_str().unwrap().to_string();
         Ok(TableEntry {
-            labels: vec![format!("{} {}", label_name, label_value)],
+            labels,
             name,
             children: HashMap::new(),
             parent,
@@ -87,6 +67,40 @@ impl TableEntry
     }
 }
 
+/*
+ * The labels of `path`, as "name value". Without labels of its own, a file
+ * has those of the nearest labelled folder it is in, up to the TARGET.
+ */
+fn read_labels(path: &Path) -> std::io::Result<Vec<String>>
+{
+    for labelled in up_to_target(path)
+    {
+        let toml = read_tag(labelled, LABEL_XATTR)?.parse::<Value>()?;
+        let labels = match toml.get("labels")
+        {
+            Some(Value::Array(labels)) => labels.iter().collect(),
+            Some(label) => vec![label],
+            None => continue,
+        };
+
+        let mut vec = Vec::with_capacity(labels.len());
+        for label in labels
+        {
+            let map = label.as_table().ok_or_else(|| {
+                Error::new(ErrorKind::InvalidInput, "un-recognizedformat, not object")
+            })?;
+            let name = map.get("name").and_then(Value::as_str);
+            match (name, map.get("value"))
+            {
+                (Some(name), Some(value)) => vec.push(format!("{} {}", name, value)),
+                _ => return Err(Error::new(ErrorKind::InvalidInput, "label without name or value")),
+            }
+        }
+        return Ok(vec);
+    }
+    Err(Error::new(ErrorKind::NotFound, format!("{:?} has no labels", path)))
+}
+
 /*
 pub struct Table
 {
@@ -151,6 +165,15 @@ impl Table
         {
             Self::_map_iter((name, rc), &mut table);
         }
+
+        // An entry with many parents is saved once under each of them
+        for rc in table.values()
+        {
+            for (name, child) in rc.borrow_mut().children.iter_mut()
+            {
+                *child = Rc::clone(&table[name]);
+            }
+        }
         table
     }
 
@@ -159,6 +182,10 @@ impl Table
         table: &mut HashMap<String, Rc<RefCell<TableEntry>>>,
     )
     {
+        if table.contains_key(name)
+        {
+            return;
+        }
         table.insert(name.to_owned(), Rc::clone(rc));
         for (name, rc) in rc.borrow().children.iter()
         {
@@ -199,11 +226,63 @@ impl Table
         let reader = std::io::BufReader::new(file);
         let map: HashMap<String, Node> = serde_json::from_reader(reader)?;
 
+        let table = Self::map_iter(map);
+        let mut broker = Broker::default();
+        for (name, entry) in table.iter()
+        {
+            for parent in entry.borrow().parent.iter().flatten()
+            {
+                broker.derive(OsStr::new(name), OsStr::new(parent));
+            }
+        }
+
         Ok(Table {
-            table: Self::map_iter(map)
+            table,
+            broker: Arc::new(broker),
         })
     }
 
+    // The datasets `dataset` was derived from, directly
+    pub fn parents<P: AsRef<Path>>(&self, dataset: P) -> Vec<String>
+    {
+        self.broker
+            .parents(OsStr::new(&Self::get_name(&dataset)))
+            .into_iter()
+            .map(|parent| parent.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    // The datasets derived from `dataset`, directly
+    pub fn children<P: AsRef<Path>>(&self, dataset: P) -> Vec<String>
+    {
+        let mut children: Vec<String> = match self.table.get(&Self::get_name(&dataset))
+        {
+            Some(entry) => entry.borrow().children.keys().cloned().collect(),
+            None => Vec::new(),
+        };
+        children.sort();
+        children
+    }
+
+    // Every dataset tracked, in order
+    pub fn datasets(&self) -> Vec<String>
+    {
+        let mut datasets: Vec<String> = self.table.keys().cloned().collect();
+        datasets.sort();
+        datasets
+    }
+
+    pub fn broker(&self) -> &Broker
+    {
+        &self.broker
+    }
+
+    // The lineage as it is now, to be followed without holding on to the table
+    pub fn lineage(&self) -> Arc<Broker>
+    {
+        Arc::clone(&self.broker)
+    }
+
     pub fn contains<P: AsRef<Path>>(&self, dataset: P) -> bool
     {
         self.table.contains_key(&Self::get_name(&dataset))
@@ -241,20 +320,41 @@ impl Table
     }
 
     // @TODO: Use absolute path OR inode
+    // A dataset derived again, from another dataset, gets another parent
     pub fn derive<P: AsRef<Path>>(&mut self, new: P, from: P) -> std::io::Result<()>
     {
         let new_name = Self::get_name(&new);
         let from_name = Self::get_name(&from);
 
-        let entry = self
-            .table
-            .entry(from_name.clone())
-            .or_insert(Rc::new(RefCell::new(TableEntry::from_file(&from, None)?)));
+        if !self.table.contains_key(&from_name)
+        {
+            let entry = TableEntry::from_file(&from, None)?;
+            self.table.insert(from_name.clone(), Rc::new(RefCell::new(entry)));
+        }
 
-        let new_rc = Rc::new(RefCell::new(TableEntry::from_file(&new, Some(vec![from_name]))?));
-        entry.borrow_mut().children.insert(new_name.clone(), Rc::clone(&new_rc));
-        self.table.insert(new_name, new_rc);
+        let new_rc = match self.table.get(&new_name)
+        {
+            Some(rc) =>
+            {
+                let mut entry = rc.borrow_mut();
+                let parents = entry.parent.get_or_insert_with(Vec::new);
+                if !parents.contains(&from_name)
+                {
+                    parents.push(from_name.clone());
+                }
+                drop(entry);
+                Rc::clone(rc)
+            },
+            None =>
+            {
+                let entry = TableEntry::from_file(&new, Some(vec![from_name.clone()]))?;
+                Rc::new(RefCell::new(entry))
+            },
+        };
 
+        self.table[&from_name].borrow_mut().children.insert(new_name.clone(), Rc::clone(&new_rc));
+        self.table.insert(new_name.clone(), new_rc);
+        Arc::make_mut(&mut self.broker).derive(OsStr::new(&new_name), OsStr::new(&from_name));
 
         Ok(())
     }
@@ -276,14 +376,27 @@ impl Table
         {
             entry.borrow_mut().name = new_name.clone();
 
-            // Move the parent's RC into the new hashmap bucket with the new name
-            if let Some(parent) = &entry.borrow().parent
+            // Move the parents' RC into the new hashmap bucket with the new name
+            for parent in entry.borrow().parent.iter().flatten()
             {
-                let parent = self.table.get(parent.first().unwrap()).unwrap();
+                let parent = self.table.get(parent).unwrap();
                 let parent_rc = parent.borrow_mut().children.remove(&old_name).unwrap();
                 parent.borrow_mut().children.insert(new_name.clone(), parent_rc);
             }
 
+            // And the children should know their parent by the new name
+            for child in entry.borrow().children.values()
+            {
+                for parent in child.borrow_mut().parent.iter_mut().flatten()
+                {
+                    if *parent == old_name
+                    {
+                        *parent = new_name.clone();
+                    }
+                }
+            }
+
+            Arc::make_mut(&mut self.broker).rename(OsStr::new(&new_name), OsStr::new(&old_name));
             self.table.insert(new_name, entry);
         }
         else
@@ -295,6 +408,43 @@ impl Table
         Ok(())
     }
 
+    /*
+     * `old` is now `new`, along with every dataset under it if it is a
+     * folder. The number of datasets renamed is given back.
+     */
+    pub fn rename_all<P: AsRef<Path>>(&mut self, new: P, old: P) -> std::io::Result<usize>
+    {
+        let (new, old) = (new.as_ref(), old.as_ref());
+        let moved: Vec<String> =
+            self.table.keys().filter(|name| Path::new(name).starts_with(old)).cloned().collect();
+
+        for name in &moved
+        {
+            let renamed = match Path::new(name).strip_prefix(old).expect("under old")
+            {
+                rest if rest.as_os_str().is_empty() => new.to_path_buf(),
+                rest => new.join(rest),
+            };
+            self.rename(renamed.as_path(), Path::new(name))?;
+        }
+        Ok(moved.len())
+    }
+
+    // `dataset` was given other labels
+    pub fn relabel<P: AsRef<Path>>(&mut self, dataset: P) -> std::io::Result<()>
+    {
+        let name = Self::get_name(&dataset);
+        match self.table.get(&name)
+        {
+            Some(entry) =>
+            {
+                entry.borrow_mut().labels = read_labels(dataset.as_ref())?;
+                Ok(())
+            },
+            None => Err(Error::new(ErrorKind::NotFound, format!("Did not find {}", name))),
+        }
+    }
+
     pub fn revoke<P: AsRef<Path>>(&mut self, dataset: P) -> std::io::Result<()>
     {
         let dataset_name = Self::get_name(&dataset);
@@ -308,28 +458,57 @@ impl Table
         }
 
 
-        let rc = self.table.remove(&dataset_name).unwrap();
-
-        let revoke_func = |name: &String, _: &Node| {
-            std::fs::remove_file(name).expect("removing file");
-        };
+        let rc = Rc::clone(&self.table[&dataset_name]);
 
-        revoke_func(&dataset_name, &rc);
-        Self::recursive_map_iter(&rc.borrow().children, revoke_func);
+        // Everything derived from the dataset is revoked along with it, once
+        let names = RefCell::new(vec![dataset_name.clone()]);
+        Self::recursive_map_iter(&rc.borrow().children, |name, _| {
+            names.borrow_mut().push(name.clone());
+        });
+        let mut seen = HashSet::new();
+        let mut names = names.into_inner();
+        names.retain(|name| seen.insert(name.clone()));
 
-        rc.borrow_mut().children.clear();
+        /*
+         * The files go before the table is changed, and the dataset last, so a
+         * revoke that fails part way is done again. One that is already gone
+         * is revoked all the same.
+         */
+        for name in names.iter().rev()
+        {
+            match std::fs::remove_file(name)
+            {
+                Err(e) if e.kind() != ErrorKind::NotFound =>
+                {
+                    return Err(Error::new(e.kind(), format!("removing {}: {}", name, e)));
+                },
+                _ => (),
+            }
+        }
 
-        if let Some(ref parent) = rc.borrow().parent
+        self.table.remove(&dataset_name);
+        for name in &names
         {
-            self.table
-                .get_mut(parent.first().unwrap())
-                .unwrap()
-                .borrow_mut()
-                .children
-                .remove(&dataset_name);
+            Arc::make_mut(&mut self.broker).remove(OsStr::new(name));
+
+            let entry = match name == &dataset_name
+            {
+                true => Rc::clone(&rc),
+                false => match self.table.remove(name)
+                {
+                    Some(entry) => entry,
+                    None => continue,
+                },
+            };
+            for parent in entry.borrow().parent.iter().flatten()
+            {
+                if let Some(parent) = self.table.get(parent)
+                {
+                    parent.borrow_mut().children.remove(name);
+                }
+            }
         }
-        self.table
-            .retain(|_, v| !(Rc::strong_count(v) < 1 && v.borrow().parent.is_some()));
+        rc.borrow_mut().children.clear();
 
         Ok(())
     }
@@ -416,3 +595,126 @@ fn lattice_color(linear_value: i64) -> String
         _ => unreachable!(),
     }
 }
+
+
+#[cfg(test)]
+mod tests
+{
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn dataset(root: &TempDir, name: &str) -> String
+    {
+        let path = root.path().join(name);
+        std::fs::write(&path, "").unwrap();
+        xattr::set(&path, "user.label", b"[labels]\nname = \"secret\"\nvalue = 1\n").unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn lineage_follows_derive_rename_and_revoke()
+    {
+        let root = TempDir::new().unwrap();
+        let (a, b, c) = (dataset(&root, "a"), dataset(&root, "b"), dataset(&root, "c"));
+        let mut table = Table::default();
+
+        table.derive(&c, &a).unwrap();
+        table.derive(&c, &b).unwrap();
+        assert_eq!(table.parents(&c), [a.clone(), b.clone()]);
+        assert_eq!(table.children(&a), [c.clone()]);
+        assert_eq!(table.datasets(), [a.clone(), b.clone(), c.clone()]);
+
+        let d = root.path().join("d").to_str().unwrap().to_string();
+        table.rename(&d, &a).unwrap();
+        assert_eq!(table.parents(&c), [d.clone(), b.clone()]);
+
+        table.revoke(&b).unwrap();
+        assert!(!table.contains(&c));
+        assert!(table.parents(&c).is_empty());
+        assert!(table.contains(&d));
+        assert!(!Path::new(&c).exists());
+    }
+
+    #[test]
+    fn revoke_that_can_not_remove_a_file_changes_nothing()
+    {
+        let root = TempDir::new().unwrap();
+        let (a, c) = (dataset(&root, "a"), dataset(&root, "c"));
+        let mut table = Table::default();
+        table.derive(&c, &a).unwrap();
+        let lineage = table.lineage();
+
+        // A folder with something in it is not removed as a file
+        std::fs::remove_file(&c).unwrap();
+        std::fs::create_dir(&c).unwrap();
+        std::fs::write(Path::new(&c).join("in"), "").unwrap();
+
+        assert!(table.revoke(&a).is_err());
+        assert!(Path::new(&a).exists());
+        assert_eq!(table.parents(&c), [a.clone()]);
+        assert_eq!(table.datasets(), [a.clone(), c.clone()]);
+
+        std::fs::remove_dir_all(&c).unwrap();
+        table.revoke(&a).unwrap();
+        assert!(table.datasets().is_empty());
+        assert_eq!(lineage.parents(OsStr::new(&c)), [OsStr::new(&a)]);
+    }
+
+    #[test]
+    fn symlink_has_the_labels_of_its_target()
+    {
+        let root = TempDir::new().unwrap();
+        let target = dataset(&root, "target");
+        let link = root.path().join("link").to_str().unwrap().to_string();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        let mut table = Table::default();
+        table.derive(&link, &target).unwrap();
+        assert_eq!(table.parents(&link), [target.clone()]);
+        assert_eq!(table.table[&link].borrow().labels, ["secret 1"]);
+    }
+
+    #[test]
+    fn unlabelled_has_the_labels_of_its_folder()
+    {
+        let root = TempDir::new().unwrap();
+        let folder = root.path().join("folder");
+        std::fs::create_dir(&folder).unwrap();
+        xattr::set(&folder, "user.label", b"labels = [{name=\"linear\",value=2}]").unwrap();
+        let (file, from) = (folder.join("file"), dataset(&root, "from"));
+        std::fs::write(&file, "").unwrap();
+        let file = file.to_str().unwrap().to_string();
+
+        let mut table = Table::default();
+        table.derive(&file, &from).unwrap();
+        assert_eq!(table.table[&file].borrow().labels, ["linear 2"]);
+
+        xattr::set(&file, "user.label", b"labels = {name=\"linear\",value=1}").unwrap();
+        table.relabel(&file).unwrap();
+        assert_eq!(table.table[&file].borrow().labels, ["linear 1"]);
+        assert!(table.relabel(root.path().join("nothing")).is_err());
+    }
+
+    #[test]
+    fn renamed_folder_keeps_the_lineage_in_it()
+    {
+        let root = TempDir::new().unwrap();
+        let folder = root.path().join("folder");
+        std::fs::create_dir(&folder).unwrap();
+        let (a, b) = (dataset(&root, "folder/a"), dataset(&root, "folder/b"));
+        let outside = dataset(&root, "outside");
+
+        let mut table = Table::default();
+        table.derive(&b, &a).unwrap();
+        table.derive(&outside, &b).unwrap();
+
+        let moved = root.path().join("moved");
+        assert_eq!(table.rename_all(&moved, &folder).unwrap(), 2);
+        let (a, b) = (moved.join("a"), moved.join("b"));
+        let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+        assert_eq!(table.parents(b), [a]);
+        assert_eq!(table.parents(&outside), [b]);
+        assert!(!table.contains(folder.join("a")));
+    }
+}
@@ -1,9 +1,38 @@
 #![allow(dead_code)]
+mod broker;
 mod policy;
 mod table;
 
+use lh_mount::{audit, logging};
+
+const USAGE: &str = "\
+usage: table                  the datasets tracked, and their lineage
+       table audit [--file <file>] [--pid <pid>] [--program <program>]
+                   [--operation <operation>] [--since <time>] [--allowed | --denied]
+                              the access decisions in the audit log, as JSON lines";
 
 fn main()
+{
+    if let Err(e) = logging::init()
+    {
+        eprintln!("table: {}", e);
+        std::process::exit(1);
+    }
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.split_first()
+    {
+        None => print_table(),
+        Some((&"audit", query)) => print_audit(query),
+        Some(_) =>
+        {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        },
+    }
+}
+
+fn print_table()
 {
     let table = match table::Table::from_file()
     {
@@ -17,3 +46,31 @@ fn main()
 
     println!("{}", table);
 }
+
+fn print_audit(query: &[&str])
+{
+    let query = match audit::Query::parse(query)
+    {
+        Ok(query) => query,
+        Err(e) =>
+        {
+            eprintln!("table: {}\n{}", e, USAGE);
+            std::process::exit(2);
+        },
+    };
+    match audit::AuditLog::from_config().and_then(|log| log.read(&query))
+    {
+        Ok(decisions) =>
+        {
+            for decision in decisions
+            {
+                println!("{}", serde_json::to_string(&decision).expect("decisions are JSON"));
+            }
+        },
+        Err(e) =>
+        {
+            eprintln!("table: reading the audit log: {}", e);
+            std::process::exit(1);
+        },
+    }
+}
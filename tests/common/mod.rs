@@ -0,0 +1,54 @@
+/*
+ * What the tests against a mounted gurret share: where it is mounted, the
+ * backing folder it mirrors, and names for the files a test makes there.
+ */
+use std::{path::PathBuf, process::Command};
+
+pub struct Mounted
+{
+    pub mount:  PathBuf,
+    pub target: PathBuf,
+    prefix:     &'static str,
+    names:      Vec<String>,
+}
+
+impl Mounted
+{
+    // `GURRET_MOUNT` and `GURRET_TARGET`, with names starting with `prefix`
+    pub fn new(prefix: &'static str) -> Self
+    {
+        let var = |name| std::env::var_os(name).unwrap_or_else(|| panic!("{} is not set", name));
+        Self {
+            mount:  PathBuf::from(var("GURRET_MOUNT")),
+            target: PathBuf::from(var("GURRET_TARGET")),
+            prefix,
+            names:  Vec::new(),
+        }
+    }
+
+    // A name only this test uses, removed again when it is done
+    pub fn name(&mut self, name: &str) -> String
+    {
+        let name = format!("{}_{}_{}", self.prefix, name, std::process::id());
+        self.names.push(name.clone());
+        name
+    }
+
+    pub fn run(&self, program: &str, args: &[&str])
+    {
+        let status = Command::new(program).args(args).current_dir(&self.mount).status().unwrap();
+        assert!(status.success(), "{} {:?} failed", program, args);
+    }
+}
+
+impl Drop for Mounted
+{
+    fn drop(&mut self)
+    {
+        for name in &self.names
+        {
+            let _ = std::fs::remove_file(self.mount.join(name));
+            let _ = std::fs::remove_dir(self.mount.join(name));
+        }
+    }
+}
@@ -0,0 +1,58 @@
+/*
+ * Copies labelled files through a mounted gurret, and checks that the lineage
+ * follows what the processes read and wrote. Mount it first, then run
+ *
+ *   GURRET_MOUNT=<mountpoint> GURRET_TARGET=<backing folder> \
+ *       cargo test --test lineage -- --ignored
+ *
+ * Without `--ignored` they are skipped.
+ */
+mod common;
+
+use common::Mounted;
+use lh_mount::table::Table;
+
+// A file in the backing folder, with a label
+fn dataset(mounted: &mut Mounted, name: &str) -> String
+{
+    let name = mounted.name(name);
+    let path = mounted.target.join(&name);
+    std::fs::write(&path, "a,b\n1,2\n").unwrap();
+    xattr::set(&path, "user.label", b"labels = {name=\"linear\",value=2}").unwrap();
+    name
+}
+
+fn parents(mounted: &Mounted, name: &str) -> Vec<String>
+{
+    Table::from_file().unwrap().parents(mounted.target.join(name))
+}
+
+#[test]
+#[ignore = "needs a mount"]
+fn copy_is_derived_from_its_source()
+{
+    let mut mounted = Mounted::new("lineage");
+    let (source, copy) = (dataset(&mut mounted, "source"), mounted.name("copy"));
+
+    mounted.run("cp", &[&source, &copy]);
+    let source = mounted.target.join(&source).to_string_lossy().into_owned();
+    assert_eq!(parents(&mounted, &copy), [source]);
+}
+
+#[test]
+#[ignore = "needs a mount"]
+fn pipeline_is_derived_from_what_it_read()
+{
+    let mut mounted = Mounted::new("lineage");
+    let (a, b) = (dataset(&mut mounted, "a"), dataset(&mut mounted, "b"));
+    let joined = mounted.name("joined");
+
+    // The shell writes what cat read, both in one process group
+    mounted.run("sh", &["-c", &format!("cat {} {} | sort > {}", a, b, joined)]);
+    let mut derived = parents(&mounted, &joined);
+    derived.sort();
+    let path = |name: &String| mounted.target.join(name).to_string_lossy().into_owned();
+    let mut read: Vec<String> = [a, b].iter().map(path).collect();
+    read.sort();
+    assert_eq!(derived, read);
+}
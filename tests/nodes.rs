@@ -0,0 +1,78 @@
+/*
+ * Makes symlinks and special files through a mounted gurret, and compares
+ * them with the backing filesystem. Mount it first, then run
+ *
+ *   GURRET_MOUNT=<mountpoint> GURRET_TARGET=<backing folder> \
+ *       cargo test --test nodes -- --ignored
+ *
+ * Without `--ignored` they are skipped.
+ */
+mod common;
+
+use std::{
+    os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt},
+    path::Path,
+};
+
+use common::Mounted;
+
+#[test]
+#[ignore = "needs a mount"]
+fn symlink_points_to_its_target()
+{
+    let mut mounted = Mounted::new("nodes");
+    let (target, link) = (mounted.name("target"), mounted.name("link"));
+
+    std::fs::write(mounted.mount.join(&target), "data").unwrap();
+    mounted.run("ln", &["-s", &target, &link]);
+
+    assert_eq!(std::fs::read_link(mounted.mount.join(&link)).unwrap(), Path::new(&target));
+    assert_eq!(std::fs::read_link(mounted.target.join(&link)).unwrap(), Path::new(&target));
+    assert_eq!(std::fs::read_to_string(mounted.mount.join(&link)).unwrap(), "data");
+    assert!(!mounted.target.join(&target).is_symlink());
+}
+
+#[test]
+#[ignore = "needs a mount"]
+fn link_into_the_backing_folder_stays_on_the_mount()
+{
+    let mut mounted = Mounted::new("nodes");
+    let (target, link) = (mounted.name("target"), mounted.name("backing_link"));
+
+    std::fs::write(mounted.mount.join(&target), "data").unwrap();
+    let backing = mounted.target.join(&target);
+    mounted.run("ln", &["-s", backing.to_str().unwrap(), &link]);
+
+    assert_eq!(std::fs::read_link(mounted.mount.join(&link)).unwrap(), mounted.mount.join(&target));
+}
+
+#[test]
+#[ignore = "needs a mount"]
+fn mkfifo()
+{
+    let mut mounted = Mounted::new("nodes");
+    let fifo = mounted.name("fifo");
+
+    mounted.run("sh", &["-c", &format!("umask 027 && mkfifo {}", fifo)]);
+    assert!(std::fs::symlink_metadata(mounted.mount.join(&fifo)).unwrap().file_type().is_fifo());
+    let backing = std::fs::symlink_metadata(mounted.target.join(&fifo)).unwrap();
+    assert!(backing.file_type().is_fifo());
+    assert_eq!(backing.permissions().mode() & 0o777, 0o640);
+    assert_eq!(backing.uid(), unsafe { libc::geteuid() });
+}
+
+#[test]
+#[ignore = "needs a mount"]
+fn file_and_folder_belong_to_their_maker()
+{
+    let mut mounted = Mounted::new("nodes");
+    let (file, folder) = (mounted.name("file"), mounted.name("folder"));
+
+    mounted.run("sh", &["-c", &format!("touch {} && mkdir {}", file, folder)]);
+    for name in [&file, &folder]
+    {
+        let backing = std::fs::symlink_metadata(mounted.target.join(name)).unwrap();
+        assert_eq!(backing.uid(), unsafe { libc::geteuid() });
+        assert_eq!(backing.gid(), unsafe { libc::getegid() });
+    }
+}
@@ -0,0 +1,126 @@
+/*
+ * Changes attributes through a mounted gurret, and compares the result with
+ * the backing filesystem. Mount it first, then run
+ *
+ *   GURRET_MOUNT=<mountpoint> GURRET_TARGET=<backing folder> \
+ *       cargo test --test setattr -- --ignored
+ *
+ * Without `--ignored` they are skipped. Changing the owner to another user is
+ * only tried as root.
+ */
+mod common;
+
+use std::{
+    fs::Metadata,
+    os::unix::fs::MetadataExt,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use common::Mounted;
+
+// A file of ten bytes on the mount, and where it is kept in the backing folder
+struct File
+{
+    mount:   Mounted,
+    name:    String,
+    mounted: PathBuf,
+    backing: PathBuf,
+}
+
+impl File
+{
+    fn new(name: &str) -> Self
+    {
+        let mut mount = Mounted::new("setattr");
+        let name = mount.name(name);
+        let mounted = mount.mount.join(&name);
+        std::fs::write(&mounted, "0123456789").unwrap();
+        Self {
+            backing: mount.target.join(&name),
+            mount,
+            name,
+            mounted,
+        }
+    }
+
+    fn run(&self, program: &str, args: &[&str])
+    {
+        self.mount.run(program, &[args, &[self.name.as_str()]].concat());
+    }
+
+    // Both sides agree, and what they agree on
+    fn stat(&self) -> Metadata
+    {
+        let mounted = std::fs::symlink_metadata(&self.mounted).unwrap();
+        let backing = std::fs::symlink_metadata(&self.backing).unwrap();
+
+        assert_eq!(mounted.mode(), backing.mode());
+        assert_eq!((mounted.uid(), mounted.gid()), (backing.uid(), backing.gid()));
+        assert_eq!(mounted.size(), backing.size());
+        assert_eq!(mounted.modified().unwrap(), backing.modified().unwrap());
+        assert_eq!(mounted.accessed().unwrap(), backing.accessed().unwrap());
+        mounted
+    }
+}
+
+
+#[test]
+#[ignore = "needs a mount"]
+fn touch()
+{
+    let file = File::new("touch");
+
+    file.run("touch", &["-d", "@981173106"]);
+    let stat = file.stat();
+    assert_eq!((stat.mtime(), stat.atime()), (981173106, 981173106));
+
+    file.run("touch", &["-m"]);
+    let elapsed = SystemTime::now().duration_since(file.stat().modified().unwrap());
+    assert!(elapsed.unwrap_or_default() < Duration::from_secs(60));
+}
+
+#[test]
+#[ignore = "needs a mount"]
+fn chmod()
+{
+    let file = File::new("chmod");
+
+    file.run("chmod", &["640"]);
+    assert_eq!(file.stat().mode() & 0o7777, 0o640);
+    file.run("chmod", &["u+x,g-r"]);
+    assert_eq!(file.stat().mode() & 0o7777, 0o700);
+}
+
+#[test]
+#[ignore = "needs a mount"]
+fn chown()
+{
+    let file = File::new("chown");
+
+    let before = file.stat();
+    file.run("chown", &[&format!("{}:{}", before.uid(), before.gid())]);
+    assert_eq!(file.stat().uid(), before.uid());
+
+    if unsafe { libc::geteuid() } == 0
+    {
+        file.run("chown", &["65534:65534"]);
+        let after = file.stat();
+        assert_eq!((after.uid(), after.gid()), (65534, 65534));
+    }
+}
+
+#[test]
+#[ignore = "needs a mount"]
+fn truncate()
+{
+    let file = File::new("truncate");
+
+    file.run("truncate", &["-s", "4"]);
+    assert_eq!(file.stat().size(), 4);
+    assert_eq!(std::fs::read(&file.mounted).unwrap(), b"0123");
+
+    file.run("truncate", &["-s", "8"]);
+    assert_eq!(file.stat().size(), 8);
+    assert_eq!(std::fs::read(&file.backing).unwrap(), b"0123\0\0\0\0");
+}